@@ -0,0 +1,19 @@
+use std::path::PathBuf;
+
+/// 覆盖配置目录的环境变量，便于测试或便携部署
+pub const CONFIG_DIR_ENV: &str = "SLINT_RMQTT_CONFIG_DIR";
+
+/// 获取当前用户的应用配置目录
+///
+/// 优先使用环境变量 `SLINT_RMQTT_CONFIG_DIR`，否则使用系统配置目录下的 `slint_rmqtt`
+///
+/// # 返回值
+/// 返回配置目录路径（不保证已经创建）
+pub fn app_config_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os(CONFIG_DIR_ENV) {
+        return PathBuf::from(dir);
+    }
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("slint_rmqtt")
+}
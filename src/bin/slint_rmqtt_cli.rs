@@ -0,0 +1,383 @@
+use anyhow::{Error, format_err};
+use clap::{Parser, Subcommand};
+#[cfg(feature = "broker")]
+use slint_rmqtt::broker::run_mqtt_broker;
+use slint_rmqtt::command_store::{StoredCommand, load_commands};
+use slint_rmqtt::command_tracker::{CommandStatus, wait_command};
+use slint_rmqtt::mqtt::receiver::mqtt_receiver_with_config;
+use slint_rmqtt::mqtt::sender::mqtt_publish_once;
+use slint_rmqtt::mqtt::transport::parse_qos;
+use slint_rmqtt::mqtt_handle::{init_command_client, send_command};
+use slint_rmqtt::profile::{ConnectionProfile, ProfileArgs, load_profiles_from};
+use slint_rmqtt::store::{AuditQuery, query_commands, query_state_history};
+use serde_json::{Value, json};
+use std::io::Write;
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(version, about = "MQTT 命令行客户端，与图形界面共用连接配置和命令库")]
+struct CliArgs {
+    #[command(flatten)]
+    profile: ProfileArgs,
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// 列出命令库中的命令
+    List {
+        /// 每行输出一个 JSON 对象
+        #[arg(long)]
+        json: bool,
+    },
+    /// 按名称发送命令并等待节点回复，回复失败或超时时返回非零退出码
+    Run {
+        /// 命令名称
+        name: String,
+        /// 发出后立即退出，不等待回复
+        #[arg(long)]
+        no_wait: bool,
+    },
+    /// 向指定主题发布原始消息
+    Publish {
+        /// 目标主题
+        topic: String,
+        /// 消息内容
+        payload: String,
+        /// 服务质量等级（0、1、2），QoS 1 和 2 收到服务器确认后才算成功
+        #[arg(long, default_value_t = 1)]
+        qos: u8,
+        /// 作为保留消息发布
+        #[arg(long)]
+        retain: bool,
+    },
+    /// 持续输出状态主题收到的消息，每行一个 JSON 对象
+    Tail {
+        /// 订阅的主题，可以带 + 和 # 通配符，默认使用配置中的状态主题
+        #[arg(long)]
+        topic: Option<String>,
+    },
+    /// 查询本地数据库中的命令记录，最新发送的在前（需要编译时启用 sqlite 功能）
+    Audit {
+        /// 只查询该命令
+        #[arg(long)]
+        name: Option<String>,
+        /// 最多输出的条数，0 表示不限
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// 每行输出一个 JSON 对象
+        #[arg(long)]
+        json: bool,
+    },
+    /// 查询本地数据库中节点的状态历史（需要编译时启用 sqlite 功能）
+    History {
+        /// 节点ID
+        #[arg(long)]
+        node: String,
+        /// 只查询该状态项
+        #[arg(long)]
+        key: Option<String>,
+        /// 查询最近多少秒内的记录
+        #[arg(long, default_value_t = 3600)]
+        since_secs: i64,
+        /// 每行输出一个 JSON 对象
+        #[arg(long)]
+        json: bool,
+    },
+    /// 按配置中的监听列表运行内置服务器，直到进程退出
+    #[cfg(feature = "broker")]
+    Broker,
+}
+
+// 读取连接配置并应用命令行参数
+fn resolve_profile(args: &ProfileArgs) -> Result<ConnectionProfile, Error> {
+    let store = load_profiles_from(&args.config_path())?;
+    let mut profile = args.resolve(&store)?;
+    // 与同时运行的图形界面使用不同的客户端ID，避免互相踢下线
+    profile.client_id_prefix = format!("{}_cli_{}", profile.client_id_prefix, std::process::id());
+    if !profile.client_id.is_empty() {
+        profile.client_id = format!("{}_cli_{}", profile.client_id, std::process::id());
+    }
+    Ok(profile)
+}
+
+fn list(commands: &[StoredCommand], json: bool, out: &mut impl Write) -> Result<(), Error> {
+    for command in commands {
+        if json {
+            writeln!(out, "{}", serde_json::to_string(command)?)?;
+        } else {
+            let params: Vec<String> = command
+                .values
+                .iter()
+                .map(|p| format!("{}={}", p.key, p.value))
+                .collect();
+            writeln!(out, "{}\t{}\t{}", command.name, command.topic, params.join(","))?;
+        }
+    }
+    Ok(())
+}
+
+async fn run(args: &ProfileArgs, name: &str, no_wait: bool) -> Result<bool, Error> {
+    let command = load_commands()?
+        .into_iter()
+        .find(|c| c.name == name)
+        .ok_or_else(|| format_err!("命令库中没有命令: {}", name))?;
+    init_command_client(resolve_profile(args)?).await?;
+    send_and_wait(&command, no_wait, &mut std::io::stdout()).await
+}
+
+// 通过当前连接池发送命令并输出结果，节点回复成功时返回 true
+async fn send_and_wait(
+    command: &StoredCommand,
+    no_wait: bool,
+    out: &mut impl Write,
+) -> Result<bool, Error> {
+    let id = send_command(command).await?;
+    if no_wait {
+        writeln!(out, "{}", json!({"name": command.name, "id": id, "status": "sent"}))?;
+        return Ok(true);
+    }
+    let (status, message) = wait_command(&id).await;
+    writeln!(
+        out,
+        "{}",
+        json!({"name": command.name, "id": id, "status": status.as_str(), "message": message})
+    )?;
+    Ok(status == CommandStatus::Acked)
+}
+
+fn audit(name: Option<String>, limit: usize, json: bool) -> Result<(), Error> {
+    for audit in query_commands(&AuditQuery {
+        name,
+        since: None,
+        limit,
+    })? {
+        if json {
+            println!("{}", audit.to_json());
+        } else {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                audit.sent_at.format("%Y-%m-%d %H:%M:%S"),
+                audit.name,
+                audit.sender,
+                audit.topic,
+                audit.status,
+                audit.message.unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn history(node: &str, key: Option<&str>, since_secs: i64, json: bool) -> Result<(), Error> {
+    let since = chrono::Local::now() - chrono::Duration::seconds(since_secs.clamp(0, 100 * 365 * 86400));
+    for (node, key, point) in query_state_history(node, key, since)? {
+        if json {
+            println!(
+                "{}",
+                json!({"time": point.at.to_rfc3339(), "node": node, "key": key, "value": point.value})
+            );
+        } else {
+            println!("{}\t{}\t{}", point.at.format("%Y-%m-%d %H:%M:%S"), key, point.value);
+        }
+    }
+    Ok(())
+}
+
+async fn publish(
+    args: &ProfileArgs,
+    topic: &str,
+    payload: &str,
+    qos: u8,
+    retain: bool,
+) -> Result<(), Error> {
+    let profile = resolve_profile(args)?;
+    let qos = parse_qos(qos)?;
+    let config = profile.mqtt_config();
+    let client_id = config.client_id("publish");
+    let timeout = Duration::from_secs(profile.ack_timeout_secs);
+    mqtt_publish_once(&config, &client_id, topic, payload, qos, retain, timeout).await
+}
+
+async fn tail(args: &ProfileArgs, topic: Option<String>) -> Result<(), Error> {
+    let profile = resolve_profile(args)?;
+    let topic = topic.unwrap_or_else(|| profile.state_topic.clone());
+    let mut receiver = mqtt_receiver_with_config(&profile.mqtt_config(), &topic, "tail").await?;
+    // 主题可以带通配符，输出每条消息实际的主题
+    receiver.add_route(&topic, parse_qos(profile.state_qos)?, |message| {
+        // 非 JSON 的消息按字符串输出，保证每行都是合法 JSON
+        let payload = serde_json::from_str::<Value>(message.payload)
+            .unwrap_or_else(|_| Value::from(message.payload));
+        println!(
+            "{}",
+            json!({
+                "time": chrono::Local::now().to_rfc3339(),
+                "topic": message.topic,
+                "payload": payload,
+            })
+        );
+    })?;
+    receiver.start().await;
+    Err(format_err!("与服务器的连接已断开"))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = CliArgs::parse();
+    let result = match args.command {
+        CliCommand::List { json } => load_commands()
+            .and_then(|commands| list(&commands, json, &mut std::io::stdout()))
+            .map(|_| true),
+        CliCommand::Run { name, no_wait } => run(&args.profile, &name, no_wait).await,
+        CliCommand::Publish {
+            topic,
+            payload,
+            qos,
+            retain,
+        } => publish(&args.profile, &topic, &payload, qos, retain)
+            .await
+            .map(|_| true),
+        CliCommand::Tail { topic } => tail(&args.profile, topic).await.map(|_| true),
+        CliCommand::Audit { name, limit, json } => audit(name, limit, json).map(|_| true),
+        CliCommand::History {
+            node,
+            key,
+            since_secs,
+            json,
+        } => history(&node, key.as_deref(), since_secs, json).map(|_| true),
+        #[cfg(feature = "broker")]
+        CliCommand::Broker => match resolve_profile(&args.profile) {
+            Ok(profile) => run_mqtt_broker(profile.broker_listeners).await.map(|_| true),
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[test]
+fn test_parse_cli_args() {
+    use clap::CommandFactory;
+    CliArgs::command().debug_assert();
+
+    let args = CliArgs::try_parse_from([
+        "slint_rmqtt_cli",
+        "--profile",
+        "lab",
+        "--port",
+        "8883",
+        "run",
+        "open_valve",
+        "--no-wait",
+    ])
+    .unwrap();
+    assert_eq!(args.profile.profile.as_deref(), Some("lab"));
+    assert_eq!(args.profile.port, Some(8883));
+    assert!(matches!(
+        args.command,
+        CliCommand::Run { ref name, no_wait: true } if name == "open_valve"
+    ));
+
+    let args = CliArgs::try_parse_from(["slint_rmqtt_cli", "publish", "a/b", "on", "--qos", "2"]).unwrap();
+    assert!(matches!(
+        args.command,
+        CliCommand::Publish { ref topic, qos: 2, retain: false, .. } if topic == "a/b"
+    ));
+    // 缺少子命令或必填参数时报错
+    assert!(CliArgs::try_parse_from(["slint_rmqtt_cli"]).is_err());
+    assert!(CliArgs::try_parse_from(["slint_rmqtt_cli", "history"]).is_err());
+}
+
+#[tokio::test]
+async fn test_list_and_run_over_loopback() {
+    use slint_rmqtt::command_param::{CommandParam, ParamType};
+    use slint_rmqtt::command_store::Delivery;
+    use slint_rmqtt::command_tracker::handle_ack;
+    use slint_rmqtt::mqtt::pool::{MqttConnection, MqttConnectionPool};
+    use slint_rmqtt::mqtt::receiver::Receiver;
+    use slint_rmqtt::mqtt::transport::{LoopbackBroker, TransportEvent};
+    use slint_rmqtt::mqtt_handle::{ACTIVE_PROFILE, POOL};
+    use std::sync::Arc;
+
+    let command = StoredCommand {
+        name: "open_valve".to_string(),
+        topic: "test/cli/command".to_string(),
+        values: vec![CommandParam::parse("valve", ParamType::Int, "1", "").unwrap()],
+        delivery: Delivery::default(),
+    };
+    let mut out = Vec::new();
+    list(std::slice::from_ref(&command), false, &mut out).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(), "open_valve\ttest/cli/command\tvalve=1\n");
+    let mut out = Vec::new();
+    list(std::slice::from_ref(&command), true, &mut out).unwrap();
+    let listed: StoredCommand = serde_json::from_slice(&out).unwrap();
+    assert_eq!(listed.name, "open_valve");
+
+    // 连接池、回复接收器和节点都接在同一个内存回环上
+    let broker = LoopbackBroker::new();
+    let profile = ConnectionProfile::default();
+    let reply = Receiver::with_transport(broker.connect("reply"), "reply", &profile.reply_topic);
+    reply.add_callback(|msg| {
+        handle_ack(msg);
+    });
+    tokio::spawn(async move {
+        let mut reply = reply;
+        reply.start().await
+    });
+    let (node, mut events) = broker.connect("node");
+    node.subscribe(&command.topic, rumqttc::QoS::AtLeastOnce).await.unwrap();
+    // 节点对 valve=1 回复成功，其余回复失败
+    tokio::spawn(async move {
+        while let Ok(event) = events.poll().await {
+            let TransportEvent::Message(message) = event else {
+                continue;
+            };
+            let request: Value = serde_json::from_str(&message.payload).unwrap();
+            let status = if request["op_value"]["valve"] == 1 { "ok" } else { "failed" };
+            let ack = json!({"id": request["id"], "status": status}).to_string();
+            let reply_to = request["reply_to"].as_str().unwrap().to_string();
+            node.publish(&reply_to, &ack, rumqttc::QoS::AtLeastOnce, false)
+                .await
+                .unwrap();
+        }
+    });
+    *ACTIVE_PROFILE.write().unwrap() = Some(profile);
+    let pool = MqttConnectionPool::from_connections(vec![MqttConnection::with_transport(
+        broker.connect("pool_0"),
+        "pool_0",
+    )])
+    .unwrap();
+    *POOL.write().unwrap() = Some(Arc::new(pool));
+    // 等待回复接收器完成订阅
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut out = Vec::new();
+    assert!(send_and_wait(&command, false, &mut out).await.unwrap());
+    let result: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(result["name"], "open_valve");
+    assert_eq!(result["status"], "acked");
+
+    let closed = StoredCommand {
+        values: vec![CommandParam::parse("valve", ParamType::Int, "0", "").unwrap()],
+        ..command
+    };
+    let mut out = Vec::new();
+    assert!(!send_and_wait(&closed, false, &mut out).await.unwrap());
+    let result: Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(result["status"], "failed");
+    let published: Vec<String> = broker
+        .published()
+        .into_iter()
+        .filter(|(id, _)| id == "pool_0")
+        .map(|(_, message)| message.topic)
+        .collect();
+    assert_eq!(published, ["test/cli/command", "test/cli/command"]);
+}
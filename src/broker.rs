@@ -0,0 +1,164 @@
+use anyhow::{Error, format_err};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+// 内置服务器的监听配置
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BrokerConfigure {
+    // 监听类型: tcp 或 ws
+    pub class: String,
+    pub name: String,
+    pub addr: [u8; 4],
+    pub port: u16,
+}
+
+impl BrokerConfigure {
+    pub fn new(class: &str, name: &str, addr: [u8; 4], port: u16) -> Self {
+        BrokerConfigure {
+            class: String::from(class),
+            name: String::from(name),
+            addr,
+            port,
+        }
+    }
+
+    /// 默认只监听本机: TCP 1883 和 WebSocket 8083
+    pub fn defaults() -> Vec<BrokerConfigure> {
+        vec![
+            BrokerConfigure::new("tcp", "tcp", [127, 0, 0, 1], 1883),
+            BrokerConfigure::new("ws", "ws", [127, 0, 0, 1], 8083),
+        ]
+    }
+}
+
+// 运行中的内置服务器及其监听配置
+type RunningBroker = (Vec<BrokerConfigure>, JoinHandle<()>);
+
+static BROKER_TASK: Lazy<Mutex<Option<RunningBroker>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// 按监听配置运行内置服务器，直到服务器退出
+#[cfg(feature = "broker")]
+pub async fn run_mqtt_broker(configures: Vec<BrokerConfigure>) -> Result<(), Error> {
+    build_mqtt_broker(&configures)
+        .await?
+        .run()
+        .await
+        .map_err(|e| format_err!("内置服务器运行失败: {}", e))
+}
+
+// 创建服务器并绑定全部端口，端口被占用等错误在这里返回
+#[cfg(feature = "broker")]
+async fn build_mqtt_broker(
+    configures: &[BrokerConfigure],
+) -> Result<rmqtt::server::MqttServer, Error> {
+    use rmqtt::context::ServerContext;
+    use rmqtt::net::Builder;
+    use rmqtt::server::MqttServer;
+
+    if configures.is_empty() {
+        return Err(format_err!("内置服务器至少需要一个监听配置"));
+    }
+    let scx = ServerContext::new().build().await;
+    let mut mqtt_server = MqttServer::new(scx);
+    for config in configures {
+        let builder = Builder::new()
+            .name(config.name.as_str())
+            .laddr((config.addr, config.port).into());
+        let bind_err = |e| format_err!("内置服务器无法监听 {}:{}: {}", config.name, config.port, e);
+        let listener = match config.class.as_str() {
+            "tcp" => builder.bind().map_err(bind_err)?.tcp(),
+            "ws" => builder.bind().map_err(bind_err)?.ws(),
+            class => return Err(format_err!("不支持的监听类型: {}", class)),
+        };
+        mqtt_server = mqtt_server.listener(listener.map_err(bind_err)?);
+    }
+    Ok(mqtt_server.build())
+}
+
+/// 启动内置服务器
+///
+/// 已经以相同的监听配置运行时不做任何事；配置变化时先停止旧服务器再启动
+///
+/// # 参数
+/// * `configures` - 监听配置
+pub async fn start_broker(configures: &[BrokerConfigure]) -> Result<(), Error> {
+    let mut task = BROKER_TASK.lock().await;
+    if task
+        .as_ref()
+        .is_some_and(|(running, handle)| running == configures && !handle.is_finished())
+    {
+        return Ok(());
+    }
+    if let Some((_, handle)) = task.take() {
+        handle.abort();
+        let _ = handle.await;
+    }
+    *task = Some((configures.to_vec(), spawn_broker(configures).await?));
+    Ok(())
+}
+
+#[cfg(feature = "broker")]
+async fn spawn_broker(configures: &[BrokerConfigure]) -> Result<JoinHandle<()>, Error> {
+    // 端口在这里已经绑定，返回后客户端即可连接
+    let server = build_mqtt_broker(configures).await?;
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            eprintln!("内置服务器运行失败: {:?}", e);
+        }
+    }))
+}
+
+#[cfg(not(feature = "broker"))]
+async fn spawn_broker(_configures: &[BrokerConfigure]) -> Result<JoinHandle<()>, Error> {
+    Err(format_err!(
+        "当前版本编译时未启用 broker 功能，无法启动内置服务器"
+    ))
+}
+
+/// 停止内置服务器，释放监听端口
+pub async fn stop_broker() {
+    if let Some((_, handle)) = BROKER_TASK.lock().await.take() {
+        handle.abort();
+        let _ = handle.await;
+    }
+}
+
+#[cfg(feature = "broker")]
+#[tokio::test]
+async fn test_embedded_broker_round_trip() {
+    use crate::mqtt::connect::MQTTConfig;
+    use crate::mqtt::receiver::mqtt_receiver_with_config;
+    use crate::mqtt::reconnect::wait_connected;
+    use crate::mqtt::sender::mqtt_publish_once;
+    use rumqttc::QoS;
+    use std::time::Duration;
+
+    let listeners = vec![BrokerConfigure::new("tcp", "tcp", [127, 0, 0, 1], 18831)];
+    start_broker(&listeners).await.unwrap();
+    let config = MQTTConfig {
+        host: "127.0.0.1:18831".to_string(),
+        client_id_prefix: "broker_test".to_string(),
+        ..MQTTConfig::default()
+    };
+    let mut receiver = mqtt_receiver_with_config(&config, "broker/test", "test").await.unwrap();
+    let client_id = receiver.client_id().to_string();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    receiver.add_callback(move |msg| {
+        let _ = tx.send(msg.to_string());
+    });
+    tokio::spawn(async move { receiver.start().await });
+    assert!(wait_connected(&client_id, Duration::from_secs(5)).await);
+    // 等待订阅生效
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let timeout = Duration::from_secs(5);
+    // QoS 2 需要完整走完 PUBREC/PUBREL/PUBCOMP 才返回
+    mqtt_publish_once(&config, "broker_test_pub", "broker/test", "hello", QoS::ExactlyOnce, false, timeout)
+        .await
+        .unwrap();
+    let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+    assert_eq!(msg.as_deref(), Some("hello"));
+    stop_broker().await;
+}
@@ -0,0 +1,444 @@
+use crate::UPDATE_STATE_EVENT;
+use crate::command_store::{StoredCommand, load_commands, load_macros, save_macros};
+use crate::command_tracker::{CommandStatus, wait_command};
+use crate::mqtt_handle::{NODES, send_or_queue_command};
+use crate::rule_engine::CompareOp;
+use anyhow::{Error, format_err};
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tokio::time::Instant;
+
+// 命令宏中的一步
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MacroStep {
+    // 发送命令库中的命令，`wait_reply` 为 true 时等待节点回复成功后再继续
+    Command { command: String, wait_reply: bool },
+    // 等待一段时间
+    Delay { secs: u64 },
+    // 等待节点状态满足条件，超时则宏失败
+    WaitState {
+        node: String,
+        key: String,
+        op: CompareOp,
+        value: String,
+        timeout_secs: u64,
+    },
+}
+
+impl MacroStep {
+    /// 步骤的显示文本，例如 "等待 5 秒"
+    pub fn describe(&self) -> String {
+        match self {
+            MacroStep::Command { command, .. } => format!("发送 {}", command),
+            MacroStep::Delay { secs } => format!("等待 {} 秒", secs),
+            MacroStep::WaitState {
+                node, key, op, value, ..
+            } => format!("等待 {}.{} {} {}", node, key, op.as_str(), value),
+        }
+    }
+
+    /// 检查步骤是否完整
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            MacroStep::Command { command, .. } if command.trim().is_empty() => {
+                Err(format_err!("需要选择发送的命令"))
+            }
+            MacroStep::WaitState { node, .. } if node.trim().is_empty() => {
+                Err(format_err!("需要填写节点ID"))
+            }
+            MacroStep::WaitState { key, .. } if key.trim().is_empty() => {
+                Err(format_err!("需要填写状态项"))
+            }
+            MacroStep::WaitState { timeout_secs: 0, .. } => Err(format_err!("超时时间必须大于0")),
+            _ => Ok(()),
+        }
+    }
+}
+
+// 命令宏：按顺序执行的命令、延时和等待状态
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommandMacro {
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+}
+
+// 命令宏的执行状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroState {
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl MacroState {
+    /// 界面使用的状态标识
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MacroState::Running => "running",
+            MacroState::Done => "done",
+            MacroState::Failed => "failed",
+            MacroState::Cancelled => "cancelled",
+        }
+    }
+}
+
+// 命令宏的执行进度
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroProgress {
+    pub state: MacroState,
+    // 当前（或停止时）的步骤，从 1 开始
+    pub step: usize,
+    pub total: usize,
+    // 当前步骤的说明或失败原因
+    pub message: String,
+}
+
+/// 已保存的命令宏
+pub static MACROS: Lazy<Mutex<Vec<CommandMacro>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// 每个命令宏最近一次执行的进度
+pub static MACRO_STATUS: Lazy<Mutex<HashMap<String, MacroProgress>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 命令宏进度变化时触发，供界面刷新
+pub static MACRO_STATUS_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+// 执行中的命令宏，用于取消
+static RUNNING: Lazy<Mutex<HashMap<String, AbortHandle>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn set_progress(name: &str, progress: MacroProgress) {
+    MACRO_STATUS
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), progress);
+    MACRO_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+/// 从命令库文件重新加载命令宏
+pub fn reload_macros() -> Result<(), Error> {
+    let macros = load_macros()?;
+    *MACROS.lock().map_err(|_| format_err!("读取命令宏失败"))? = macros;
+    Ok(())
+}
+
+/// 按名称获取命令宏
+pub fn find_macro(name: &str) -> Option<CommandMacro> {
+    MACROS.lock().ok()?.iter().find(|m| m.name == name).cloned()
+}
+
+// 修改命令宏并保存到命令库文件，保存成功后才生效
+fn update_macros(f: impl FnOnce(&mut Vec<CommandMacro>) -> Result<(), Error>) -> Result<(), Error> {
+    let mut macros = MACROS.lock().map_err(|_| format_err!("读取命令宏失败"))?;
+    let mut updated = macros.clone();
+    f(&mut updated)?;
+    save_macros(&updated)?;
+    *macros = updated;
+    Ok(())
+}
+
+/// 新增或修改命令宏（可同时改名）
+///
+/// # 参数
+/// * `original` - 修改前的名称，新增时为 None
+/// * `command_macro` - 新的命令宏，名称不能与其它命令宏重复
+pub fn save_macro(original: Option<&str>, command_macro: CommandMacro) -> Result<(), Error> {
+    if command_macro.name.trim().is_empty() {
+        return Err(format_err!("名称不能为空"));
+    }
+    if command_macro.steps.is_empty() {
+        return Err(format_err!("至少需要一个步骤"));
+    }
+    for (i, step) in command_macro.steps.iter().enumerate() {
+        step.validate()
+            .map_err(|e| format_err!("第 {} 步: {}", i + 1, e))?;
+    }
+    update_macros(|macros| {
+        if macros
+            .iter()
+            .any(|m| m.name == command_macro.name && Some(m.name.as_str()) != original)
+        {
+            return Err(format_err!("命令宏 {} 已存在", command_macro.name));
+        }
+        match original.and_then(|name| macros.iter().position(|m| m.name == name)) {
+            Some(index) => macros[index] = command_macro,
+            None => macros.push(command_macro),
+        }
+        Ok(())
+    })
+}
+
+/// 删除命令宏
+pub fn remove_macro(name: &str) -> Result<(), Error> {
+    update_macros(|macros| {
+        macros.retain(|m| m.name != name);
+        Ok(())
+    })?;
+    MACRO_STATUS.lock().unwrap().remove(name);
+    Ok(())
+}
+
+/// 在后台执行命令宏，进度写入 `MACRO_STATUS`
+///
+/// 需要在 tokio 运行时中调用；同一个命令宏正在执行时返回错误
+pub fn start_macro(command_macro: CommandMacro) -> Result<(), Error> {
+    let mut running = RUNNING.lock().unwrap();
+    if running.contains_key(&command_macro.name) {
+        return Err(format_err!("命令宏 {} 正在执行", command_macro.name));
+    }
+    let name = command_macro.name.clone();
+    set_progress(
+        &name,
+        MacroProgress {
+            state: MacroState::Running,
+            step: 0,
+            total: command_macro.steps.len(),
+            message: String::new(),
+        },
+    );
+    let task = tokio::spawn(async move {
+        let result = run_macro(&command_macro).await;
+        // 已被取消时不再覆盖状态
+        if RUNNING.lock().unwrap().remove(&command_macro.name).is_none() {
+            return;
+        }
+        let step = MACRO_STATUS
+            .lock()
+            .unwrap()
+            .get(&command_macro.name)
+            .map(|p| p.step)
+            .unwrap_or_default();
+        let (state, message) = match result {
+            Ok(()) => (MacroState::Done, String::new()),
+            Err(e) => (MacroState::Failed, e.to_string()),
+        };
+        set_progress(
+            &command_macro.name,
+            MacroProgress {
+                state,
+                step,
+                total: command_macro.steps.len(),
+                message,
+            },
+        );
+    });
+    running.insert(name, task.abort_handle());
+    Ok(())
+}
+
+/// 取消执行中的命令宏，已发出的命令不会撤回
+///
+/// # 返回值
+/// 命令宏正在执行时返回 true
+pub fn cancel_macro(name: &str) -> bool {
+    let Some(task) = RUNNING.lock().unwrap().remove(name) else {
+        return false;
+    };
+    task.abort();
+    let progress = MACRO_STATUS.lock().unwrap().get(name).cloned();
+    if let Some(mut progress) = progress {
+        progress.state = MacroState::Cancelled;
+        progress.message = String::new();
+        set_progress(name, progress);
+    }
+    true
+}
+
+/// 按顺序执行命令宏的每一步，任意一步失败时停止
+///
+/// 命令在开始执行时从命令库读取，宏中引用的命令不存在时不会发送任何命令；
+/// 未连接时命令进入发送队列，需要等待回复的步骤等到命令发出并收到回复后才继续
+pub async fn run_macro(command_macro: &CommandMacro) -> Result<(), Error> {
+    let commands: HashMap<String, StoredCommand> = load_commands()?
+        .into_iter()
+        .map(|c| (c.name.clone(), c))
+        .collect();
+    for step in &command_macro.steps {
+        if let MacroStep::Command { command, .. } = step
+            && !commands.contains_key(command)
+        {
+            return Err(format_err!("命令库中没有命令: {}", command));
+        }
+    }
+    let total = command_macro.steps.len();
+    for (i, step) in command_macro.steps.iter().enumerate() {
+        set_progress(
+            &command_macro.name,
+            MacroProgress {
+                state: MacroState::Running,
+                step: i + 1,
+                total,
+                message: step.describe(),
+            },
+        );
+        match step {
+            MacroStep::Command {
+                command,
+                wait_reply,
+            } => {
+                let id = send_or_queue_command(&commands[command]).await?.id();
+                if *wait_reply {
+                    let (status, message) = wait_command(&id).await;
+                    if status != CommandStatus::Acked {
+                        return Err(format_err!(
+                            "{} {}{}",
+                            command,
+                            status.as_str(),
+                            message.map(|m| format!(": {}", m)).unwrap_or_default()
+                        ));
+                    }
+                }
+            }
+            MacroStep::Delay { secs } => tokio::time::sleep(Duration::from_secs(*secs)).await,
+            MacroStep::WaitState {
+                node,
+                key,
+                op,
+                value,
+                timeout_secs,
+            } => {
+                let deadline = Instant::now() + Duration::from_secs(*timeout_secs);
+                loop {
+                    let listener = UPDATE_STATE_EVENT.listen();
+                    let matched = NODES
+                        .lock()
+                        .await
+                        .get(node)
+                        .and_then(|n| n.state.get(key))
+                        .is_some_and(|v| op.compare(v, value));
+                    if matched {
+                        break;
+                    }
+                    if tokio::time::timeout_at(deadline, listener).await.is_err() {
+                        return Err(format_err!("{} 超时", step.describe()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_macro_step_serde_and_validate() {
+    let command_macro = CommandMacro {
+        name: "startup".to_string(),
+        steps: vec![
+            MacroStep::Command {
+                command: "mode".to_string(),
+                wait_reply: true,
+            },
+            MacroStep::Delay { secs: 5 },
+            MacroStep::WaitState {
+                node: "n1".to_string(),
+                key: "status".to_string(),
+                op: CompareOp::Eq,
+                value: "ready".to_string(),
+                timeout_secs: 30,
+            },
+        ],
+    };
+    let json = serde_json::to_value(&command_macro).unwrap();
+    assert_eq!(json["steps"][1], serde_json::json!({"type": "delay", "secs": 5}));
+    assert_eq!(json["steps"][2]["op"], "==");
+    assert_eq!(serde_json::from_value::<CommandMacro>(json).unwrap(), command_macro);
+    assert_eq!(command_macro.steps[2].describe(), "等待 n1.status == ready");
+    assert!(MacroStep::Command { command: String::new(), wait_reply: false }.validate().is_err());
+    assert!(command_macro.steps.iter().all(|s| s.validate().is_ok()));
+}
+
+#[tokio::test]
+async fn test_macro_over_loopback() {
+    use crate::command_store::{Delivery, use_commands_in_memory};
+    use crate::command_tracker::handle_ack;
+    use crate::mqtt::transport::LoopbackBroker;
+    use crate::mqtt_handle::use_loopback_pool;
+    use crate::node_registry::NodeInfo;
+    use chrono::Local;
+    use std::collections::BTreeMap;
+
+    let broker = LoopbackBroker::new();
+    let _pool = use_loopback_pool(&broker).await;
+    use_commands_in_memory(&[StoredCommand {
+        name: "macro_test".to_string(),
+        topic: "test/macro".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    }]);
+    let send = |wait_reply| MacroStep::Command {
+        command: "macro_test".to_string(),
+        wait_reply,
+    };
+    let wait_state = |value: &str, timeout_secs| MacroStep::WaitState {
+        node: "macro-node".to_string(),
+        key: "mode".to_string(),
+        op: CompareOp::Eq,
+        value: value.to_string(),
+        timeout_secs,
+    };
+    let step = |name: &str| MACRO_STATUS.lock().unwrap().get(name).map(|p| p.step);
+    let sleep = |ms| tokio::time::sleep(Duration::from_millis(ms));
+
+    // 按顺序执行：等待回复 -> 延时 -> 等待状态 -> 发送
+    let command_macro = CommandMacro {
+        name: "macro_order".to_string(),
+        steps: vec![send(true), MacroStep::Delay { secs: 1 }, wait_state("auto", 5), send(false)],
+    };
+    let task = tokio::spawn(async move { run_macro(&command_macro).await });
+    sleep(100).await;
+    let published = broker.published();
+    assert_eq!(published.len(), 1);
+    assert_eq!(step("macro_order"), Some(1));
+    let payload: serde_json::Value = serde_json::from_str(&published[0].1.payload).unwrap();
+    let acked = Instant::now();
+    handle_ack(&format!(r#"{{"id": {}, "status": "ok"}}"#, payload["id"]));
+    sleep(300).await;
+    assert_eq!(step("macro_order"), Some(2));
+    sleep(1000).await;
+    assert_eq!(step("macro_order"), Some(3));
+    assert_eq!(broker.published().len(), 1);
+    let now = Local::now();
+    NODES.lock().await.insert(NodeInfo {
+        id: "macro-node".to_string(),
+        position_type: 1,
+        position: vec![],
+        state: BTreeMap::from([("mode".to_string(), "auto".to_string())]),
+        registered_at: now,
+        updated_at: now,
+    });
+    UPDATE_STATE_EVENT.notify(usize::MAX);
+    task.await.unwrap().unwrap();
+    assert!(acked.elapsed() >= Duration::from_secs(1));
+    assert_eq!(broker.published().len(), 2);
+
+    // 等待状态超时后停止，之后的命令不发送
+    let timeout = CommandMacro {
+        name: "macro_timeout".to_string(),
+        steps: vec![wait_state("manual", 1), send(false)],
+    };
+    let error = run_macro(&timeout).await.unwrap_err();
+    assert!(error.to_string().contains("超时"), "{}", error);
+    assert_eq!(broker.published().len(), 2);
+
+    // 执行中取消，之后的步骤不再执行
+    let cancelled = CommandMacro {
+        name: "macro_cancel".to_string(),
+        steps: vec![MacroStep::Delay { secs: 30 }, send(false)],
+    };
+    start_macro(cancelled.clone()).unwrap();
+    assert!(start_macro(cancelled).is_err());
+    sleep(100).await;
+    assert_eq!(step("macro_cancel"), Some(1));
+    assert!(cancel_macro("macro_cancel"));
+    assert!(!cancel_macro("macro_cancel"));
+    sleep(100).await;
+    let progress = MACRO_STATUS.lock().unwrap().get("macro_cancel").cloned().unwrap();
+    assert_eq!(progress.state, MacroState::Cancelled);
+    assert_eq!(broker.published().len(), 2);
+}
@@ -0,0 +1,142 @@
+use anyhow::{Error, format_err};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// 命令参数类型
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    Float,
+    Int,
+    Bool,
+    String,
+    Enum,
+    Object,
+}
+
+impl ParamType {
+    /// 所有类型，顺序与编辑界面的下拉框一致
+    pub const ALL: [ParamType; 6] = [
+        ParamType::Float,
+        ParamType::Int,
+        ParamType::Bool,
+        ParamType::String,
+        ParamType::Enum,
+        ParamType::Object,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParamType::Float => "float",
+            ParamType::Int => "int",
+            ParamType::Bool => "bool",
+            ParamType::String => "string",
+            ParamType::Enum => "enum",
+            ParamType::Object => "object",
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<ParamType, Error> {
+        ParamType::ALL
+            .into_iter()
+            .find(|t| t.as_str() == text)
+            .ok_or_else(|| format_err!("未知的参数类型: {}", text))
+    }
+}
+
+// 带类型的命令参数
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommandParam {
+    pub key: String,
+    #[serde(rename = "type", default)]
+    pub param_type: ParamType,
+    pub value: Value,
+    // 枚举类型的可选值
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl CommandParam {
+    /// 按声明的类型解析编辑框中的文本
+    ///
+    /// # 参数
+    /// * `key` - 参数名
+    /// * `param_type` - 参数类型
+    /// * `text` - 编辑框中的值
+    /// * `options` - 枚举可选值，逗号分隔
+    pub fn parse(key: &str, param_type: ParamType, text: &str, options: &str) -> Result<Self, Error> {
+        let text = text.trim();
+        let options: Vec<String> = options
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .map(str::to_string)
+            .collect();
+        let value = match param_type {
+            ParamType::Float => text
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .map(Value::from)
+                .ok_or_else(|| format_err!("\"{}\" 不是有效的数字", text))?,
+            ParamType::Int => text
+                .parse::<i64>()
+                .map(Value::from)
+                .map_err(|_| format_err!("\"{}\" 不是有效的整数", text))?,
+            ParamType::Bool => match text.to_ascii_lowercase().as_str() {
+                "true" | "1" => Value::Bool(true),
+                "false" | "0" => Value::Bool(false),
+                _ => return Err(format_err!("\"{}\" 不是 true 或 false", text)),
+            },
+            ParamType::String => Value::from(text),
+            ParamType::Enum => {
+                if options.is_empty() {
+                    return Err(format_err!("枚举参数需要填写可选值"));
+                }
+                if !options.iter().any(|o| o == text) {
+                    return Err(format_err!("\"{}\" 不在可选值 {} 中", text, options.join(",")));
+                }
+                Value::from(text)
+            }
+            ParamType::Object => {
+                let value: Value = serde_json::from_str(text)
+                    .map_err(|e| format_err!("JSON 格式错误: {}", e))?;
+                if !value.is_object() && !value.is_array() {
+                    return Err(format_err!("需要 JSON 对象或数组"));
+                }
+                value
+            }
+        };
+        Ok(CommandParam {
+            key: key.to_string(),
+            param_type,
+            value,
+            options: if param_type == ParamType::Enum { options } else { Vec::new() },
+        })
+    }
+
+    /// 编辑框中显示的值，字符串不带引号
+    pub fn value_text(&self) -> String {
+        match &self.value {
+            Value::String(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_parse_typed_params() {
+    let p = CommandParam::parse("speed", ParamType::Float, "75.5", "").unwrap();
+    assert_eq!(p.value, Value::from(75.5));
+    assert!(CommandParam::parse("speed", ParamType::Float, "fast", "").is_err());
+    assert_eq!(CommandParam::parse("n", ParamType::Int, "3", "").unwrap().value, Value::from(3));
+    assert!(CommandParam::parse("n", ParamType::Int, "3.5", "").is_err());
+    assert_eq!(CommandParam::parse("on", ParamType::Bool, "TRUE", "").unwrap().value, Value::Bool(true));
+    let mode = CommandParam::parse("mode", ParamType::Enum, "auto", "auto, manual").unwrap();
+    assert_eq!(mode.options, vec!["auto", "manual"]);
+    assert!(CommandParam::parse("mode", ParamType::Enum, "off", "auto,manual").is_err());
+    let obj = CommandParam::parse("limits", ParamType::Object, r#"{"max": 3}"#, "").unwrap();
+    assert_eq!(obj.value_text(), r#"{"max":3}"#);
+    assert!(CommandParam::parse("limits", ParamType::Object, "3", "").is_err());
+}
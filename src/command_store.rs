@@ -0,0 +1,675 @@
+use crate::app_dir::app_config_dir;
+use crate::command_macro::{CommandMacro, MacroStep};
+use crate::command_param::CommandParam;
+use crate::mqtt::transport::parse_qos;
+use crate::rule_engine::Rule;
+use crate::scheduler::Schedule;
+use anyhow::{Error, format_err};
+use once_cell::sync::Lazy;
+use rumqttc::QoS;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 当前命令库文件的结构版本
+///
+/// * 0: 无版本号，以命令名为键
+/// * 1: 命令列表，参数只有浮点数
+/// * 2: 参数带类型
+/// * 3: 命令带发布的 QoS 和 retain，之后增加的规则、命令宏和定时任务缺省为空列表
+pub const STORE_VERSION: u32 = 3;
+
+const STORE_FILE_NAME: &str = "commands.json";
+
+// 命令库文件的修改时间和长度，文件不存在时为 None
+type FileStamp = Option<(SystemTime, u64)>;
+
+// 命令库的内存副本及读取时文件的状态
+struct CachedStore {
+    file: CommandStoreFile,
+    stamp: FileStamp,
+}
+
+// 按文件路径保存的命令库内存副本；所有读写都先取得这把锁，避免本进程内并发的读-改-写互相覆盖。
+// 每次使用前比较文件的修改时间和长度，其它进程或手工修改过文件时重新读取
+static STORE_CACHE: Lazy<Mutex<HashMap<PathBuf, CachedStore>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// 测试使用内存中的默认命令库时不读写文件
+#[cfg(test)]
+static IN_MEMORY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+#[cfg(test)]
+fn in_memory(path: &Path) -> bool {
+    IN_MEMORY.load(Ordering::Relaxed) && path == store_path()
+}
+
+#[cfg(not(test))]
+fn in_memory(_path: &Path) -> bool {
+    false
+}
+
+// 临时文件序号，每次写入使用不同的临时文件
+static TMP_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+// 命令的发布方式
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Delivery {
+    // 0、1、2
+    pub qos: u8,
+    // 作为保留消息发布，之后订阅该主题的节点也会收到
+    pub retain: bool,
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Delivery {
+            qos: 1,
+            retain: false,
+        }
+    }
+}
+
+impl Delivery {
+    pub fn qos_level(&self) -> Result<QoS, Error> {
+        parse_qos(self.qos)
+    }
+}
+
+// 持久化的命令
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredCommand {
+    pub name: String,
+    pub topic: String,
+    pub values: Vec<CommandParam>,
+    #[serde(flatten)]
+    pub delivery: Delivery,
+}
+
+// 命令库文件
+#[derive(Serialize, Deserialize, Clone)]
+struct CommandStoreFile {
+    version: u32,
+    commands: Vec<StoredCommand>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    macros: Vec<CommandMacro>,
+    #[serde(default)]
+    schedules: Vec<Schedule>,
+}
+
+impl CommandStoreFile {
+    // 引用命令的规则、命令宏和定时任务，例如 "规则 stop"
+    fn references(&self, name: &str) -> Vec<String> {
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| rule.command == name)
+            .map(|rule| format!("规则 {}", rule.name));
+        let macros = self
+            .macros
+            .iter()
+            .filter(|m| {
+                m.steps
+                    .iter()
+                    .any(|step| matches!(step, MacroStep::Command { command, .. } if command == name))
+            })
+            .map(|m| format!("命令宏 {}", m.name));
+        let schedules = self
+            .schedules
+            .iter()
+            .filter(|schedule| schedule.command == name)
+            .map(|schedule| format!("定时任务 {}", schedule.name));
+        rules.chain(macros).chain(schedules).collect()
+    }
+
+    // 把规则、命令宏和定时任务中对命令 `old` 的引用改为 `new`
+    fn rename_references(&mut self, old: &str, new: &str) {
+        let rename = |command: &mut String| {
+            if command == old {
+                *command = new.to_string();
+            }
+        };
+        self.rules.iter_mut().for_each(|rule| rename(&mut rule.command));
+        for step in self.macros.iter_mut().flat_map(|m| m.steps.iter_mut()) {
+            if let MacroStep::Command { command, .. } = step {
+                rename(command);
+            }
+        }
+        self.schedules
+            .iter_mut()
+            .for_each(|schedule| rename(&mut schedule.command));
+    }
+}
+
+// 版本 0: 无版本号, 直接以命令名为键 {"name": {"topic": "...", "values": [["k", 1.0]]}}
+#[derive(Deserialize)]
+struct LegacyCommandV0 {
+    topic: String,
+    values: Vec<(String, f64)>,
+}
+
+/// 命令库文件路径
+pub fn store_path() -> PathBuf {
+    app_config_dir().join(STORE_FILE_NAME)
+}
+
+/// 从默认位置加载命令库
+///
+/// 只在首次读取时访问文件，之后返回内存中的副本。
+/// 文件不存在时返回空列表；文件损坏时会把原文件改名备份，并返回错误说明
+pub fn load_commands() -> Result<Vec<StoredCommand>, Error> {
+    load_commands_from(&store_path())
+}
+
+/// 从指定文件加载命令库
+///
+/// # 参数
+/// * `path` - 命令库文件路径
+///
+/// # 返回值
+/// 返回按保存顺序排列的命令列表
+pub fn load_commands_from(path: &Path) -> Result<Vec<StoredCommand>, Error> {
+    read_store(path, |file| file.commands.clone())
+}
+
+/// 从默认位置加载自动执行规则
+pub fn load_rules() -> Result<Vec<Rule>, Error> {
+    load_rules_from(&store_path())
+}
+
+/// 从指定的命令库文件加载自动执行规则
+pub fn load_rules_from(path: &Path) -> Result<Vec<Rule>, Error> {
+    read_store(path, |file| file.rules.clone())
+}
+
+/// 从默认位置加载命令宏
+pub fn load_macros() -> Result<Vec<CommandMacro>, Error> {
+    load_macros_from(&store_path())
+}
+
+/// 从指定的命令库文件加载命令宏
+pub fn load_macros_from(path: &Path) -> Result<Vec<CommandMacro>, Error> {
+    read_store(path, |file| file.macros.clone())
+}
+
+/// 从默认位置加载定时任务
+pub fn load_schedules() -> Result<Vec<Schedule>, Error> {
+    load_schedules_from(&store_path())
+}
+
+/// 从指定的命令库文件加载定时任务
+pub fn load_schedules_from(path: &Path) -> Result<Vec<Schedule>, Error> {
+    read_store(path, |file| file.schedules.clone())
+}
+
+fn file_stamp(path: &Path) -> FileStamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// 取得命令库的内存副本，文件在上次读写之后被修改过时重新读取
+fn cached_store<'a>(
+    cache: &'a mut HashMap<PathBuf, CachedStore>,
+    path: &Path,
+) -> Result<&'a mut CachedStore, Error> {
+    if in_memory(path) {
+        return cache
+            .get_mut(path)
+            .ok_or_else(|| format_err!("读取命令库失败"));
+    }
+    let stamp = file_stamp(path);
+    if cache.get(path).is_none_or(|cached| cached.stamp != stamp) {
+        let file = load_store_from(path)?;
+        cache.insert(path.to_path_buf(), CachedStore { file, stamp });
+    }
+    cache
+        .get_mut(path)
+        .ok_or_else(|| format_err!("读取命令库失败"))
+}
+
+// 读取命令库，文件未被修改时使用内存副本
+fn read_store<R>(path: &Path, f: impl FnOnce(&CommandStoreFile) -> R) -> Result<R, Error> {
+    let mut cache = STORE_CACHE.lock().map_err(|_| format_err!("读取命令库失败"))?;
+    Ok(f(&cached_store(&mut cache, path)?.file))
+}
+
+// 在同一把锁内完成读-改-写，写入成功后才更新内存副本
+fn update_store(path: &Path, f: impl FnOnce(&mut CommandStoreFile)) -> Result<(), Error> {
+    let mut cache = STORE_CACHE.lock().map_err(|_| format_err!("读取命令库失败"))?;
+    let cached = cached_store(&mut cache, path)?;
+    let mut file = cached.file.clone();
+    f(&mut file);
+    if !in_memory(path) {
+        write_store_to(path, &file)?;
+        cached.stamp = file_stamp(path);
+    }
+    cached.file = file;
+    Ok(())
+}
+
+fn load_store_from(path: &Path) -> Result<CommandStoreFile, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(CommandStoreFile {
+                version: STORE_VERSION,
+                commands: Vec::new(),
+                rules: Vec::new(),
+                macros: Vec::new(),
+                schedules: Vec::new(),
+            });
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match serde_json::from_str::<Value>(&text)
+        .map_err(Error::from)
+        .and_then(migrate)
+    {
+        Ok(file) => {
+            // 旧版本文件读取后立即以新版本写回
+            if needs_rewrite(&text) {
+                write_store_to(path, &file)?;
+            }
+            Ok(file)
+        }
+        Err(e) => {
+            let backup = backup_corrupt_file(path)?;
+            Err(format_err!(
+                "命令库文件损坏: {}，已备份到 {}",
+                e,
+                backup.display()
+            ))
+        }
+    }
+}
+
+// 测试使用内存中的默认命令库，不读写配置目录中的文件；同名命令被替换
+#[cfg(test)]
+pub(crate) fn use_commands_in_memory(commands: &[StoredCommand]) {
+    let mut cache = STORE_CACHE.lock().unwrap();
+    IN_MEMORY.store(true, Ordering::Relaxed);
+    let file = &mut cache
+        .entry(store_path())
+        .or_insert_with(|| CachedStore {
+            file: CommandStoreFile {
+                version: STORE_VERSION,
+                commands: Vec::new(),
+                rules: Vec::new(),
+                macros: Vec::new(),
+                schedules: Vec::new(),
+            },
+            stamp: None,
+        })
+        .file;
+    for command in commands {
+        file.commands.retain(|c| c.name != command.name);
+        file.commands.push(command.clone());
+    }
+}
+
+/// 将命令库保存到默认位置
+pub fn save_commands(commands: &[StoredCommand]) -> Result<(), Error> {
+    save_commands_to(&store_path(), commands)
+}
+
+/// 将命令库保存到指定文件，文件中已有的规则、命令宏和定时任务保持不变
+pub fn save_commands_to(path: &Path, commands: &[StoredCommand]) -> Result<(), Error> {
+    update_store(path, |file| file.commands = commands.to_vec())
+}
+
+/// 保存改名后的命令库，规则、命令宏和定时任务中对原名称的引用在同一次写入中一并改名
+///
+/// # 参数
+/// * `commands` - 改名后的命令库
+/// * `old` - 命令原名称
+/// * `new` - 命令新名称
+pub fn save_renamed_commands(commands: &[StoredCommand], old: &str, new: &str) -> Result<(), Error> {
+    save_renamed_commands_to(&store_path(), commands, old, new)
+}
+
+/// 保存改名后的命令库到指定文件，同时修改文件中对原名称的引用
+pub fn save_renamed_commands_to(
+    path: &Path,
+    commands: &[StoredCommand],
+    old: &str,
+    new: &str,
+) -> Result<(), Error> {
+    update_store(path, |file| {
+        file.commands = commands.to_vec();
+        file.rename_references(old, new);
+    })
+}
+
+/// 默认位置的命令库中引用该命令的规则、命令宏和定时任务
+///
+/// # 返回值
+/// 引用者的说明，例如 "规则 stop"；没有引用时为空
+pub fn command_references(name: &str) -> Result<Vec<String>, Error> {
+    command_references_in(&store_path(), name)
+}
+
+/// 指定命令库文件中引用该命令的规则、命令宏和定时任务
+pub fn command_references_in(path: &Path, name: &str) -> Result<Vec<String>, Error> {
+    read_store(path, |file| file.references(name))
+}
+
+/// 将自动执行规则保存到默认位置
+pub fn save_rules(rules: &[Rule]) -> Result<(), Error> {
+    save_rules_to(&store_path(), rules)
+}
+
+/// 将自动执行规则保存到指定的命令库文件，文件中的其它内容保持不变
+pub fn save_rules_to(path: &Path, rules: &[Rule]) -> Result<(), Error> {
+    update_store(path, |file| file.rules = rules.to_vec())
+}
+
+/// 将命令宏保存到默认位置
+pub fn save_macros(macros: &[CommandMacro]) -> Result<(), Error> {
+    save_macros_to(&store_path(), macros)
+}
+
+/// 将命令宏保存到指定的命令库文件，文件中的其它内容保持不变
+pub fn save_macros_to(path: &Path, macros: &[CommandMacro]) -> Result<(), Error> {
+    update_store(path, |file| file.macros = macros.to_vec())
+}
+
+/// 将定时任务保存到默认位置
+pub fn save_schedules(schedules: &[Schedule]) -> Result<(), Error> {
+    save_schedules_to(&store_path(), schedules)
+}
+
+/// 将定时任务保存到指定的命令库文件，文件中的其它内容保持不变
+pub fn save_schedules_to(path: &Path, schedules: &[Schedule]) -> Result<(), Error> {
+    update_store(path, |file| file.schedules = schedules.to_vec())
+}
+
+// 以当前版本写入，先写入临时文件再改名，避免写到一半时程序退出导致文件损坏
+fn write_store_to(path: &Path, file: &CommandStoreFile) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = CommandStoreFile {
+        version: STORE_VERSION,
+        ..file.clone()
+    };
+    // 临时文件名带进程号和序号，命令行工具同时写入时也不会共用
+    let tmp = path.with_extension(format!(
+        "json.tmp-{}-{}",
+        std::process::id(),
+        TMP_INDEX.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, serde_json::to_string_pretty(&file)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn needs_rewrite(text: &str) -> bool {
+    serde_json::from_str::<Value>(text)
+        .ok()
+        .and_then(|v| v.get("version").and_then(Value::as_u64))
+        != Some(STORE_VERSION as u64)
+}
+
+// 按版本逐级迁移到当前结构
+fn migrate(mut value: Value) -> Result<CommandStoreFile, Error> {
+    loop {
+        let version = match value.get("version") {
+            None => 0,
+            Some(v) => v
+                .as_u64()
+                .ok_or_else(|| format_err!("版本号格式错误: {}", v))? as u32,
+        };
+        value = match version {
+            0 => migrate_v0(value)?,
+            1 => migrate_v1(value)?,
+            2 => migrate_v2(value)?,
+            STORE_VERSION => return Ok(serde_json::from_value(value)?),
+            v => return Err(format_err!("不支持的命令库版本: {}", v)),
+        };
+    }
+}
+
+// 版本 0 -> 1: 以命令名为键的对象改为命令列表
+fn migrate_v0(value: Value) -> Result<Value, Error> {
+    let legacy: serde_json::Map<String, Value> = serde_json::from_value(value)?;
+    let mut commands = Vec::with_capacity(legacy.len());
+    for (name, v) in legacy {
+        let command: LegacyCommandV0 = serde_json::from_value(v)?;
+        let values: Vec<Value> = command
+            .values
+            .into_iter()
+            .map(|(key, value)| serde_json::json!({"key": key, "value": value}))
+            .collect();
+        commands.push(serde_json::json!({"name": name, "topic": command.topic, "values": values}));
+    }
+    Ok(serde_json::json!({"version": 1, "commands": commands}))
+}
+
+// 版本 1 -> 2: 浮点参数补充类型
+fn migrate_v1(mut value: Value) -> Result<Value, Error> {
+    let commands = value
+        .get_mut("commands")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| format_err!("缺少 commands 字段"))?;
+    for command in commands {
+        let params = command
+            .get_mut("values")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| format_err!("缺少 values 字段"))?;
+        for param in params {
+            let param = param
+                .as_object_mut()
+                .ok_or_else(|| format_err!("参数格式错误"))?;
+            if !param.get("value").is_some_and(Value::is_number) {
+                return Err(format_err!("版本 1 的参数值必须是数字"));
+            }
+            param.insert("type".to_string(), Value::from("float"));
+        }
+    }
+    value["version"] = Value::from(2);
+    Ok(value)
+}
+
+// 版本 2 -> 3: 补充发布方式，使用之前固定的 QoS 1、不保留
+fn migrate_v2(mut value: Value) -> Result<Value, Error> {
+    let commands = value
+        .get_mut("commands")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| format_err!("缺少 commands 字段"))?;
+    for command in commands {
+        let command = command
+            .as_object_mut()
+            .ok_or_else(|| format_err!("命令格式错误"))?;
+        command.insert("qos".to_string(), Value::from(1));
+        command.insert("retain".to_string(), Value::Bool(false));
+    }
+    value["version"] = Value::from(3);
+    Ok(value)
+}
+
+fn backup_corrupt_file(path: &Path) -> Result<PathBuf, Error> {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".corrupt-{}", stamp));
+    let backup = path.with_file_name(name);
+    fs::rename(path, &backup)?;
+    Ok(backup)
+}
+
+#[test]
+fn test_command_store_round_trip_and_migrate() {
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_store_{}", std::process::id()));
+    let path = dir.join(STORE_FILE_NAME);
+    let commands = vec![StoredCommand {
+        name: "fan".to_string(),
+        topic: "node/1".to_string(),
+        values: vec![CommandParam::parse("speed", crate::command_param::ParamType::Float, "75.5", "").unwrap()],
+        delivery: Delivery::default(),
+    }];
+    save_commands_to(&path, &commands).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), commands);
+
+    // 旧版本文件迁移后写回当前版本
+    fs::write(&path, r#"{"fan": {"topic": "node/1", "values": [["speed", 75.5]]}}"#).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), commands);
+    assert!(!needs_rewrite(&fs::read_to_string(&path).unwrap()));
+    fs::write(
+        &path,
+        r#"{"version": 1, "commands": [{"name": "fan", "topic": "node/1", "values": [{"key": "speed", "value": 75.5}]}]}"#,
+    )
+    .unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), commands);
+    let retained = vec![StoredCommand {
+        delivery: Delivery { qos: 2, retain: true },
+        ..commands[0].clone()
+    }];
+    save_commands_to(&path, &retained).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), retained);
+    // 规则与命令保存在同一个文件中，互不覆盖
+    let rules = vec![Rule {
+        name: "stop".to_string(),
+        key: "alert".to_string(),
+        command: "fan".to_string(),
+        ..Rule::default()
+    }];
+    save_rules_to(&path, &rules).unwrap();
+    save_commands_to(&path, &commands).unwrap();
+    assert_eq!(load_rules_from(&path).unwrap(), rules);
+    assert_eq!(load_commands_from(&path).unwrap(), commands);
+
+    // 损坏文件被备份，原位置不再存在
+    fs::write(&path, "{not json").unwrap();
+    assert!(load_commands_from(&path).is_err());
+    assert!(!path.exists());
+    assert_eq!(load_commands_from(&path).unwrap(), vec![]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_concurrent_saves_keep_each_other() {
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_store_concurrent_{}", std::process::id()));
+    let path = dir.join(STORE_FILE_NAME);
+    let commands: Vec<StoredCommand> = (0..20)
+        .map(|i| StoredCommand {
+            name: format!("cmd{}", i),
+            topic: "node/1".to_string(),
+            values: vec![],
+            delivery: Delivery::default(),
+        })
+        .collect();
+    let rules: Vec<Rule> = (0..20)
+        .map(|i| Rule {
+            name: format!("rule{}", i),
+            command: format!("cmd{}", i),
+            ..Rule::default()
+        })
+        .collect();
+    // 命令和规则同时逐条保存，读-改-写不会丢掉另一方的内容
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            for i in 1..=commands.len() {
+                save_commands_to(&path, &commands[..i]).unwrap();
+            }
+        });
+        s.spawn(|| {
+            for i in 1..=rules.len() {
+                save_rules_to(&path, &rules[..i]).unwrap();
+            }
+        });
+    });
+    assert_eq!(load_commands_from(&path).unwrap(), commands);
+    assert_eq!(load_rules_from(&path).unwrap(), rules);
+    // 不残留临时文件
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_command_references_and_rename() {
+    use crate::scheduler::ScheduleKind;
+
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_store_refs_{}", std::process::id()));
+    let path = dir.join(STORE_FILE_NAME);
+    let command = |name: &str| StoredCommand {
+        name: name.to_string(),
+        topic: "node/1".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    save_commands_to(&path, &[command("fan"), command("pump")]).unwrap();
+    save_rules_to(&path, &[Rule {
+        name: "cool".to_string(),
+        command: "fan".to_string(),
+        ..Rule::default()
+    }])
+    .unwrap();
+    save_macros_to(&path, &[CommandMacro {
+        name: "start".to_string(),
+        steps: vec![
+            MacroStep::Delay { secs: 1 },
+            MacroStep::Command { command: "fan".to_string(), wait_reply: false },
+        ],
+    }])
+    .unwrap();
+    save_schedules_to(&path, &[Schedule {
+        name: "daily".to_string(),
+        command: "pump".to_string(),
+        kind: ScheduleKind::Interval { secs: 60 },
+        ..Schedule::default()
+    }])
+    .unwrap();
+    assert_eq!(command_references_in(&path, "fan").unwrap(), ["规则 cool", "命令宏 start"]);
+    assert_eq!(command_references_in(&path, "pump").unwrap(), ["定时任务 daily"]);
+    assert!(command_references_in(&path, "heater").unwrap().is_empty());
+
+    // 改名时引用一并修改，未引用原名称的内容不变
+    save_renamed_commands_to(&path, &[command("blower"), command("pump")], "fan", "blower").unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), [command("blower"), command("pump")]);
+    assert!(command_references_in(&path, "fan").unwrap().is_empty());
+    assert_eq!(command_references_in(&path, "blower").unwrap(), ["规则 cool", "命令宏 start"]);
+    assert_eq!(load_rules_from(&path).unwrap()[0].command, "blower");
+    assert_eq!(
+        load_macros_from(&path).unwrap()[0].steps[1],
+        MacroStep::Command { command: "blower".to_string(), wait_reply: false }
+    );
+    assert_eq!(load_schedules_from(&path).unwrap()[0].command, "pump");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_external_edits_are_not_overwritten() {
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_store_external_{}", std::process::id()));
+    let path = dir.join(STORE_FILE_NAME);
+    let command = |name: &str| StoredCommand {
+        name: name.to_string(),
+        topic: "node/1".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    save_commands_to(&path, &[command("fan")]).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), [command("fan")]);
+    // 另一个实例或手工修改了文件，之后的读写以文件为准
+    let mut file = load_store_from(&path).unwrap();
+    file.commands.push(command("pump"));
+    file.rules.push(Rule {
+        name: "hot".to_string(),
+        command: "fan".to_string(),
+        ..Rule::default()
+    });
+    fs::write(&path, serde_json::to_string_pretty(&file).unwrap()).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), [command("fan"), command("pump")]);
+    save_macros_to(&path, &[]).unwrap();
+    assert_eq!(load_commands_from(&path).unwrap(), [command("fan"), command("pump")]);
+    assert_eq!(load_rules_from(&path).unwrap().len(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,278 @@
+use crate::node_state_entity::CommandAck;
+use crate::store::persist_command_outcome;
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 命令执行状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandStatus {
+    // 未连接，在发送队列中等待
+    Queued,
+    // 排队时被取消，未发送
+    Cancelled,
+    Pending,
+    Acked,
+    Failed,
+    TimedOut,
+}
+
+impl CommandStatus {
+    /// 界面使用的状态标识
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Queued => "queued",
+            CommandStatus::Cancelled => "cancelled",
+            CommandStatus::Pending => "pending",
+            CommandStatus::Acked => "acked",
+            CommandStatus::Failed => "failed",
+            CommandStatus::TimedOut => "timeout",
+        }
+    }
+}
+
+/// 等待回复的命令：关联ID -> (命令名称, 超时时间)
+static PENDING_COMMANDS: Lazy<Mutex<HashMap<String, (String, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// 状态及节点回复的附带信息
+pub type StatusEntry = (CommandStatus, Option<String>);
+
+/// 最多保留的已结束发送的状态，超过后丢弃不再显示的记录
+pub const STATUS_CAPACITY: usize = 1000;
+
+/// 命令状态表：每次发送按关联ID记录，界面按命令名称显示最近一次发送的状态
+///
+/// 同一个命令可以同时发送多次，各自的回复只更新对应关联ID的状态
+#[derive(Default)]
+pub struct StatusTable {
+    // 关联ID -> 状态
+    by_id: HashMap<String, StatusEntry>,
+    // 命令名称 -> 最近一次发送的关联ID
+    latest: HashMap<String, String>,
+}
+
+impl StatusTable {
+    /// 某次发送的状态
+    pub fn get(&self, id: &str) -> Option<&StatusEntry> {
+        self.by_id.get(id)
+    }
+
+    /// 命令最近一次发送的状态
+    pub fn latest(&self, command_name: &str) -> Option<&StatusEntry> {
+        self.by_id.get(self.latest.get(command_name)?)
+    }
+
+    /// 命令最近一次发送的关联ID
+    pub fn latest_id(&self, command_name: &str) -> Option<&str> {
+        self.latest.get(command_name).map(String::as_str)
+    }
+
+    // 记录状态，第一次出现的关联ID成为该命令最近一次发送
+    fn set(&mut self, id: &str, command_name: &str, status: CommandStatus, message: Option<String>) {
+        if !self.by_id.contains_key(id) {
+            self.latest.insert(command_name.to_string(), id.to_string());
+        }
+        self.by_id.insert(id.to_string(), (status, message));
+        if self.by_id.len() > STATUS_CAPACITY {
+            self.prune();
+        }
+    }
+
+    // 更新已有发送的状态，未知的关联ID忽略
+    fn update(&mut self, id: &str, status: CommandStatus, message: Option<String>) {
+        if let Some(entry) = self.by_id.get_mut(id) {
+            *entry = (status, message);
+        }
+    }
+
+    // 丢弃已结束且不是最近一次发送的记录
+    fn prune(&mut self) {
+        let latest = &self.latest;
+        self.by_id.retain(|id, (status, _)| {
+            matches!(status, CommandStatus::Queued | CommandStatus::Pending)
+                || latest.values().any(|l| l == id)
+        });
+    }
+
+    /// 不再显示命令的状态，命令被删除或改名时使用
+    pub fn forget(&mut self, command_name: &str) {
+        self.latest.remove(command_name);
+    }
+}
+
+/// 命令状态，按关联ID记录
+pub static COMMAND_STATUS: Lazy<Mutex<StatusTable>> =
+    Lazy::new(|| Mutex::new(StatusTable::default()));
+
+/// 命令状态变化时触发，供界面刷新
+pub static COMMAND_STATUS_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+/// 生成命令关联ID
+pub fn new_correlation_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn set_status(id: &str, command_name: &str, status: CommandStatus, message: Option<String>) {
+    COMMAND_STATUS
+        .lock()
+        .unwrap()
+        .set(id, command_name, status, message);
+    COMMAND_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+fn update_status(id: &str, status: CommandStatus, message: Option<String>) {
+    COMMAND_STATUS.lock().unwrap().update(id, status, message);
+    COMMAND_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+/// 命令最近一次发送的状态，供界面显示
+pub fn command_status(command_name: &str) -> Option<StatusEntry> {
+    COMMAND_STATUS.lock().unwrap().latest(command_name).cloned()
+}
+
+// 已发送的命令得到结果，同时写入本地数据库的命令记录
+fn finish(id: &str, status: CommandStatus, message: Option<String>) {
+    persist_command_outcome(id, status.as_str(), message.as_deref());
+    update_status(id, status, message);
+}
+
+/// 记录已发送的命令，超时仍未收到回复时标记为超时
+///
+/// # 参数
+/// * `id` - 关联ID
+/// * `command_name` - 命令名称
+/// * `timeout` - 等待回复的时间
+pub fn track_command(id: &str, command_name: &str, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    PENDING_COMMANDS
+        .lock()
+        .unwrap()
+        .insert(id.to_string(), (command_name.to_string(), deadline));
+    set_status(id, command_name, CommandStatus::Pending, None);
+    let id = id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(timeout).await;
+        // 排队的命令重发时使用相同的关联ID，只处理本次发送的超时
+        let expired = {
+            let mut pending = PENDING_COMMANDS.lock().unwrap();
+            let expired = pending.get(&id).is_some_and(|(_, d)| *d <= Instant::now());
+            if expired {
+                pending.remove(&id);
+            }
+            expired
+        };
+        if expired {
+            finish(&id, CommandStatus::TimedOut, None);
+        }
+    });
+}
+
+/// 命令发送失败，不再等待回复
+pub fn mark_send_failed(id: &str, message: String) {
+    let pending = PENDING_COMMANDS.lock().unwrap().remove(id);
+    if pending.is_some() {
+        finish(id, CommandStatus::Failed, Some(message));
+    }
+}
+
+/// 排队的命令因连接中断未能发出，回到队列等待下次连接，不记为失败
+pub fn mark_requeued(id: &str) {
+    PENDING_COMMANDS.lock().unwrap().remove(id);
+    update_status(id, CommandStatus::Queued, None);
+}
+
+/// 命令已进入发送队列，等待连接恢复
+pub fn mark_queued(id: &str, command_name: &str) {
+    set_status(id, command_name, CommandStatus::Queued, None);
+}
+
+/// 排队中的命令被取消
+pub fn mark_cancelled(id: &str) {
+    update_status(id, CommandStatus::Cancelled, None);
+}
+
+/// 排队中的命令已过期，不再发送
+pub fn mark_expired(id: &str) {
+    update_status(
+        id,
+        CommandStatus::Failed,
+        Some("排队超过有效期，未发送".to_string()),
+    );
+}
+
+/// 等待某次发送得到结果（回复、失败、超时或取消）
+///
+/// # 参数
+/// * `id` - 发送时的关联ID，需要已经通过 `track_command` 或 `mark_queued` 记录
+pub async fn wait_command(id: &str) -> StatusEntry {
+    loop {
+        let listener = COMMAND_STATUS_EVENT.listen();
+        let entry = COMMAND_STATUS.lock().unwrap().get(id).cloned();
+        if let Some(entry) = entry.filter(|(status, _)| {
+            !matches!(status, CommandStatus::Queued | CommandStatus::Pending)
+        }) {
+            return entry;
+        }
+        listener.await;
+    }
+}
+
+/// 处理节点回复的消息
+///
+/// # 返回值
+/// 消息是某个等待中命令的回复时返回 true
+pub fn handle_ack(payload: &str) -> bool {
+    let Ok(ack) = serde_json::from_str::<CommandAck>(payload) else {
+        eprintln!("Command ack {} not recognized", payload);
+        return false;
+    };
+    let pending = PENDING_COMMANDS.lock().unwrap().remove(&ack.id);
+    if pending.is_none() {
+        // 已超时或不是本程序发出的命令
+        return false;
+    }
+    let status = match ack.status.to_ascii_lowercase().as_str() {
+        "ok" | "acked" | "success" => CommandStatus::Acked,
+        _ => CommandStatus::Failed,
+    };
+    finish(&ack.id, status, ack.message);
+    true
+}
+
+#[tokio::test]
+async fn test_command_ack_and_timeout() {
+    let status = |name: &str| command_status(name).map(|s| s.0);
+
+    track_command("ack-1", "test_ack", Duration::from_secs(10));
+    assert_eq!(status("test_ack"), Some(CommandStatus::Pending));
+    assert!(handle_ack(r#"{"id": "ack-1", "status": "ok"}"#));
+    assert_eq!(status("test_ack"), Some(CommandStatus::Acked));
+    // 重复回复不再处理
+    assert!(!handle_ack(r#"{"id": "ack-1", "status": "failed"}"#));
+
+    track_command("ack-2", "test_timeout", Duration::from_millis(20));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(status("test_timeout"), Some(CommandStatus::TimedOut));
+    assert!(!handle_ack(r#"{"id": "ack-2", "status": "ok"}"#));
+}
+
+#[tokio::test]
+async fn test_status_per_send() {
+    // 同一个命令的两次发送，回复顺序与发送顺序相反
+    track_command("same-1", "test_same", Duration::from_secs(10));
+    track_command("same-2", "test_same", Duration::from_secs(10));
+    assert_eq!(COMMAND_STATUS.lock().unwrap().latest_id("test_same"), Some("same-2"));
+    assert!(handle_ack(r#"{"id": "same-2", "status": "failed", "message": "busy"}"#));
+    assert!(handle_ack(r#"{"id": "same-1", "status": "ok"}"#));
+    let status = COMMAND_STATUS.lock().unwrap();
+    assert_eq!(status.get("same-1"), Some(&(CommandStatus::Acked, None)));
+    assert_eq!(
+        status.get("same-2"),
+        Some(&(CommandStatus::Failed, Some("busy".to_string())))
+    );
+    // 界面显示最近一次发送的结果，不被较早发送的回复覆盖
+    assert_eq!(status.latest("test_same").map(|s| s.0), Some(CommandStatus::Failed));
+}
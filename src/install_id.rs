@@ -0,0 +1,61 @@
+use crate::app_dir::app_config_dir;
+use anyhow::{Error, format_err};
+use once_cell::sync::Lazy;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+const INSTALL_ID_FILE_NAME: &str = "install_id";
+
+/// 本机安装的唯一ID，首次使用时生成并保存在配置目录下，之后保持不变
+///
+/// 配置目录不可写时使用本次运行临时生成的ID
+pub static INSTALL_ID: Lazy<String> = Lazy::new(|| {
+    load_or_create_install_id(&app_config_dir().join(INSTALL_ID_FILE_NAME)).unwrap_or_else(|e| {
+        eprintln!("读取安装ID失败，使用临时ID: {:?}", e);
+        Uuid::new_v4().simple().to_string()
+    })
+});
+
+/// 读取安装ID，文件不存在或内容无效时生成新的ID并写入
+///
+/// # 参数
+/// * `path` - 保存安装ID的文件
+///
+/// # 返回值
+/// 不带连字符的 32 位十六进制字符串
+pub fn load_or_create_install_id(path: &Path) -> Result<String, Error> {
+    if let Ok(text) = fs::read_to_string(path)
+        && let Ok(id) = Uuid::parse_str(text.trim())
+    {
+        return Ok(id.simple().to_string());
+    }
+    let id = Uuid::new_v4().simple().to_string();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format_err!("创建目录 {:?} 失败: {}", dir, e))?;
+    }
+    fs::write(path, &id).map_err(|e| format_err!("写入 {:?} 失败: {}", path, e))?;
+    Ok(id)
+}
+
+/// 客户端ID中使用的安装ID简写（前 12 位）
+///
+/// 与前缀和用途拼接后一般不超过 MQTT 3.1.1 要求服务器必须支持的 23 个字符
+pub fn short_install_id() -> &'static str {
+    &INSTALL_ID[..12]
+}
+
+#[test]
+fn test_install_id_is_persisted() {
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_install_id_{}", std::process::id()));
+    let path = dir.join(INSTALL_ID_FILE_NAME);
+    let id = load_or_create_install_id(&path).unwrap();
+    assert_eq!(id.len(), 32);
+    assert_eq!(load_or_create_install_id(&path).unwrap(), id);
+    // 内容损坏时重新生成
+    fs::write(&path, "not a uuid").unwrap();
+    let regenerated = load_or_create_install_id(&path).unwrap();
+    assert_ne!(regenerated, id);
+    assert_eq!(fs::read_to_string(&path).unwrap(), regenerated);
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,26 @@
+//! MQTT 连接、命令库和节点状态的核心逻辑，图形界面和命令行客户端共用
+
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+
+pub mod app_dir;
+pub mod broker;
+pub mod command_macro;
+pub mod command_param;
+pub mod command_store;
+pub mod command_tracker;
+pub mod install_id;
+pub mod mqtt;
+pub mod mqtt_handle;
+pub mod node_registry;
+pub mod node_state_entity;
+pub mod outbox;
+pub mod profile;
+pub mod rule_engine;
+pub mod scheduler;
+pub mod state_history;
+pub mod store;
+
+/// 节点状态变化时触发，供界面刷新
+pub static UPDATE_STATE_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
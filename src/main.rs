@@ -1,73 +1,271 @@
 #![windows_subsystem = "windows"]
 
-use crate::mqtt_handle::{POOL, STATE, init_mqtt};
-use crate::node_state_entity::NodeCommand;
-use crate::slint_handle::{COMMAND_NAME2COMMAND_DICT, COMMAND_NAME2TOPIC, open_command_set_window};
-use event_listener::Event;
-use once_cell::sync::Lazy;
-use serde::Serialize;
+use crate::slint_handle::{
+    delete_command, dismiss_notification, dispatch_command, duplicate_command, load_profiles,
+    load_stored_commands, open_command_set_window, open_macro_window, open_message_log_window,
+    open_settings_window, open_rules_window, open_schedule_window, open_state_chart_window, refresh_command_list,
+    show_notification,
+};
+use clap::Parser;
 use slint::{ModelRc, SharedString, VecModel, Weak};
-use std::collections::HashMap;
+use slint_rmqtt::UPDATE_STATE_EVENT;
+use slint_rmqtt::command_macro::{MACRO_STATUS_EVENT, cancel_macro, find_macro};
+use slint_rmqtt::command_tracker::COMMAND_STATUS_EVENT;
+use slint_rmqtt::mqtt::reconnect::{
+    CONNECTION_STATUS, CONNECTION_STATUS_EVENT, ConnectionStatus, LAST_CONNECTION_ERROR,
+    LAST_EVENT_ERROR,
+};
+use slint_rmqtt::mqtt::traffic::TRAFFIC;
+use slint_rmqtt::mqtt_handle::{
+    ACTIVE_PROFILE, NODES, cancel_queued_command, switch_profile,
+};
+use slint_rmqtt::outbox::restore_queued_status;
+use slint_rmqtt::scheduler::run_scheduler;
+use slint_rmqtt::profile::ProfileArgs;
 use std::rc::Rc;
-use std::sync::Arc;
-use futures::executor::block_on;
-
-struct ForceSend<T>(T);
-unsafe impl<T> Send for ForceSend<T> {}
+use std::time::{Duration, Instant};
 
 slint::include_modules!();
 
-pub static UPDATE_STATE_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
-mod mqtt;
-mod mqtt_handle;
-mod node_state_entity;
 mod slint_handle;
 
+#[derive(Parser)]
+#[command(version, about = "MQTT 命令与状态客户端")]
+struct AppArgs {
+    #[command(flatten)]
+    profile: ProfileArgs,
+}
+
 async fn update_state_handle(main_view: Weak<MainView>) {
-    println!("update_state_handle");
     let event = UPDATE_STATE_EVENT.clone();
     loop {
         let listener = event.listen();
         listener.await;
-        let current_state = STATE.lock().await;
-        let mut insert_vec = Vec::new();
-        current_state.iter().for_each(|(k, v)| {
-            insert_vec.push((SharedString::from(k), SharedString::from(v)));
-        });
+        // 每个节点的详情：注册信息在前，状态项在后
+        let snapshot: Vec<(SharedString, Vec<(SharedString, SharedString)>)> = NODES
+            .lock()
+            .await
+            .nodes()
+            .map(|node| {
+                let mut rows = vec![
+                    (SharedString::from("id"), SharedString::from(node.id.as_str())),
+                    (
+                        SharedString::from("position_type"),
+                        SharedString::from(node.position_type.to_string()),
+                    ),
+                    (
+                        SharedString::from("position"),
+                        SharedString::from(node.position_text()),
+                    ),
+                    (
+                        SharedString::from("registered_at"),
+                        SharedString::from(node.registered_at.format("%H:%M:%S").to_string()),
+                    ),
+                ];
+                node.state.iter().for_each(|(k, v)| {
+                    rows.push((SharedString::from(k), SharedString::from(v)));
+                });
+                (SharedString::from(node.id.as_str()), rows)
+            })
+            .collect();
+        let main_view = main_view.clone();
+        slint::invoke_from_event_loop(move || {
+            let main_view = main_view.unwrap();
+            let mut selected = main_view.get_selected_node();
+            if !snapshot.iter().any(|(id, _)| *id == selected) {
+                selected = snapshot.first().map(|(id, _)| id.clone()).unwrap_or_default();
+                main_view.set_selected_node(selected.clone());
+            }
+            let rows = snapshot
+                .iter()
+                .find(|(id, _)| *id == selected)
+                .map(|(_, rows)| rows.clone())
+                .unwrap_or_default();
+            let ids: Vec<SharedString> = snapshot.into_iter().map(|(id, _)| id).collect();
+            main_view.set_nodes(ModelRc::from(Rc::new(VecModel::from(ids))));
+            main_view.set_node_states(ModelRc::from(Rc::new(VecModel::from(rows))));
+        })
+        .expect("to slint thread失败");
+    }
+}
+// 汇总所有连接的状态，例如 "已连接 10/11，重连等待 1"
+fn connection_status_summary() -> String {
+    if let Some(e) = LAST_CONNECTION_ERROR.lock().unwrap().as_ref() {
+        return format!("连接失败: {}", e);
+    }
+    let all = CONNECTION_STATUS.lock().unwrap();
+    if all.is_empty() {
+        return "未连接".to_string();
+    }
+    let count = |status| all.values().filter(|s| **s == status).count();
+    let mut summary = format!("已连接 {}/{}", count(ConnectionStatus::Connected), all.len());
+    let connecting = count(ConnectionStatus::Connecting);
+    if connecting > 0 {
+        summary.push_str(&format!("，连接中 {}", connecting));
+    }
+    let backing_off = count(ConnectionStatus::BackingOff);
+    if backing_off > 0 {
+        summary.push_str(&format!("，重连等待 {}", backing_off));
+    }
+    summary
+}
+
+async fn update_connection_status_handle(main_view: Weak<MainView>) {
+    let event = CONNECTION_STATUS_EVENT.clone();
+    loop {
+        let listener = event.listen();
+        listener.await;
+        let summary = SharedString::from(connection_status_summary());
         let main_view = main_view.clone();
         slint::invoke_from_event_loop(move || {
-            main_view
-                .unwrap()
-                .set_node_states(ModelRc::from(Rc::new(VecModel::from(insert_vec))));
+            main_view.unwrap().set_connection_status(summary);
+        })
+        .expect("to slint thread失败");
+    }
+}
+// 状态栏中一次刷新的内容
+struct StatusBarSnapshot {
+    pool_connections: Vec<ConnectionItem>,
+    receivers: Vec<ConnectionItem>,
+    last_message: String,
+    traffic: String,
+    last_error: String,
+}
+
+fn status_bar_snapshot(now: Instant) -> StatusBarSnapshot {
+    // 客户端ID都是 "<公共部分>_<用途>"，界面上只显示用途
+    let base = ACTIVE_PROFILE
+        .read()
+        .ok()
+        .and_then(|p| p.as_ref().map(|p| p.mqtt_config().client_id("")))
+        .unwrap_or_default();
+    let mut all: Vec<(String, ConnectionStatus)> = CONNECTION_STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, status)| (id.strip_prefix(&base).unwrap_or(id).to_string(), *status))
+        .collect();
+    all.sort_by(|a, b| a.0.cmp(&b.0));
+    let (pool, receivers): (Vec<_>, Vec<_>) =
+        all.into_iter().partition(|(name, _)| name.starts_with("pool_"));
+    let item = |(name, status): (String, ConnectionStatus)| ConnectionItem {
+        name: SharedString::from(name),
+        status: SharedString::from(status.as_str()),
+    };
+    let mut traffic = TRAFFIC.lock().unwrap();
+    let (received, sent) = traffic.sample_rates(now);
+    let last_message = traffic
+        .last_message()
+        .map(|t| format!("最近消息 {}", t.format("%H:%M:%S")))
+        .unwrap_or_else(|| "尚无消息".to_string());
+    let last_error = LAST_EVENT_ERROR
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|e| {
+            let name = e.name.strip_prefix(&base).unwrap_or(&e.name);
+            format!("{} [{}] {}", e.at.format("%H:%M:%S"), name, e.message)
+        })
+        .unwrap_or_default();
+    StatusBarSnapshot {
+        pool_connections: pool.into_iter().map(item).collect(),
+        receivers: receivers.into_iter().map(item).collect(),
+        last_message,
+        traffic: format!("收 {:.1}/s 发 {:.1}/s", received, sent),
+        last_error,
+    }
+}
+
+// 每秒刷新一次状态栏
+async fn update_status_bar_handle(main_view: Weak<MainView>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let now = interval.tick().await;
+        let snapshot = status_bar_snapshot(now.into_std());
+        let main_view = main_view.clone();
+        slint::invoke_from_event_loop(move || {
+            let main_view = main_view.unwrap();
+            main_view.set_pool_connections(ModelRc::from(Rc::new(VecModel::from(
+                snapshot.pool_connections,
+            ))));
+            main_view.set_receivers(ModelRc::from(Rc::new(VecModel::from(snapshot.receivers))));
+            main_view.set_last_message(SharedString::from(snapshot.last_message));
+            main_view.set_traffic(SharedString::from(snapshot.traffic));
+            main_view.set_last_error(SharedString::from(snapshot.last_error));
+        })
+        .expect("to slint thread失败");
+    }
+}
+// 命令或命令宏的状态变化时刷新命令列表
+async fn update_command_status_handle(main_view: Weak<MainView>) {
+    let event = COMMAND_STATUS_EVENT.clone();
+    let macro_event = MACRO_STATUS_EVENT.clone();
+    loop {
+        tokio::select! {
+            _ = event.listen() => {}
+            _ = macro_event.listen() => {}
+        }
+        let main_view = main_view.clone();
+        slint::invoke_from_event_loop(move || {
+            refresh_command_list(&main_view.unwrap());
         })
         .expect("to slint thread失败");
     }
 }
 #[tokio::main]
 async fn main() {
+    let args = AppArgs::parse();
     let main_view = MainView::new().unwrap();
+    load_stored_commands(&main_view);
+    // 上次退出时未发送的命令继续排队
+    restore_queued_status();
+    let profile = load_profiles(&args.profile, &main_view);
+    let main_view_week = main_view.as_weak();
+    main_view.on_open_command_set_window(move || open_command_set_window(main_view_week.clone(), None));
+    let main_view_week = main_view.as_weak();
+    main_view.on_open_macro_window(move || open_macro_window(main_view_week.clone(), None));
+    let main_view_week = main_view.as_weak();
+    main_view.on_edit_command(move |name| {
+        if find_macro(name.as_str()).is_some() {
+            open_macro_window(main_view_week.clone(), Some(name.to_string()))
+        } else {
+            open_command_set_window(main_view_week.clone(), Some(name.to_string()))
+        }
+    });
+    let main_view_week = main_view.as_weak();
+    main_view.on_duplicate_command(move |name| {
+        duplicate_command(name.as_str(), &main_view_week.unwrap())
+    });
+    let main_view_week = main_view.as_weak();
+    main_view.on_delete_command(move |name| delete_command(name.to_string(), main_view_week.clone()));
     let main_view_week = main_view.as_weak();
-    main_view.on_open_command_set_window(move || open_command_set_window(main_view_week.clone()));
-    main_view.on_run_command(|arg0: SharedString| run_cmd(arg0.as_str()));
+    main_view.on_open_settings_window(move || open_settings_window(main_view_week.clone()));
+    main_view.on_open_message_log_window(open_message_log_window);
+    main_view.on_open_state_chart_window(|node| open_state_chart_window(node.to_string()));
+    main_view.on_open_rules_window(open_rules_window);
+    main_view.on_open_schedule_window(open_schedule_window);
+    let main_view_week = main_view.as_weak();
+    main_view.on_cancel_command(move |name| {
+        if cancel_macro(name.as_str()) {
+            return;
+        }
+        if let Err(e) = cancel_queued_command(name.as_str()) {
+            show_notification(&main_view_week.unwrap(), "取消失败", e.to_string());
+        }
+    });
+    let main_view_week = main_view.as_weak();
+    main_view.on_run_command(move |name| dispatch_command(main_view_week.clone(), name.as_str()));
+    let main_view_week = main_view.as_weak();
+    main_view.on_dismiss_notification(move |id| dismiss_notification(&main_view_week.unwrap(), id));
+    main_view.on_select_node(|_| {
+        UPDATE_STATE_EVENT.clone().notify(usize::MAX);
+    });
     tokio::spawn(update_state_handle(main_view.as_weak()));
-    tokio::spawn(init_mqtt("127.0.0.1:1883", "server/0"));
+    tokio::spawn(update_connection_status_handle(main_view.as_weak()));
+    tokio::spawn(update_command_status_handle(main_view.as_weak()));
+    tokio::spawn(update_status_bar_handle(main_view.as_weak()));
+    // 定时任务在 load_stored_commands 中加载
+    tokio::spawn(run_scheduler());
+    switch_profile(profile).await;
     main_view.run().unwrap();
 }
-fn run_cmd(command_name: &str) {
-    let c2t = COMMAND_NAME2TOPIC.lock().unwrap();
-    let c2d = COMMAND_NAME2COMMAND_DICT.lock().unwrap();
-    let topic = c2t.get(command_name).unwrap();
-    let dict = c2d.get(command_name).unwrap();
-    let resp_dict: HashMap<String, f64> = dict.iter().map(|(k,v)|{
-        (k.to_string(),*v as f64)
-    }).collect();
-    let resp = NodeCommand::builder()
-        .op(command_name.to_string())
-        .op_value(resp_dict)
-        .build();
-    let text = serde_json::to_string(&resp).unwrap();
-    block_on(POOL.get()
-        .unwrap()
-        .send(topic.as_str(), text.as_str())).unwrap();
-
-}
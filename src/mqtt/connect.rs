@@ -1,6 +1,53 @@
-use rumqttc::{AsyncClient, EventLoop, MqttOptions};
+use crate::install_id::short_install_id;
+use crate::mqtt::tls::{TlsSettings, build_tls_config};
+use anyhow::{Error, format_err};
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, Transport};
 use std::time::Duration;
 
+// 连接配置
+#[derive(Clone, Debug)]
+pub struct MQTTConfig {
+    pub host: String,
+    pub username: String,
+    pub password: String,
+    pub client_id_prefix: String,
+    // 用户指定的客户端ID，为空时由前缀和安装ID生成
+    pub client_id: String,
+    pub keep_alive_secs: u64,
+    pub tls: TlsSettings,
+}
+
+impl Default for MQTTConfig {
+    fn default() -> Self {
+        MQTTConfig {
+            host: "127.0.0.1:1883".to_string(),
+            username: String::new(),
+            password: String::new(),
+            client_id_prefix: "dt".to_string(),
+            client_id: String::new(),
+            keep_alive_secs: 2,
+            tls: TlsSettings::default(),
+        }
+    }
+}
+
+impl MQTTConfig {
+    /// 某个用途的连接使用的客户端ID
+    ///
+    /// 默认为 `{前缀}_{安装ID}_{用途}`，同一安装每次启动保持不变，不同安装之间不会冲突；
+    /// 指定了 `client_id` 时为 `{client_id}_{用途}`
+    ///
+    /// # 参数
+    /// * `role` - 连接用途，例如 `pool_0`、`state`、`reply`，同一配置下的各连接必须不同
+    pub fn client_id(&self, role: &str) -> String {
+        if self.client_id.is_empty() {
+            format!("{}_{}_{}", self.client_id_prefix, short_install_id(), role)
+        } else {
+            format!("{}_{}", self.client_id, role)
+        }
+    }
+}
+
 /// MQTT连接函数，返回客户端和事件循环
 ///
 /// # 参数
@@ -11,7 +58,8 @@ use std::time::Duration;
 /// # 返回值
 /// 返回一个元组，包含 AsyncClient 和 EventLoop
 pub async fn mqtt_connect(url: &str, user: &str, password: &str) -> (AsyncClient, EventLoop) {
-    mqtt_connect_with_client_id(url, user, password, "dt_command_client").await
+    let client_id = MQTTConfig::default().client_id("command");
+    mqtt_connect_with_client_id(url, user, password, &client_id).await
 }
 
 /// MQTT连接函数（带自定义客户端ID），返回客户端和事件循环
@@ -30,34 +78,142 @@ pub async fn mqtt_connect_with_client_id(
     password: &str,
     client_id: &str,
 ) -> (AsyncClient, EventLoop) {
-    // 解析主机名和端口
-    let (host, port) = parse_url(url);
+    let config = MQTTConfig {
+        host: url.to_string(),
+        username: user.to_string(),
+        password: password.to_string(),
+        ..MQTTConfig::default()
+    };
+    // 未启用TLS时构造连接参数不会失败
+    mqtt_connect_with_config(&config, client_id)
+        .await
+        .expect("TCP连接参数无效")
+}
 
-    // 创建MQTT选项
-    let mut mqtt_options = MqttOptions::new(client_id, host, port);
+/// 按连接配置建立MQTT连接，返回客户端和事件循环
+///
+/// # 参数
+/// * `config` - 连接配置
+/// * `client_id` - 客户端ID
+///
+/// # 返回值
+/// 返回一个元组，包含 AsyncClient 和 EventLoop；TLS证书读取失败时返回错误
+pub async fn mqtt_connect_with_config(
+    config: &MQTTConfig,
+    client_id: &str,
+) -> Result<(AsyncClient, EventLoop), Error> {
+    // 解析地址和传输方式
+    let url = parse_url(&config.host)?;
+
+    // 创建MQTT选项，WebSocket 传输需要完整URL作为地址
+    let mut mqtt_options = match url.scheme {
+        Scheme::Ws | Scheme::Wss => MqttOptions::new(client_id, url.ws_url(), url.port),
+        Scheme::Tcp | Scheme::Tls => MqttOptions::new(client_id, url.host.clone(), url.port),
+    };
 
     // 配置连接参数
     mqtt_options
-        .set_keep_alive(Duration::from_secs(2))
+        .set_keep_alive(Duration::from_secs(config.keep_alive_secs))
         .set_clean_session(true)
-        .set_credentials(user.to_string(), password.to_string());
+        .set_credentials(config.username.clone(), config.password.clone());
+
+    // mqtts/wss 总是使用TLS；普通地址在启用TLS设置时使用TLS
+    let transport = match url.scheme {
+        Scheme::Tcp if config.tls.enabled => Transport::Tls(build_tls_config(&config.tls)?),
+        Scheme::Tcp => Transport::Tcp,
+        Scheme::Tls => Transport::Tls(build_tls_config(&config.tls)?),
+        Scheme::Ws => Transport::Ws,
+        Scheme::Wss => Transport::Wss(build_tls_config(&config.tls)?),
+    };
+    mqtt_options.set_transport(transport);
 
     // 创建客户端和事件循环
-    AsyncClient::new(mqtt_options, 10)
+    Ok(AsyncClient::new(mqtt_options, 10))
 }
 
-/// 解析URL获取主机名和端口
-fn parse_url(url: &str) -> (String, u16) {
-    let parts: Vec<&str> = url.split(':').collect();
-    match parts.len() {
-        2 => {
-            let host = parts[0].to_string();
-            let port = parts[1].parse().unwrap_or(1883);
-            (host, port)
-        }
-        _ => {
-            // 如果URL格式不正确，使用默认端口
-            (url.to_string(), 1883)
-        }
+// 传输方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+}
+
+// 解析后的服务器地址
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrokerUrl {
+    pub scheme: Scheme,
+    pub host: String,
+    pub port: u16,
+    // WebSocket 路径，例如 "/mqtt"
+    pub path: String,
+}
+
+impl BrokerUrl {
+    /// WebSocket 连接使用的完整URL
+    pub fn ws_url(&self) -> String {
+        let scheme = if self.scheme == Scheme::Wss { "wss" } else { "ws" };
+        format!("{}://{}:{}{}", scheme, self.host, self.port, self.path)
     }
 }
+
+/// 解析服务器地址
+///
+/// 支持 `host:port`、`mqtt://`、`mqtts://`、`ws://` 和 `wss://` 形式，
+/// 未写端口时分别使用 1883、1883、8883、80、443，WebSocket 未写路径时使用 `/mqtt`
+pub fn parse_url(url: &str) -> Result<BrokerUrl, Error> {
+    let url = url.trim();
+    let (scheme, rest) = match url.split_once("://") {
+        None => (Scheme::Tcp, url),
+        Some((scheme, rest)) => match scheme.to_ascii_lowercase().as_str() {
+            "mqtt" | "tcp" => (Scheme::Tcp, rest),
+            "mqtts" | "ssl" | "tls" => (Scheme::Tls, rest),
+            "ws" => (Scheme::Ws, rest),
+            "wss" => (Scheme::Wss, rest),
+            other => return Err(format_err!("不支持的地址协议: {}", other)),
+        },
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    let default_port = match scheme {
+        Scheme::Tcp => 1883,
+        Scheme::Tls => 8883,
+        Scheme::Ws => 80,
+        Scheme::Wss => 443,
+    };
+    // 如果端口格式不正确，使用默认端口
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => (host, port.parse().unwrap_or(default_port)),
+        _ => (authority, default_port),
+    };
+    if host.is_empty() {
+        return Err(format_err!("服务器地址为空: {}", url));
+    }
+    let path = match scheme {
+        Scheme::Ws | Scheme::Wss if path.is_empty() => "/mqtt".to_string(),
+        Scheme::Ws | Scheme::Wss => path.to_string(),
+        Scheme::Tcp | Scheme::Tls => String::new(),
+    };
+    Ok(BrokerUrl {
+        scheme,
+        host: host.to_string(),
+        port,
+        path,
+    })
+}
+
+#[test]
+fn test_parse_url_schemes() {
+    let url = parse_url("127.0.0.1:1883").unwrap();
+    assert_eq!((url.scheme, url.host.as_str(), url.port), (Scheme::Tcp, "127.0.0.1", 1883));
+    let url = parse_url("mqtts://broker.local").unwrap();
+    assert_eq!((url.scheme, url.port), (Scheme::Tls, 8883));
+    let url = parse_url("ws://proxy.local:8080").unwrap();
+    assert_eq!(url.ws_url(), "ws://proxy.local:8080/mqtt");
+    let url = parse_url("wss://proxy.local/broker/ws").unwrap();
+    assert_eq!(url.ws_url(), "wss://proxy.local:443/broker/ws");
+    assert!(parse_url("http://proxy.local").is_err());
+}
@@ -0,0 +1,225 @@
+use crate::mqtt::topic::{topic_matches, validate_filter};
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use rumqttc::QoS;
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// 消息日志默认保存的条数，超过后丢弃最早的
+pub const MESSAGE_LOG_CAPACITY: usize = 1000;
+
+// 消息方向
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // 收到的消息
+    In,
+    // 发出的消息
+    Out,
+}
+
+impl Direction {
+    /// 界面使用的方向标识
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+// 一条收发记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    // 写入日志时分配的序号，递增
+    pub seq: u64,
+    pub at: DateTime<Local>,
+    pub direction: Direction,
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: String,
+}
+
+impl LogEntry {
+    /// 导出文件中的一行
+    pub fn to_json(&self) -> Value {
+        json!({
+            "seq": self.seq,
+            "at": self.at.to_rfc3339(),
+            "direction": self.direction.as_str(),
+            "topic": self.topic,
+            "qos": self.qos as u8,
+            "retain": self.retain,
+            "payload": self.payload,
+        })
+    }
+}
+
+// 日志筛选条件
+#[derive(Clone, Debug, Default)]
+pub struct LogFilter {
+    // 带 `+`、`#` 时按主题过滤器匹配，否则匹配主题或内容中的文字，为空时不筛选
+    pub text: String,
+    // None 表示收发都显示
+    pub direction: Option<Direction>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        if self.direction.is_some_and(|d| d != entry.direction) {
+            return false;
+        }
+        let text = self.text.trim();
+        if text.is_empty() {
+            return true;
+        }
+        if text.contains(['+', '#']) && validate_filter(text).is_ok() {
+            return topic_matches(text, &entry.topic);
+        }
+        entry.topic.contains(text) || entry.payload.contains(text)
+    }
+}
+
+/// 固定容量的收发记录，满了以后丢弃最早的
+pub struct MessageLog {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+/// 所有连接共用的消息日志
+pub static MESSAGE_LOG: Lazy<Mutex<MessageLog>> =
+    Lazy::new(|| Mutex::new(MessageLog::new(MESSAGE_LOG_CAPACITY)));
+
+/// 有新记录或清空时触发，供界面刷新
+pub static MESSAGE_LOG_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+/// 记录一条收到或发出的消息
+///
+/// # 参数
+/// * `direction` - 消息方向
+/// * `topic` - 主题
+/// * `payload` - 消息内容
+/// * `qos` - 服务质量等级
+/// * `retain` - 是否为保留消息
+pub fn log_message(direction: Direction, topic: &str, payload: &str, qos: QoS, retain: bool) {
+    if let Ok(mut log) = MESSAGE_LOG.lock() {
+        log.push(LogEntry {
+            seq: 0,
+            at: Local::now(),
+            direction,
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload: payload.to_string(),
+        });
+    }
+    MESSAGE_LOG_EVENT.clone().notify(usize::MAX);
+}
+
+/// 内容是 JSON 时格式化显示，否则原样返回
+pub fn pretty_payload(payload: &str) -> String {
+    serde_json::from_str::<Value>(payload)
+        .ok()
+        .and_then(|value| serde_json::to_string_pretty(&value).ok())
+        .unwrap_or_else(|| payload.to_string())
+}
+
+impl MessageLog {
+    pub fn new(capacity: usize) -> Self {
+        MessageLog {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            next_seq: 0,
+        }
+    }
+
+    /// 追加一条记录并分配序号
+    pub fn push(&mut self, mut entry: LogEntry) {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// 按时间先后返回符合条件的记录
+    pub fn filtered(&self, filter: &LogFilter) -> Vec<LogEntry> {
+        self.entries
+            .iter()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// 把全部记录导出为 JSON Lines 文件，每行一条
+    pub fn export_to(&self, path: &Path) -> Result<(), Error> {
+        let mut text = String::new();
+        for entry in &self.entries {
+            text.push_str(&entry.to_json().to_string());
+            text.push('\n');
+        }
+        fs::write(path, text)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_message_log_ring_and_filter() {
+    let entry = |direction, topic: &str, payload: &str| LogEntry {
+        seq: 0,
+        at: Local::now(),
+        direction,
+        topic: topic.to_string(),
+        qos: QoS::AtLeastOnce,
+        retain: false,
+        payload: payload.to_string(),
+    };
+    let mut log = MessageLog::new(3);
+    log.push(entry(Direction::Out, "node/1", "{\"op\":\"fan\"}"));
+    log.push(entry(Direction::In, "nodes/1/state", "{\"mode\":\"auto\"}"));
+    log.push(entry(Direction::In, "nodes/2/state", "{\"mode\":\"manual\"}"));
+    log.push(entry(Direction::In, "server/0/reply", "ok"));
+    // 超出容量后丢弃最早的
+    assert_eq!(log.len(), 3);
+    assert_eq!(log.filtered(&LogFilter::default())[0].seq, 1);
+    let topics = |filter: LogFilter| -> Vec<String> {
+        log.filtered(&filter).into_iter().map(|e| e.topic).collect()
+    };
+    assert_eq!(
+        topics(LogFilter { text: "nodes/+/state".to_string(), direction: None }),
+        ["nodes/1/state", "nodes/2/state"]
+    );
+    assert_eq!(
+        topics(LogFilter { text: "manual".to_string(), direction: None }),
+        ["nodes/2/state"]
+    );
+    assert!(topics(LogFilter { text: String::new(), direction: Some(Direction::Out) }).is_empty());
+    assert_eq!(pretty_payload("{\"a\":1}"), "{\n  \"a\": 1\n}");
+    assert_eq!(pretty_payload("ok"), "ok");
+
+    let path = std::env::temp_dir().join(format!("slint_rmqtt_log_{}.jsonl", std::process::id()));
+    log.export_to(&path).unwrap();
+    let text = fs::read_to_string(&path).unwrap();
+    assert_eq!(text.lines().count(), 3);
+    assert!(text.contains("\"direction\":\"in\""));
+    fs::remove_file(&path).unwrap();
+}
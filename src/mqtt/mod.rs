@@ -1,4 +1,10 @@
 pub mod connect;
+pub mod message_log;
 pub mod pool;
 pub mod receiver;
+pub mod reconnect;
 pub mod sender;
+pub mod tls;
+pub mod topic;
+pub mod traffic;
+pub mod transport;
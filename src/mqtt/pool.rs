@@ -1,151 +1,276 @@
-use crate::mqtt::connect::mqtt_connect_with_client_id;
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming, QoS};
+use crate::mqtt::connect::MQTTConfig;
+use crate::mqtt::reconnect::{
+    Backoff, ConnectionStatus, ReconnectPolicy, ShortSessionDetector, report_status, wait_reconnect,
+};
+use crate::mqtt::message_log::{Direction, log_message};
+use crate::mqtt::topic::{RoutedMessage, TopicRouter};
+use crate::mqtt::traffic::{record_received, record_sent};
+use crate::mqtt::transport::{Transport, TransportEvent, TransportPair, mqtt_transport};
+use anyhow::{Error, format_err};
+use rumqttc::QoS;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::sync::{Mutex, Semaphore};
 
-// 定义消息回调函数类型
-type MessageCallback = Arc<dyn Fn(&str, &str) + Send + Sync + 'static>; // (topic, payload)
+// 发布后等待服务器确认的最长时间
+const PUBLISH_ACK_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct MqttConnection {
-    client: AsyncClient,
-    _eventloop_handle: tokio::task::JoinHandle<()>, // 保持EventLoop运行
-    message_callbacks: Arc<Mutex<Vec<MessageCallback>>>, // 消息回调列表
+    client: Arc<dyn Transport>,
+    eventloop_handle: tokio::task::JoinHandle<()>, // 保持EventLoop运行
+    router: Arc<Mutex<TopicRouter>>, // 按主题过滤器注册的消息回调
+    subscriptions: Arc<Mutex<Vec<(String, QoS)>>>, // 已订阅主题，重连后重新订阅
+    connected: Arc<AtomicBool>, // 当前是否已连上服务器
 }
 
 impl MqttConnection {
     pub async fn new(host: &str, username: &str, password: &str, client_id: &str) -> Self {
-        let (client, mut eventloop) =
-            mqtt_connect_with_client_id(host, username, password, client_id).await;
+        let config = MQTTConfig {
+            host: host.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            ..MQTTConfig::default()
+        };
+        // 未启用TLS时构造连接不会失败
+        Self::new_with_config(&config, client_id)
+            .await
+            .expect("TCP连接参数无效")
+    }
+
+    pub async fn new_with_config(config: &MQTTConfig, client_id: &str) -> Result<Self, Error> {
+        let connection = Self::with_transport(mqtt_transport(config, client_id).await?, client_id);
+        // 等待连接建立
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        Ok(connection)
+    }
 
+    /// 使用已建立的传输层创建连接，并在后台运行事件循环
+    ///
+    /// # 参数
+    /// * `transport` - 连接的发送端和接收端
+    /// * `client_id` - 客户端ID，用于上报连接状态
+    pub fn with_transport(transport: TransportPair, client_id: &str) -> Self {
+        let (client, mut eventloop) = transport;
         // 克隆client_id以便在异步闭包中使用
         let client_id_owned = client_id.to_string();
 
-        // 创建消息回调列表
-        let message_callbacks = Arc::new(Mutex::new(Vec::<MessageCallback>::new()));
-        let callbacks_clone = message_callbacks.clone();
+        // 创建消息回调路由
+        let router = Arc::new(Mutex::new(TopicRouter::default()));
+        let router_clone = router.clone();
+        let subscriptions = Arc::new(Mutex::new(Vec::<(String, QoS)>::new()));
+        let subscriptions_clone = subscriptions.clone();
+        let resubscribe_client = client.clone();
+        let connected = Arc::new(AtomicBool::new(false));
+        let connected_clone = connected.clone();
 
-        // 启动EventLoop
+        // 启动EventLoop，断线后按退避策略重连
         let eventloop_handle = tokio::spawn(async move {
+            let policy = ReconnectPolicy::default();
+            let mut sessions = ShortSessionDetector::new(&policy);
+            let mut backoff = Backoff::new(policy);
+            report_status(&client_id_owned, ConnectionStatus::Connecting);
             loop {
                 match eventloop.poll().await {
-                    Ok(Event::Incoming(Incoming::Publish(publish))) => {
-                        // 处理接收到的消息
-                        if let Ok(payload) = String::from_utf8(publish.payload.to_vec()) {
-                            let topic = publish.topic.clone();
-
-                            // 调用所有注册的回调函数
-                            let callbacks = callbacks_clone.lock().await;
-                            for callback in callbacks.iter() {
-                                callback(&topic, &payload);
+                    Ok(TransportEvent::Connected) => {
+                        // 会话持续一段时间后才重置退避时间，见 ShortSessionDetector
+                        sessions.connected();
+                        connected_clone.store(true, Ordering::Relaxed);
+                        report_status(&client_id_owned, ConnectionStatus::Connected);
+                        let topics = subscriptions_clone.lock().await.clone();
+                        for (topic, qos) in topics {
+                            if let Err(e) = resubscribe_client.subscribe(&topic, qos).await {
+                                eprintln!("重新订阅失败 [{}]: {:?}", topic, e);
                             }
                         }
                     }
+                    Ok(TransportEvent::Message(message)) => {
+                        record_received();
+                        log_message(
+                            Direction::In,
+                            &message.topic,
+                            &message.payload,
+                            message.qos,
+                            message.retain,
+                        );
+                        // 只调用过滤器匹配的回调函数
+                        router_clone
+                            .lock()
+                            .await
+                            .dispatch(&message.topic, &message.payload);
+                    }
                     Ok(_) => {
                         // 处理其他事件
                     }
                     Err(e) => {
-                        eprintln!("MQTT EventLoop错误 [{}]: {:?}", client_id_owned, e);
-                        break;
+                        connected_clone.store(false, Ordering::Relaxed);
+                        if !wait_reconnect(&client_id_owned, &e, &mut sessions, &mut backoff).await {
+                            break;
+                        }
                     }
                 }
             }
         });
 
-        // 等待连接建立
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-
         Self {
             client,
-            _eventloop_handle: eventloop_handle,
-            message_callbacks,
+            eventloop_handle,
+            router,
+            subscriptions,
+            connected,
         }
     }
 
-    pub async fn publish(&self, topic: &str, message: &str) -> Result<(), rumqttc::ClientError> {
-        // 先尝试AtLeastOnce，失败后降级到AtMostOnce
-        match self
-            .client
-            .publish(topic, QoS::AtLeastOnce, false, message)
-            .await
-        {
-            Ok(_) => {
-                println!("消息发送成功: topic={}, message={}", topic, message);
-                Ok(())
-            }
-            Err(e) => {
-                eprintln!("AtLeastOnce发送失败，尝试AtMostOnce: {:?}", e);
-                match self
-                    .client
-                    .publish(topic, QoS::AtMostOnce, false, message)
-                    .await
-                {
-                    Ok(_) => {
-                        println!("AtMostOnce发送成功: topic={}, message={}", topic, message);
-                        Ok(())
-                    }
-                    Err(e2) => {
-                        eprintln!("AtMostOnce也失败: {:?}", e2);
-                        Err(e2)
-                    }
-                }
-            }
-        }
+    /// 是否已连上服务器，断线重连期间为 false
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// 发布消息，收到服务器确认后才返回成功，失败时不会降级 QoS 重试
+    ///
+    /// # 参数
+    /// * `topic` - 目标主题
+    /// * `message` - 消息内容
+    /// * `qos` - 服务质量等级
+    /// * `retain` - 是否作为保留消息
+    pub async fn publish(&self, topic: &str, message: &str, qos: QoS, retain: bool) -> Result<(), Error> {
+        tokio::time::timeout(
+            PUBLISH_ACK_TIMEOUT,
+            self.client.publish(topic, message, qos, retain),
+        )
+        .await
+        .map_err(|_| {
+            format_err!(
+                "{} 秒内未收到服务器对 {} 的确认",
+                PUBLISH_ACK_TIMEOUT.as_secs(),
+                topic
+            )
+        })??;
+        record_sent();
+        log_message(Direction::Out, topic, message, qos, retain);
+        Ok(())
     }
 
-    /// 订阅主题
-    pub async fn subscribe(&self, topic: &str) -> Result<(), rumqttc::ClientError> {
-        self.client.subscribe(topic, QoS::AtLeastOnce).await
+    /// 订阅主题，断线重连后会以相同的 QoS 自动重新订阅
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), Error> {
+        let mut subscriptions = self.subscriptions.lock().await;
+        match subscriptions.iter_mut().find(|(t, _)| t == topic) {
+            Some(subscription) => subscription.1 = qos,
+            None => subscriptions.push((topic.to_string(), qos)),
+        }
+        self.client.subscribe(topic, qos).await
     }
 
-    /// 添加消息回调
+    /// 添加消息回调，接收所有已订阅主题的消息
     pub async fn add_message_callback<F>(&self, callback: F)
     where
         F: Fn(&str, &str) + Send + Sync + 'static,
     {
-        let mut callbacks = self.message_callbacks.lock().await;
-        callbacks.push(Arc::new(callback));
+        let mut router = self.router.lock().await;
+        // "#" 是合法的过滤器，不会失败
+        let _ = router.add("#", move |message| callback(message.topic, message.payload));
+    }
+
+    /// 按主题过滤器添加消息回调并订阅该过滤器
+    ///
+    /// # 参数
+    /// * `filter` - 主题过滤器，支持 `+` 和 `#`，例如 `nodes/+/state`
+    /// * `qos` - 订阅的服务质量等级
+    /// * `callback` - 回调函数，只处理主题与过滤器匹配的消息
+    pub async fn add_route<F>(&self, filter: &str, qos: QoS, callback: F) -> Result<(), Error>
+    where
+        F: Fn(&RoutedMessage) + Send + Sync + 'static,
+    {
+        self.router.lock().await.add(filter, callback)?;
+        self.subscribe(filter, qos).await
+    }
+}
+
+impl Drop for MqttConnection {
+    fn drop(&mut self) {
+        // 连接被丢弃时停止后台EventLoop，避免切换连接后旧连接继续运行
+        self.eventloop_handle.abort();
     }
 }
 
 pub struct MqttConnectionPool {
     connections: Vec<Arc<Mutex<MqttConnection>>>,
+    connected: Vec<Arc<AtomicBool>>, // 各连接的连接状态，不需要锁住连接即可读取
     semaphore: Arc<Semaphore>,
     current_index: Arc<Mutex<usize>>,
 }
 
 impl MqttConnectionPool {
     pub async fn new(host: &str, username: &str, password: &str, pool_size: usize) -> Self {
+        let config = MQTTConfig {
+            host: host.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            ..MQTTConfig::default()
+        };
+        // 未启用TLS时只有连接数为0会失败
+        Self::new_with_config(&config, pool_size)
+            .await
+            .expect("连接池大小必须大于0")
+    }
+
+    pub async fn new_with_config(config: &MQTTConfig, pool_size: usize) -> Result<Self, Error> {
         let mut connections = Vec::with_capacity(pool_size);
 
         for i in 0..pool_size {
-            let client_id = format!("dt_client_{}", i);
-            let connection = MqttConnection::new(host, username, password, &client_id).await;
-            connections.push(Arc::new(Mutex::new(connection)));
+            let client_id = config.client_id(&format!("pool_{}", i));
+            let connection = MqttConnection::new_with_config(config, &client_id).await?;
+            connections.push(connection);
         }
+        Self::from_connections(connections)
+    }
 
-        Self {
-            connections,
+    /// 由已创建的连接组成连接池
+    ///
+    /// # 返回值
+    /// 没有任何连接时返回错误，空连接池的发送会一直等待
+    pub fn from_connections(connections: Vec<MqttConnection>) -> Result<Self, Error> {
+        let pool_size = connections.len();
+        if pool_size == 0 {
+            return Err(format_err!("连接池大小必须大于0"));
+        }
+        let connected = connections.iter().map(|c| c.connected.clone()).collect();
+        Ok(Self {
+            connections: connections
+                .into_iter()
+                .map(|c| Arc::new(Mutex::new(c)))
+                .collect(),
+            connected,
             semaphore: Arc::new(Semaphore::new(pool_size)),
             current_index: Arc::new(Mutex::new(0)),
-        }
+        })
     }
 
-    /// 获取一个连接进行发送（轮询策略）
-    pub async fn send(&self, topic: &str, message: &str) -> Result<(), rumqttc::ClientError> {
+    /// 获取一个连接进行发送（轮询策略），收到服务器确认后返回
+    pub async fn send(&self, topic: &str, message: &str, qos: QoS, retain: bool) -> Result<(), Error> {
         // 获取信号量许可
         let _permit = self.semaphore.acquire().await.unwrap();
 
-        // 轮询选择连接
+        // 轮询选择连接，跳过正在重连的连接；全部未连接时仍按顺序选择
         let connection_arc = {
             let mut index = self.current_index.lock().await;
-            let current = *index;
-            *index = (current + 1) % self.connections.len();
+            let len = self.connections.len();
+            let current = (0..len)
+                .map(|offset| (*index + offset) % len)
+                .find(|&i| self.connected[i].load(Ordering::Relaxed))
+                .unwrap_or(*index);
+            *index = (current + 1) % len;
             self.connections[current].clone()
         };
 
         // 使用连接发送消息
         let connection = connection_arc.lock().await;
-        connection.publish(topic, message).await
+        connection.publish(topic, message, qos, retain).await
+    }
+
+    /// 是否至少有一个连接已连上服务器
+    pub fn is_connected(&self) -> bool {
+        self.connected.iter().any(|c| c.load(Ordering::Relaxed))
     }
 
     /// 获取池大小
@@ -166,7 +291,47 @@ impl MqttConnectionPool {
         username: &str,
         password: &str,
     ) -> MqttConnection {
-        let client_id = format!("dt_receiver_{}", std::process::id());
+        let client_id = MQTTConfig::default().client_id("receiver");
         MqttConnection::new(host, username, password, &client_id).await
     }
 }
+
+#[tokio::test]
+async fn test_empty_pool_is_rejected() {
+    assert!(MqttConnectionPool::from_connections(Vec::new()).is_err());
+    assert!(MqttConnectionPool::new_with_config(&MQTTConfig::default(), 0).await.is_err());
+}
+
+#[tokio::test]
+async fn test_short_sessions_over_loopback() {
+    use crate::mqtt::reconnect::{CONNECTION_STATUS, LAST_EVENT_ERROR, wait_connected};
+    use crate::mqtt::transport::LoopbackBroker;
+
+    let id = "test_short_session";
+    let broker = LoopbackBroker::new();
+    let connection = MqttConnection::with_transport(broker.connect(id), id);
+    let status = || CONNECTION_STATUS.lock().unwrap().get(id).copied();
+    let warned = || {
+        LAST_EVENT_ERROR
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|e| e.name == id && e.message.contains("相同的客户端ID"))
+    };
+    // 服务器反复在连上后立即关闭连接，第三次后提示可能有客户端ID冲突
+    for _ in 0..3 {
+        assert!(!warned());
+        assert!(wait_connected(id, Duration::from_secs(5)).await);
+        broker.disconnect(id);
+        for _ in 0..100 {
+            if status() != Some(ConnectionStatus::Connected) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    assert!(warned());
+    // 提示后仍继续重连
+    assert!(wait_connected(id, Duration::from_secs(10)).await);
+    assert!(connection.is_connected());
+}
@@ -1,81 +1,160 @@
-use crate::mqtt::connect::mqtt_connect;
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming, QoS};
+use crate::mqtt::connect::MQTTConfig;
+use crate::mqtt::reconnect::{
+    Backoff, ConnectionStatus, ReconnectPolicy, ShortSessionDetector, report_status, wait_reconnect,
+};
+use crate::mqtt::message_log::{Direction, log_message};
+use crate::mqtt::topic::{RoutedMessage, TopicRouter};
+use crate::mqtt::traffic::record_received;
+use crate::mqtt::transport::{
+    Transport, TransportEvent, TransportEvents, TransportPair, mqtt_transport,
+};
+use anyhow::Error;
+use rumqttc::QoS;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-// 定义回调函数类型
-type Callback = Arc<dyn Fn(&str) + Send + Sync + 'static>;
-
-pub struct MQTTConfig {
-    pub host: String,
-    pub username: String,
-    pub password: String,
-}
-
 // 接收器结构体
 pub struct Receiver {
-    callbacks: Arc<Mutex<Vec<Callback>>>,
-    client: AsyncClient,
-    event_loop: EventLoop,
+    router: Arc<Mutex<TopicRouter>>,
+    client: Arc<dyn Transport>,
+    event_loop: Box<dyn TransportEvents>,
     topic: String,
+    // 创建时指定的主题的订阅 QoS
+    qos: QoS,
+    // add_route 添加的过滤器及其订阅 QoS
+    subscriptions: Arc<Mutex<Vec<(String, QoS)>>>,
+    client_id: String,
+    policy: ReconnectPolicy,
 }
 
 impl Receiver {
-    // 添加回调函数
+    /// 使用已建立的传输层创建接收器，主题在 `start` 中连接成功后订阅
+    ///
+    /// # 参数
+    /// * `transport` - 连接的发送端和接收端
+    /// * `client_id` - 客户端ID，用于上报连接状态
+    /// * `topic` - 订阅的主题，`add_callback` 注册的回调只处理该主题的消息
+    pub fn with_transport(transport: TransportPair, client_id: &str, topic: &str) -> Self {
+        let (client, event_loop) = transport;
+        Receiver {
+            router: Arc::new(Mutex::new(TopicRouter::default())),
+            client,
+            event_loop,
+            topic: topic.to_string(),
+            qos: QoS::AtLeastOnce,
+            subscriptions: Arc::new(Mutex::new(Vec::new())),
+            client_id: client_id.to_string(),
+            policy: ReconnectPolicy::default(),
+        }
+    }
+
+    // 添加回调函数，处理创建接收器时指定的主题
     pub fn add_callback<F>(&self, callback: F)
     where
         F: Fn(&str) + Send + Sync + 'static,
     {
-        let mut callbacks = self.callbacks.lock().unwrap();
-        callbacks.push(Arc::new(callback));
+        // 创建时指定的主题按 set_qos 设置的 QoS 订阅，这里只登记回调
+        let mut router = self.router.lock().unwrap();
+        if let Err(e) = router.add(&self.topic, move |message| callback(message.payload)) {
+            eprintln!("添加回调失败: {:?}", e);
+        }
     }
 
-    // 处理接收到的消息
-    fn handle_message(&self, payload: &str) {
-        // 获取所有回调的克隆
-        let callbacks = self
-            .callbacks
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|cb| Arc::clone(cb))
-            .collect::<Vec<_>>();
+    /// 按主题过滤器添加回调，过滤器在 `start` 中连接成功后订阅
+    ///
+    /// 需要在 `start` 之前调用
+    ///
+    /// # 参数
+    /// * `filter` - 主题过滤器，支持 `+` 和 `#`
+    /// * `qos` - 订阅的服务质量等级
+    /// * `callback` - 回调函数，可以从 `wildcards` 中取出通配符对应的主题层级
+    pub fn add_route<F>(&self, filter: &str, qos: QoS, callback: F) -> Result<(), Error>
+    where
+        F: Fn(&RoutedMessage) + Send + Sync + 'static,
+    {
+        self.router.lock().unwrap().add(filter, callback)?;
+        self.subscriptions.lock().unwrap().push((filter.to_string(), qos));
+        Ok(())
+    }
 
-        let mut handles = vec![];
+    // 处理接收到的消息，只调用过滤器匹配的回调
+    fn handle_message(&self, topic: &str, payload: &str) {
+        let routes = self.router.lock().unwrap().route(topic, payload);
 
-        // 为每个回调创建新线程
-        for callback in callbacks {
-            let payload = payload.to_string();
+        // 为每个回调创建新线程，并等待所有线程完成
+        thread::scope(|scope| {
+            for (callback, message) in &routes {
+                scope.spawn(move || callback(message));
+            }
+        });
+    }
 
-            handles.push(thread::spawn(move || {
-                callback(&payload);
-            }));
+    // 需要订阅的主题：创建时指定的主题和所有回调的过滤器，重复的过滤器取较高的 QoS
+    fn filters(&self) -> Vec<(String, QoS)> {
+        let mut filters = vec![(self.topic.clone(), self.qos)];
+        for (filter, qos) in self.subscriptions.lock().unwrap().iter() {
+            match filters.iter_mut().find(|(f, _)| f == filter) {
+                Some(existing) if *qos > existing.1 => existing.1 = *qos,
+                Some(_) => {}
+                None => filters.push((filter.clone(), *qos)),
+            }
         }
+        filters
+    }
 
-        // 等待所有线程完成
-        for handle in handles {
-            let _ = handle.join();
-        }
+    // 设置创建时指定的主题的订阅 QoS，默认为 QoS 1
+    pub fn set_qos(&mut self, qos: QoS) {
+        self.qos = qos;
     }
 
-    // 启动事件循环 - 消费self但可以通过Arc共享
-    pub async fn start(&mut self) {
-        // 订阅主题
-        if let Err(e) = self.client.subscribe(&self.topic, QoS::ExactlyOnce).await {
-            eprintln!("订阅主题失败: {:?}", e);
-        }
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    // 连接使用的客户端ID
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    // 设置断线重连策略
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
 
+    // 启动事件循环 - 断线后按退避策略自动重连，每次连上后重新订阅主题
+    pub async fn start(&mut self) {
+        let mut backoff = Backoff::new(self.policy.clone());
+        let mut sessions = ShortSessionDetector::new(&self.policy);
+        report_status(&self.client_id, ConnectionStatus::Connecting);
         loop {
             match self.event_loop.poll().await {
-                Ok(Event::Incoming(Incoming::Publish(publish))) => {
-                    if let Ok(payload) = String::from_utf8(publish.payload.to_vec()) {
-                        self.handle_message(&payload);
+                Ok(TransportEvent::Connected) => {
+                    // 会话持续一段时间后才重置退避时间，见 ShortSessionDetector
+                    sessions.connected();
+                    report_status(&self.client_id, ConnectionStatus::Connected);
+                    // 订阅主题
+                    for (filter, qos) in self.filters() {
+                        if let Err(e) = self.client.subscribe(&filter, qos).await {
+                            eprintln!("订阅主题失败 [{}]: {:?}", filter, e);
+                        }
                     }
                 }
+                Ok(TransportEvent::Message(message)) => {
+                    record_received();
+                    log_message(
+                        Direction::In,
+                        &message.topic,
+                        &message.payload,
+                        message.qos,
+                        message.retain,
+                    );
+                    self.handle_message(&message.topic, &message.payload);
+                }
                 Ok(_) => {}
                 Err(e) => {
-                    eprintln!("事件循环错误: {:?}", e);
-                    break;
+                    if !wait_reconnect(&self.client_id, &e, &mut sessions, &mut backoff).await {
+                        break;
+                    }
                 }
             }
         }
@@ -121,25 +200,19 @@ impl Receiver {
 //     receiver
 // }
 
-// 兼容旧版本的函数（使用配置创建连接）
-pub async fn mqtt_receiver_with_config(mqtt_cfg: &MQTTConfig, topic: &str) -> Receiver {
-    let (client, event_loop) = mqtt_connect(
-        mqtt_cfg.host.as_str(),
-        mqtt_cfg.username.as_str(),
-        mqtt_cfg.password.as_str(),
-    )
-    .await;
-    let receiver = Receiver {
-        callbacks: Arc::new(Mutex::new(Vec::new())),
-        client: client.clone(),
-        event_loop,
-        topic: topic.to_string(),
-    };
-
-    // 订阅指定主题
-    if let Err(e) = client.subscribe(topic, QoS::ExactlyOnce).await {
-        eprintln!("订阅主题失败: {:?}", e);
-    }
-
-    receiver
+/// 按连接配置创建接收器
+///
+/// # 参数
+/// * `mqtt_cfg` - 连接配置
+/// * `topic` - 订阅的主题
+/// * `role` - 连接用途，用于生成客户端ID，同时运行的接收器必须不同
+pub async fn mqtt_receiver_with_config(
+    mqtt_cfg: &MQTTConfig,
+    topic: &str,
+    role: &str,
+) -> Result<Receiver, Error> {
+    let client_id = mqtt_cfg.client_id(role);
+    let transport = mqtt_transport(mqtt_cfg, &client_id).await?;
+    // 主题在 start 中收到 ConnAck 后订阅，断线重连后也会重新订阅
+    Ok(Receiver::with_transport(transport, &client_id, topic))
 }
@@ -0,0 +1,317 @@
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// 连接状态
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    BackingOff,
+}
+
+impl ConnectionStatus {
+    /// 界面使用的状态标识
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionStatus::Connecting => "connecting",
+            ConnectionStatus::Connected => "connected",
+            ConnectionStatus::BackingOff => "backing_off",
+        }
+    }
+}
+
+/// 各连接（按客户端ID）的当前状态
+pub static CONNECTION_STATUS: Lazy<Mutex<HashMap<String, ConnectionStatus>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// 最近一次无法自动恢复的连接错误（例如证书配置错误）
+pub static LAST_CONNECTION_ERROR: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+// 事件循环错误（断线、服务器拒绝等），会自动重连
+#[derive(Clone, Debug)]
+pub struct EventError {
+    pub at: DateTime<Local>,
+    // 连接名称（客户端ID）
+    pub name: String,
+    pub message: String,
+}
+
+/// 最近一次事件循环错误
+pub static LAST_EVENT_ERROR: Lazy<Mutex<Option<EventError>>> = Lazy::new(|| Mutex::new(None));
+
+/// 连接状态变化时触发，供界面刷新
+pub static CONNECTION_STATUS_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+/// 上报连接状态
+///
+/// # 参数
+/// * `name` - 连接名称（客户端ID）
+/// * `status` - 新状态
+pub fn report_status(name: &str, status: ConnectionStatus) {
+    if let Ok(mut all) = CONNECTION_STATUS.lock() {
+        if all.get(name) == Some(&status) {
+            return;
+        }
+        all.insert(name.to_string(), status);
+    }
+    CONNECTION_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+/// 上报事件循环错误，界面上显示最近一次
+///
+/// # 参数
+/// * `name` - 连接名称（客户端ID）
+/// * `message` - 错误信息
+pub fn report_event_error(name: &str, message: &str) {
+    eprintln!("MQTT EventLoop错误 [{}]: {}", name, message);
+    if let Ok(mut last) = LAST_EVENT_ERROR.lock() {
+        *last = Some(EventError {
+            at: Local::now(),
+            name: name.to_string(),
+            message: message.to_string(),
+        });
+    }
+    CONNECTION_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+/// 上报无法自动恢复的连接错误
+pub fn report_error(message: &str) {
+    if let Ok(mut last) = LAST_CONNECTION_ERROR.lock() {
+        *last = Some(message.to_string());
+    }
+    CONNECTION_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+/// 等待指定连接进入已连接状态
+///
+/// # 参数
+/// * `name` - 连接名称（客户端ID）
+/// * `timeout` - 最长等待时间
+///
+/// # 返回值
+/// 超时前连上返回 true
+pub async fn wait_connected(name: &str, timeout: Duration) -> bool {
+    let connected = async {
+        loop {
+            // 先注册监听再检查状态，避免错过两者之间的通知
+            let listener = CONNECTION_STATUS_EVENT.listen();
+            if CONNECTION_STATUS.lock().unwrap().get(name) == Some(&ConnectionStatus::Connected) {
+                return;
+            }
+            listener.await;
+        }
+    };
+    tokio::time::timeout(timeout, connected).await.is_ok()
+}
+
+/// 清空所有连接状态（断开全部连接时调用）
+pub fn clear_status() {
+    if let Ok(mut all) = CONNECTION_STATUS.lock() {
+        all.clear();
+    }
+    if let Ok(mut last) = LAST_CONNECTION_ERROR.lock() {
+        *last = None;
+    }
+    if let Ok(mut last) = LAST_EVENT_ERROR.lock() {
+        *last = None;
+    }
+    CONNECTION_STATUS_EVENT.clone().notify(usize::MAX);
+}
+
+// 重连策略
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    // 随机抖动比例，0.2 表示在 ±20% 范围内浮动
+    pub jitter: f64,
+    // 连续失败达到该次数后放弃，None 表示一直重试
+    pub max_attempts: Option<u32>,
+    // 连接成功后不到该时间就被断开视为短会话，短会话后不重置退避时间
+    pub short_session_window: Duration,
+    // 连续出现该次数的短会话后提示可能有客户端ID冲突（仍继续重连），0 表示不提示
+    pub short_session_threshold: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+            short_session_window: Duration::from_secs(5),
+            short_session_threshold: 3,
+        }
+    }
+}
+
+// 指数退避计数器，连接成功后需调用 reset
+pub struct Backoff {
+    policy: ReconnectPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: ReconnectPolicy) -> Self {
+        Backoff { policy, attempt: 0 }
+    }
+
+    /// 连接成功后重置失败次数
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// 记录一次失败并返回下次重连前的等待时间，超过最大次数时返回 None
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.policy.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None;
+        }
+        let base = self.policy.initial_delay.as_secs_f64()
+            * self.policy.multiplier.powi(self.attempt.min(32) as i32);
+        let base = base.min(self.policy.max_delay.as_secs_f64());
+        let jitter = base * self.policy.jitter * (fastrand::f64() * 2.0 - 1.0);
+        self.attempt += 1;
+        Some(Duration::from_secs_f64((base + jitter).max(0.0)))
+    }
+}
+
+// 一次断线后的处理方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    // 会话持续了较长时间，退避从头开始
+    Stable,
+    // 连接失败或连上后很快被断开，退避时间继续增加；
+    // `suspected` 为 true 表示短会话刚好连续出现 `short_session_threshold` 次，可能有客户端ID冲突
+    Unstable { suspected: bool },
+}
+
+/// 短会话检测
+///
+/// 连接使用 MQTT 3.1.1，另一个连接以相同的客户端ID连上时，服务器直接关闭旧连接而不告知原因，
+/// 与网络中断或服务器重启无法区分。因此这里不判定"被顶替"，只根据连接成功后很快被断开、
+/// 并且反复发生来推测可能有客户端ID冲突：这种情况不停止重连，只是不再重置退避时间，
+/// 并在连续出现 `short_session_threshold` 次时提示
+pub struct ShortSessionDetector {
+    window: Duration,
+    threshold: u32,
+    connected_at: Option<Instant>,
+    short_sessions: u32,
+}
+
+impl ShortSessionDetector {
+    pub fn new(policy: &ReconnectPolicy) -> Self {
+        ShortSessionDetector {
+            window: policy.short_session_window,
+            threshold: policy.short_session_threshold,
+            connected_at: None,
+            short_sessions: 0,
+        }
+    }
+
+    /// 连接成功时调用
+    pub fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+    }
+
+    /// 连接断开（或连接失败）时调用
+    pub fn disconnected(&mut self) -> Disconnect {
+        let session = self.connected_at.take().map(|t| t.elapsed());
+        self.record(session)
+    }
+
+    // session 为本次会话的持续时间，None 表示没有连接成功
+    fn record(&mut self, session: Option<Duration>) -> Disconnect {
+        match session {
+            Some(duration) if duration < self.window => {
+                self.short_sessions += 1;
+                Disconnect::Unstable {
+                    suspected: self.threshold > 0 && self.short_sessions == self.threshold,
+                }
+            }
+            Some(_) => {
+                self.short_sessions = 0;
+                Disconnect::Stable
+            }
+            // 连接失败（网络、服务器问题）不计入短会话
+            None => {
+                self.short_sessions = 0;
+                Disconnect::Unstable { suspected: false }
+            }
+        }
+    }
+}
+
+/// 事件循环出错后调用：上报错误，并按退避时间等待到可以重连
+///
+/// # 参数
+/// * `client_id` - 客户端ID，用于上报连接状态
+/// * `error` - 事件循环返回的错误
+///
+/// # 返回值
+/// 重试次数用完时返回 false，调用方应停止事件循环
+pub async fn wait_reconnect(
+    client_id: &str,
+    error: &Error,
+    sessions: &mut ShortSessionDetector,
+    backoff: &mut Backoff,
+) -> bool {
+    report_event_error(client_id, &error.to_string());
+    match sessions.disconnected() {
+        Disconnect::Stable => backoff.reset(),
+        Disconnect::Unstable { suspected: true } => report_event_error(
+            client_id,
+            "连接后很快被断开且反复发生，可能有其他实例使用相同的客户端ID，继续重连",
+        ),
+        Disconnect::Unstable { suspected: false } => {}
+    }
+    let Some(delay) = backoff.next_delay() else {
+        return false;
+    };
+    report_status(client_id, ConnectionStatus::BackingOff);
+    tokio::time::sleep(delay).await;
+    report_status(client_id, ConnectionStatus::Connecting);
+    true
+}
+
+#[test]
+fn test_short_session_detection() {
+    let mut detector = ShortSessionDetector::new(&ReconnectPolicy::default());
+    let short = Some(Duration::from_millis(600));
+    let unstable = Disconnect::Unstable { suspected: false };
+    assert_eq!(detector.record(short), unstable);
+    assert_eq!(detector.record(short), unstable);
+    // 中间出现一次正常的长会话，重新计数
+    assert_eq!(detector.record(Some(Duration::from_secs(60))), Disconnect::Stable);
+    assert_eq!(detector.record(short), unstable);
+    assert_eq!(detector.record(None), unstable);
+    assert_eq!(detector.record(short), unstable);
+    assert_eq!(detector.record(short), unstable);
+    // 短会话只提示一次，不停止重连
+    assert_eq!(detector.record(short), Disconnect::Unstable { suspected: true });
+    assert_eq!(detector.record(short), unstable);
+}
+
+#[test]
+fn test_backoff_growth_and_limit() {
+    let mut backoff = Backoff::new(ReconnectPolicy {
+        initial_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(5),
+        jitter: 0.0,
+        max_attempts: Some(4),
+        ..ReconnectPolicy::default()
+    });
+    let delays: Vec<u64> = std::iter::from_fn(|| backoff.next_delay())
+        .map(|d| d.as_secs())
+        .collect();
+    assert_eq!(delays, vec![1, 2, 4, 5]);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Some(Duration::from_secs(1)));
+}
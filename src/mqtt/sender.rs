@@ -1,19 +1,26 @@
-use rumqttc::{AsyncClient, QoS};
+use crate::mqtt::connect::{MQTTConfig, mqtt_connect_with_config};
+use anyhow::{Error, format_err};
+use crate::mqtt::transport::Transport;
+use rumqttc::{Event, Incoming, Outgoing, QoS};
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
-/// 发送消息到指定主题
+/// 发送消息到指定主题，收到服务器确认后返回
 ///
 /// # 参数
 /// * `client` - MQTT客户端
 /// * `topic` - 目标主题
 /// * `message` - 要发送的消息
-pub async fn mqtt_send(client: &AsyncClient, topic: &str, message: &str) {
-    if let Err(e) = client
-        .publish(topic, QoS::ExactlyOnce, false, message)
-        .await
-    {
-        eprintln!("发送消息失败: {:?}", e);
-    }
+/// * `qos` - 服务质量等级
+/// * `retain` - 是否作为保留消息
+pub async fn mqtt_send(
+    client: &dyn Transport,
+    topic: &str,
+    message: &str,
+    qos: QoS,
+    retain: bool,
+) -> Result<(), Error> {
+    client.publish(topic, message, qos, retain).await
 }
 
 /// 同步发送消息到指定主题
@@ -22,9 +29,62 @@ pub async fn mqtt_send(client: &AsyncClient, topic: &str, message: &str) {
 /// * `client` - MQTT客户端
 /// * `topic` - 目标主题
 /// * `message` - 要发送的消息
-pub fn mqtt_send_sync(client: &AsyncClient, topic: &str, message: &str) {
+/// * `qos` - 服务质量等级
+/// * `retain` - 是否作为保留消息
+pub fn mqtt_send_sync(
+    client: &dyn Transport,
+    topic: &str,
+    message: &str,
+    qos: QoS,
+    retain: bool,
+) -> Result<(), Error> {
     // 创建一个运行时实例
-    let rt = Runtime::new().unwrap();
+    let rt = Runtime::new()?;
     // 在运行时中执行异步发送
-    rt.block_on(async { mqtt_send(client, topic, message).await });
+    rt.block_on(mqtt_send(client, topic, message, qos, retain))
+}
+
+/// 建立一次性连接发送消息，收到服务器确认后断开
+///
+/// # 参数
+/// * `config` - 连接参数
+/// * `client_id` - 客户端ID
+/// * `topic` - 目标主题
+/// * `message` - 要发送的消息
+/// * `qos` - 服务质量等级，QoS 1 等待 PUBACK，QoS 2 等待 PUBCOMP，QoS 0 写出即返回
+/// * `retain` - 是否作为保留消息
+/// * `timeout` - 等待服务器确认的最长时间
+pub async fn mqtt_publish_once(
+    config: &MQTTConfig,
+    client_id: &str,
+    topic: &str,
+    message: &str,
+    qos: QoS,
+    retain: bool,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let (client, mut event_loop) = mqtt_connect_with_config(config, client_id).await?;
+    client.publish(topic, qos, retain, message).await?;
+    let acked = async {
+        // 只发布了一条消息，写出时记下报文ID，再等待对应的确认
+        let mut pkid = None;
+        loop {
+            match event_loop.poll().await? {
+                Event::Outgoing(Outgoing::Publish(0)) => return Ok::<(), Error>(()),
+                Event::Outgoing(Outgoing::Publish(id)) => pkid = Some(id),
+                Event::Incoming(Incoming::PubAck(ack)) if pkid == Some(ack.pkid) => return Ok(()),
+                Event::Incoming(Incoming::PubComp(comp)) if pkid == Some(comp.pkid) => {
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(timeout, acked)
+        .await
+        .map_err(|_| format_err!("{} 秒内未收到服务器确认", timeout.as_secs()))??;
+    let _ = client.disconnect().await;
+    // 驱动事件循环把断开请求发出去
+    let _ = tokio::time::timeout(Duration::from_millis(200), event_loop.poll()).await;
+    Ok(())
 }
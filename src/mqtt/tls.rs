@@ -0,0 +1,329 @@
+use anyhow::{Error, format_err};
+use rumqttc::TlsConfiguration;
+use rumqttc::tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rumqttc::tokio_rustls::rustls::crypto::{
+    WebPkiSupportedAlgorithms, ring, verify_tls12_signature, verify_tls13_signature,
+};
+use rumqttc::tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rumqttc::tokio_rustls::rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// TLS 连接设置
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    // CA 证书文件(PEM)，为空时使用系统根证书
+    pub ca_file: Option<PathBuf>,
+    // 双向认证使用的客户端证书和私钥(PEM)
+    pub client_cert_file: Option<PathBuf>,
+    pub client_key_file: Option<PathBuf>,
+    // 跳过服务器证书校验，仅用于实验环境
+    pub insecure_skip_verify: bool,
+    pub alpn: Vec<String>,
+}
+
+/// 根据 TLS 设置构造 rumqttc 使用的 TLS 配置
+///
+/// # 参数
+/// * `settings` - TLS 设置
+///
+/// # 返回值
+/// 证书文件无法读取或格式错误时返回错误
+pub fn build_tls_config(settings: &TlsSettings) -> Result<TlsConfiguration, Error> {
+    let builder = ClientConfig::builder();
+    let builder = if settings.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoServerVerification::new()))
+    } else {
+        builder.with_root_certificates(root_store(settings.ca_file.as_deref())?)
+    };
+    let mut config = match (&settings.client_cert_file, &settings.client_key_file) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(read_certs(cert)?, read_private_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(format_err!("客户端证书和私钥需要同时配置")),
+    };
+    config.alpn_protocols = settings
+        .alpn
+        .iter()
+        .map(|p| p.as_bytes().to_vec())
+        .collect();
+    Ok(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+fn root_store(ca_file: Option<&Path>) -> Result<RootCertStore, Error> {
+    let mut store = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let (added, _) = store.add_parsable_certificates(read_certs(path)?);
+            if added == 0 {
+                return Err(format_err!("CA 文件 {} 中没有可用证书", path.display()));
+            }
+        }
+        None => {
+            let certs = rustls_native_certs::load_native_certs()
+                .map_err(|e| format_err!("读取系统根证书失败: {}", e))?;
+            store.add_parsable_certificates(certs);
+        }
+    }
+    Ok(store)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let file = File::open(path).map_err(|e| format_err!("无法打开证书 {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format_err!("{} 中没有证书", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    let file = File::open(path).map_err(|e| format_err!("无法打开私钥 {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| format_err!("{} 中没有私钥", path.display()))
+}
+
+// 不校验服务器证书链，但仍校验握手签名
+#[derive(Debug)]
+struct NoServerVerification {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl NoServerVerification {
+    fn new() -> Self {
+        NoServerVerification {
+            algorithms: ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rumqttc::tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rumqttc::tokio_rustls::rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rumqttc::tokio_rustls::rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[test]
+fn test_build_tls_config_with_self_signed_certs() {
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ca = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let client = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+    std::fs::write(dir.join("client.pem"), client.cert.pem()).unwrap();
+    std::fs::write(dir.join("client.key"), client.key_pair.serialize_pem()).unwrap();
+
+    let settings = TlsSettings {
+        enabled: true,
+        ca_file: Some(dir.join("ca.pem")),
+        client_cert_file: Some(dir.join("client.pem")),
+        client_key_file: Some(dir.join("client.key")),
+        alpn: vec!["mqtt".to_string()],
+        ..TlsSettings::default()
+    };
+    assert!(build_tls_config(&settings).is_ok());
+
+    // 只配置证书不配置私钥是错误的
+    let half = TlsSettings {
+        client_key_file: None,
+        ..settings.clone()
+    };
+    assert!(build_tls_config(&half).is_err());
+
+    let insecure = TlsSettings {
+        enabled: true,
+        insecure_skip_verify: true,
+        ..TlsSettings::default()
+    };
+    assert!(build_tls_config(&insecure).is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// 测试用的证书：一个 CA 及其签发的服务器和客户端证书
+#[cfg(test)]
+struct TestPki {
+    dir: PathBuf,
+    ca: rcgen::Certificate,
+    server: (rcgen::Certificate, rcgen::KeyPair),
+}
+
+#[cfg(test)]
+impl TestPki {
+    fn generate(name: &str) -> TestPki {
+        use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+        let dir = std::env::temp_dir().join(format!("slint_rmqtt_pki_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let leaf = |san: &str, usage| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec![san.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            (params.signed_by(&key, &ca, &ca_key).unwrap(), key)
+        };
+        let server = leaf("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = leaf("client", ExtendedKeyUsagePurpose::ClientAuth);
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.join("client.pem"), client.0.pem()).unwrap();
+        std::fs::write(dir.join("client.key"), client.1.serialize_pem()).unwrap();
+        TestPki { dir, ca, server }
+    }
+
+    // 使用该 CA 的客户端设置，with_client_cert 决定是否带客户端证书
+    fn settings(&self, with_client_cert: bool) -> TlsSettings {
+        TlsSettings {
+            enabled: true,
+            ca_file: Some(self.dir.join("ca.pem")),
+            client_cert_file: with_client_cert.then(|| self.dir.join("client.pem")),
+            client_key_file: with_client_cert.then(|| self.dir.join("client.key")),
+            ..TlsSettings::default()
+        }
+    }
+
+    // 启动一个只完成一次 TLS 握手的服务端，require_client_cert 时要求客户端证书由该 CA 签发
+    async fn serve_once(
+        &self,
+        require_client_cert: bool,
+    ) -> (u16, tokio::task::JoinHandle<Result<(), std::io::Error>>) {
+        use rumqttc::tokio_rustls::TlsAcceptor;
+        use rumqttc::tokio_rustls::rustls::ServerConfig;
+        use rumqttc::tokio_rustls::rustls::server::WebPkiClientVerifier;
+        let builder = ServerConfig::builder();
+        let builder = if require_client_cert {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.der().clone()).unwrap();
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build().unwrap())
+        } else {
+            builder.with_no_client_auth()
+        };
+        let key = PrivateKeyDer::Pkcs8(self.server.1.serialize_der().into());
+        let config = builder
+            .with_single_cert(vec![self.server.0.der().clone()], key)
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let task = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            let (stream, _) = listener.accept().await?;
+            let mut stream = TlsAcceptor::from(Arc::new(config)).accept(stream).await?;
+            // 写一个字节，让客户端在 TLS 1.3 下也能观察到服务端对客户端证书的判断
+            stream.write_all(b"\x00").await?;
+            stream.shutdown().await
+        });
+        (port, task)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestPki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// 用 build_tls_config 生成的配置和服务端完成一次握手并读取服务端写入的字节
+#[cfg(test)]
+async fn handshake(settings: &TlsSettings, port: u16) -> Result<(), Error> {
+    use rumqttc::tokio_rustls::TlsConnector;
+    use tokio::io::AsyncReadExt;
+    let TlsConfiguration::Rustls(config) = build_tls_config(settings)? else {
+        return Err(format_err!("不是 rustls 配置"));
+    };
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut stream = TlsConnector::from(config)
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_handshake_with_generated_ca() {
+    let pki = TestPki::generate("server");
+    let (port, server) = pki.serve_once(false).await;
+    handshake(&pki.settings(false), port).await.unwrap();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_tls_handshake_with_client_cert() {
+    let pki = TestPki::generate("mtls");
+    let (port, server) = pki.serve_once(true).await;
+    handshake(&pki.settings(true), port).await.unwrap();
+    server.await.unwrap().unwrap();
+
+    // 服务端要求客户端证书时，不带证书的连接被拒绝
+    let (port, server) = pki.serve_once(true).await;
+    assert!(handshake(&pki.settings(false), port).await.is_err());
+    assert!(server.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_tls_handshake_rejects_unknown_ca() {
+    let pki = TestPki::generate("trusted");
+    let other = TestPki::generate("other");
+    // 客户端只信任另一个 CA，服务器证书校验失败
+    let (port, server) = pki.serve_once(false).await;
+    assert!(handshake(&other.settings(false), port).await.is_err());
+    assert!(server.await.unwrap().is_err());
+
+    // 客户端证书不是服务端信任的 CA 签发的
+    let (port, server) = pki.serve_once(true).await;
+    let mut settings = other.settings(true);
+    settings.ca_file = pki.settings(false).ca_file;
+    assert!(handshake(&settings, port).await.is_err());
+    assert!(server.await.unwrap().is_err());
+
+    // 跳过校验时可以连上不受信任的服务器
+    let (port, server) = pki.serve_once(false).await;
+    let insecure = TlsSettings {
+        insecure_skip_verify: true,
+        ..other.settings(false)
+    };
+    handshake(&insecure, port).await.unwrap();
+    server.await.unwrap().unwrap();
+}
@@ -0,0 +1,173 @@
+use anyhow::{Error, format_err};
+use std::sync::Arc;
+
+/// 检查主题过滤器是否合法
+///
+/// `+` 必须独占一级，`#` 必须独占一级且只能在最后一级
+pub fn validate_filter(filter: &str) -> Result<(), Error> {
+    if filter.is_empty() {
+        return Err(format_err!("主题不能为空"));
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('+') && *level != "+" {
+            return Err(format_err!("主题 {} 中的 + 必须独占一级", filter));
+        }
+        if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+            return Err(format_err!("主题 {} 中的 # 必须独占最后一级", filter));
+        }
+    }
+    Ok(())
+}
+
+/// 主题是否匹配过滤器，匹配时返回通配符对应的内容
+///
+/// 每个 `+` 对应一级，`#` 对应剩余的全部层级（用 `/` 连接，可能为空）。
+/// 以 `$` 开头的系统主题不匹配首级的通配符
+///
+/// # 参数
+/// * `filter` - 主题过滤器，例如 `nodes/+/state`
+/// * `topic` - 消息的主题，例如 `nodes/n1/state`
+pub fn match_topic<'a>(filter: &str, topic: &'a str) -> Option<Vec<&'a str>> {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return None;
+    }
+    let mut wildcards = Vec::new();
+    let mut rest = Some(topic);
+    for level in filter.split('/') {
+        if level == "#" {
+            wildcards.push(rest.unwrap_or(""));
+            return Some(wildcards);
+        }
+        let current = rest?;
+        let (head, tail) = match current.split_once('/') {
+            Some((head, tail)) => (head, Some(tail)),
+            None => (current, None),
+        };
+        match level {
+            "+" => wildcards.push(head),
+            level if level == head => {}
+            _ => return None,
+        }
+        rest = tail;
+    }
+    rest.is_none().then_some(wildcards)
+}
+
+/// 主题是否匹配过滤器
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    match_topic(filter, topic).is_some()
+}
+
+// 按过滤器分发的消息
+pub struct RoutedMessage<'a> {
+    pub topic: &'a str,
+    pub payload: &'a str,
+    // 过滤器中各通配符对应的内容，顺序与过滤器中一致
+    pub wildcards: Vec<&'a str>,
+}
+
+// 消息处理函数
+pub type RouteHandler = Arc<dyn Fn(&RoutedMessage) + Send + Sync + 'static>;
+
+/// 按主题过滤器注册处理函数，每条消息只交给过滤器匹配的处理函数
+#[derive(Default, Clone)]
+pub struct TopicRouter {
+    routes: Vec<(String, RouteHandler)>,
+}
+
+impl TopicRouter {
+    /// 注册处理函数
+    ///
+    /// # 参数
+    /// * `filter` - 主题过滤器，支持 `+` 和 `#`
+    /// * `handler` - 处理函数
+    pub fn add<F>(&mut self, filter: &str, handler: F) -> Result<(), Error>
+    where
+        F: Fn(&RoutedMessage) + Send + Sync + 'static,
+    {
+        validate_filter(filter)?;
+        self.routes.push((filter.to_string(), Arc::new(handler)));
+        Ok(())
+    }
+
+    /// 已注册的过滤器（去重，按注册顺序）
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters: Vec<String> = Vec::new();
+        for (filter, _) in &self.routes {
+            if !filters.contains(filter) {
+                filters.push(filter.clone());
+            }
+        }
+        filters
+    }
+
+    /// 找出匹配的处理函数及对应的消息，不直接调用，方便调用方决定在哪个线程执行
+    pub fn route<'a>(&self, topic: &'a str, payload: &'a str) -> Vec<(RouteHandler, RoutedMessage<'a>)> {
+        self.routes
+            .iter()
+            .filter_map(|(filter, handler)| {
+                let wildcards = match_topic(filter, topic)?;
+                Some((
+                    handler.clone(),
+                    RoutedMessage {
+                        topic,
+                        payload,
+                        wildcards,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// 分发消息
+    ///
+    /// # 返回值
+    /// 处理该消息的处理函数个数
+    pub fn dispatch(&self, topic: &str, payload: &str) -> usize {
+        let routes = self.route(topic, payload);
+        for (handler, message) in &routes {
+            handler(message);
+        }
+        routes.len()
+    }
+}
+
+#[test]
+fn test_topic_filters_and_routing() {
+    use std::sync::Mutex;
+
+    assert_eq!(match_topic("nodes/+/state", "nodes/n1/state"), Some(vec!["n1"]));
+    assert_eq!(match_topic("nodes/+/state", "nodes/n1/other"), None);
+    assert_eq!(match_topic("nodes/+", "nodes/n1/state"), None);
+    assert_eq!(match_topic("nodes/#", "nodes/n1/state"), Some(vec!["n1/state"]));
+    assert_eq!(match_topic("nodes/#", "nodes"), Some(vec![""]));
+    assert_eq!(match_topic("+/+", "a/"), Some(vec!["a", ""]));
+    assert!(topic_matches("server/0", "server/0"));
+    assert!(!topic_matches("server/0", "server/0/reply"));
+    assert!(!topic_matches("#", "$SYS/broker/uptime"));
+    assert!(validate_filter("nodes/+/state").is_ok());
+    assert!(validate_filter("nodes/n+/state").is_err());
+    assert!(validate_filter("nodes/#/state").is_err());
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut router = TopicRouter::default();
+    let s = seen.clone();
+    router
+        .add("nodes/+/state", move |m| {
+            s.lock().unwrap().push(format!("state:{}", m.wildcards[0]))
+        })
+        .unwrap();
+    let s = seen.clone();
+    router
+        .add("nodes/#", move |m| s.lock().unwrap().push(format!("all:{}", m.topic)))
+        .unwrap();
+    assert_eq!(router.dispatch("nodes/n1/state", "{}"), 2);
+    assert_eq!(router.dispatch("nodes/n1/log", "{}"), 1);
+    assert_eq!(router.dispatch("server/0", "{}"), 0);
+    assert_eq!(
+        *seen.lock().unwrap(),
+        ["state:n1", "all:nodes/n1/state", "all:nodes/n1/log"]
+    );
+    assert_eq!(router.filters(), ["nodes/+/state", "nodes/#"]);
+}
@@ -0,0 +1,84 @@
+use chrono::{DateTime, Local};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// 收发消息计数
+#[derive(Default)]
+pub struct TrafficStats {
+    received: u64,
+    sent: u64,
+    // 最近一次收到消息的时间
+    last_message: Option<DateTime<Local>>,
+    // 上次计算速率时的时间和计数
+    sample: Option<(Instant, u64, u64)>,
+}
+
+/// 所有连接共用的收发统计
+pub static TRAFFIC: Lazy<Mutex<TrafficStats>> = Lazy::new(|| Mutex::new(TrafficStats::default()));
+
+/// 记录收到一条消息
+pub fn record_received() {
+    if let Ok(mut traffic) = TRAFFIC.lock() {
+        traffic.received += 1;
+        traffic.last_message = Some(Local::now());
+    }
+}
+
+/// 记录成功发出一条消息
+pub fn record_sent() {
+    if let Ok(mut traffic) = TRAFFIC.lock() {
+        traffic.sent += 1;
+    }
+}
+
+impl TrafficStats {
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    pub fn last_message(&self) -> Option<DateTime<Local>> {
+        self.last_message
+    }
+
+    /// 计算距离上次调用期间每秒收发的消息数，第一次调用返回 0
+    ///
+    /// # 返回值
+    /// (每秒收到, 每秒发出)
+    pub fn sample_rates(&mut self, now: Instant) -> (f64, f64) {
+        let rates = match self.sample {
+            Some((at, received, sent)) => {
+                let secs = now.duration_since(at).as_secs_f64();
+                if secs > 0.0 {
+                    (
+                        (self.received - received) as f64 / secs,
+                        (self.sent - sent) as f64 / secs,
+                    )
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+            None => (0.0, 0.0),
+        };
+        self.sample = Some((now, self.received, self.sent));
+        rates
+    }
+}
+
+#[test]
+fn test_traffic_rates() {
+    use std::time::Duration;
+
+    let mut traffic = TrafficStats::default();
+    let start = Instant::now();
+    assert_eq!(traffic.sample_rates(start), (0.0, 0.0));
+    traffic.received += 10;
+    traffic.sent += 3;
+    assert_eq!(traffic.sample_rates(start + Duration::from_secs(2)), (5.0, 1.5));
+    assert_eq!(traffic.sample_rates(start + Duration::from_secs(3)), (0.0, 0.0));
+    assert_eq!((traffic.received(), traffic.sent()), (10, 3));
+}
@@ -0,0 +1,414 @@
+use crate::mqtt::connect::{MQTTConfig, mqtt_connect_with_config};
+use crate::mqtt::topic::topic_matches;
+use anyhow::{Error, format_err};
+use futures::FutureExt;
+use futures::future::BoxFuture;
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, Outgoing, QoS};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio::sync::oneshot;
+
+// 收到的消息
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IncomingMessage {
+    pub topic: String,
+    pub payload: String,
+    pub qos: QoS,
+    // 服务器保存的保留消息（订阅时收到）
+    pub retain: bool,
+}
+
+// 传输层事件
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransportEvent {
+    // 连接（或重连）成功，需要重新订阅
+    Connected,
+    Message(IncomingMessage),
+    // 其他不需要处理的协议事件
+    Other,
+}
+
+/// 把配置中的 QoS 等级（0、1、2）转换为 rumqttc 的 QoS
+pub fn parse_qos(level: u8) -> Result<QoS, Error> {
+    rumqttc::qos(level).map_err(|_| format_err!("不支持的 QoS 等级: {}，只能是 0、1、2", level))
+}
+
+/// 发送端：发布和订阅
+pub trait Transport: Send + Sync {
+    /// 发布消息，QoS 1 收到 PUBACK、QoS 2 收到 PUBCOMP 后才返回成功，QoS 0 在消息写出后返回
+    ///
+    /// 需要接收端同时在运行，否则收不到确认
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+        qos: QoS,
+        retain: bool,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn subscribe<'a>(&'a self, topic: &'a str, qos: QoS) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// 接收端：依次取出连接事件和收到的消息
+///
+/// 返回错误表示连接断开，之后再次调用会尝试重新连接
+pub trait TransportEvents: Send {
+    fn poll(&mut self) -> BoxFuture<'_, Result<TransportEvent, Error>>;
+}
+
+/// 一条连接的发送端和接收端
+pub type TransportPair = (Arc<dyn Transport>, Box<dyn TransportEvents>);
+
+// 等待服务器确认的发布
+//
+// rumqttc 按提交顺序逐个写出发布请求，写出时才分配报文ID，
+// 所以先按顺序排队，写出（Outgoing::Publish）时再按报文ID登记
+#[derive(Default)]
+struct PendingAcks {
+    // 已提交、尚未写出的发布
+    queued: VecDeque<oneshot::Sender<()>>,
+    // 已写出、等待 PUBACK/PUBCOMP 的发布
+    inflight: HashMap<u16, oneshot::Sender<()>>,
+}
+
+impl PendingAcks {
+    fn written(&mut self, pkid: u16) {
+        // 重连后 rumqttc 会用原来的报文ID重发未确认的消息，这些消息已经登记过
+        if pkid != 0 && self.inflight.contains_key(&pkid) {
+            return;
+        }
+        let Some(sender) = self.queued.pop_front() else {
+            return;
+        };
+        if pkid == 0 {
+            // QoS 0 没有确认，写出即完成
+            let _ = sender.send(());
+        } else {
+            self.inflight.insert(pkid, sender);
+        }
+    }
+
+    fn acked(&mut self, pkid: u16) {
+        if let Some(sender) = self.inflight.remove(&pkid) {
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// rumqttc 客户端，发布时等待服务器确认
+pub struct MqttClient {
+    client: AsyncClient,
+    acks: Arc<Mutex<PendingAcks>>,
+    // 保证排队顺序与提交给 rumqttc 的顺序一致
+    order: tokio::sync::Mutex<()>,
+}
+
+/// rumqttc 事件循环，同时处理发布确认
+pub struct MqttEvents {
+    event_loop: EventLoop,
+    acks: Arc<Mutex<PendingAcks>>,
+}
+
+impl Transport for MqttClient {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+        qos: QoS,
+        retain: bool,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let (sender, receiver) = oneshot::channel();
+            {
+                let _order = self.order.lock().await;
+                self.acks.lock().unwrap().queued.push_back(sender);
+                if let Err(e) = self
+                    .client
+                    .publish(topic, qos, retain, payload.as_bytes().to_vec())
+                    .await
+                {
+                    self.acks.lock().unwrap().queued.pop_back();
+                    return Err(e.into());
+                }
+            }
+            receiver
+                .await
+                .map_err(|_| format_err!("连接已关闭，未收到服务器对 {} 的确认", topic))
+        }
+        .boxed()
+    }
+
+    fn subscribe<'a>(&'a self, topic: &'a str, qos: QoS) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            self.client.subscribe(topic, qos).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl TransportEvents for MqttEvents {
+    fn poll(&mut self) -> BoxFuture<'_, Result<TransportEvent, Error>> {
+        async move {
+            Ok(match self.event_loop.poll().await? {
+                Event::Outgoing(Outgoing::Publish(pkid)) => {
+                    self.acks.lock().unwrap().written(pkid);
+                    TransportEvent::Other
+                }
+                Event::Incoming(Incoming::PubAck(ack)) => {
+                    self.acks.lock().unwrap().acked(ack.pkid);
+                    TransportEvent::Other
+                }
+                Event::Incoming(Incoming::PubComp(comp)) => {
+                    self.acks.lock().unwrap().acked(comp.pkid);
+                    TransportEvent::Other
+                }
+                Event::Incoming(Incoming::ConnAck(_)) => TransportEvent::Connected,
+                Event::Incoming(Incoming::Publish(publish)) => {
+                    // 非UTF-8消息不处理
+                    match String::from_utf8(publish.payload.to_vec()) {
+                        Ok(payload) => TransportEvent::Message(IncomingMessage {
+                            topic: publish.topic,
+                            payload,
+                            qos: publish.qos,
+                            retain: publish.retain,
+                        }),
+                        Err(_) => TransportEvent::Other,
+                    }
+                }
+                _ => TransportEvent::Other,
+            })
+        }
+        .boxed()
+    }
+}
+
+impl Drop for MqttEvents {
+    fn drop(&mut self) {
+        // 事件循环停止后不会再收到确认，让等待中的发布立即失败
+        let mut acks = self.acks.lock().unwrap();
+        acks.queued.clear();
+        acks.inflight.clear();
+    }
+}
+
+/// 按连接配置建立 rumqttc 连接
+///
+/// # 参数
+/// * `config` - 连接配置
+/// * `client_id` - 客户端ID
+pub async fn mqtt_transport(config: &MQTTConfig, client_id: &str) -> Result<TransportPair, Error> {
+    let (client, event_loop) = mqtt_connect_with_config(config, client_id).await?;
+    let acks = Arc::new(Mutex::new(PendingAcks::default()));
+    let client = MqttClient {
+        client,
+        acks: acks.clone(),
+        order: tokio::sync::Mutex::new(()),
+    };
+    Ok((Arc::new(client), Box::new(MqttEvents { event_loop, acks })))
+}
+
+// 内存回环中的一个连接
+struct LoopbackSubscriber {
+    client_id: String,
+    topics: Vec<String>,
+    sender: UnboundedSender<TransportEvent>,
+}
+
+#[derive(Default)]
+struct LoopbackState {
+    subscribers: Vec<LoopbackSubscriber>,
+    // (客户端ID, 消息)
+    published: Vec<(String, IncomingMessage)>,
+}
+
+/// 内存中的消息回环，不需要网络，用于测试
+///
+/// 每个连接建立后立即收到 `Connected` 事件，发布的消息投递给过滤器匹配的订阅者。
+/// 被断开的连接与 rumqttc 一样，下一次 `poll` 时重新连上
+#[derive(Clone, Default)]
+pub struct LoopbackBroker {
+    state: Arc<Mutex<LoopbackState>>,
+}
+
+impl LoopbackBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 建立一条连接
+    pub fn connect(&self, client_id: &str) -> TransportPair {
+        let client = LoopbackClient {
+            client_id: client_id.to_string(),
+            broker: self.clone(),
+        };
+        let events = LoopbackEvents {
+            client_id: client_id.to_string(),
+            broker: self.clone(),
+            receiver: Some(self.attach(client_id)),
+        };
+        (Arc::new(client), Box::new(events))
+    }
+
+    // 登记一个新会话，订阅从空开始
+    fn attach(&self, client_id: &str) -> UnboundedReceiver<TransportEvent> {
+        let (sender, receiver) = unbounded_channel();
+        let _ = sender.send(TransportEvent::Connected);
+        self.state.lock().unwrap().subscribers.push(LoopbackSubscriber {
+            client_id: client_id.to_string(),
+            topics: Vec::new(),
+            sender,
+        });
+        receiver
+    }
+
+    /// 像服务器关闭连接那样断开指定客户端ID的连接，不告知原因
+    pub fn disconnect(&self, client_id: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .retain(|s| s.client_id != client_id);
+    }
+
+    /// 所有已发布的消息，按发布顺序，附带发布者的客户端ID
+    pub fn published(&self) -> Vec<(String, IncomingMessage)> {
+        self.state.lock().unwrap().published.clone()
+    }
+}
+
+struct LoopbackClient {
+    client_id: String,
+    broker: LoopbackBroker,
+}
+
+impl Transport for LoopbackClient {
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: &'a str,
+        qos: QoS,
+        retain: bool,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let message = IncomingMessage {
+                topic: topic.to_string(),
+                payload: payload.to_string(),
+                qos,
+                retain,
+            };
+            let mut state = self.broker.state.lock().unwrap();
+            state
+                .published
+                .push((self.client_id.clone(), message.clone()));
+            // 接收端已丢弃的连接不再投递
+            state.subscribers.retain(|s| !s.sender.is_closed());
+            for subscriber in &state.subscribers {
+                if subscriber.topics.iter().any(|t| topic_matches(t, topic)) {
+                    let _ = subscriber
+                        .sender
+                        .send(TransportEvent::Message(message.clone()));
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn subscribe<'a>(&'a self, topic: &'a str, _qos: QoS) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut state = self.broker.state.lock().unwrap();
+            let subscriber = state
+                .subscribers
+                .iter_mut()
+                .find(|s| s.client_id == self.client_id)
+                .ok_or_else(|| format_err!("连接 {} 已断开", self.client_id))?;
+            if !subscriber.topics.iter().any(|t| t == topic) {
+                subscriber.topics.push(topic.to_string());
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+struct LoopbackEvents {
+    client_id: String,
+    broker: LoopbackBroker,
+    // None 表示连接已断开，下一次 poll 时重新连接
+    receiver: Option<UnboundedReceiver<TransportEvent>>,
+}
+
+impl TransportEvents for LoopbackEvents {
+    fn poll(&mut self) -> BoxFuture<'_, Result<TransportEvent, Error>> {
+        async move {
+            let receiver = match &mut self.receiver {
+                Some(receiver) => receiver,
+                None => self.receiver.insert(self.broker.attach(&self.client_id)),
+            };
+            match receiver.recv().await {
+                Some(event) => Ok(event),
+                None => {
+                    self.receiver = None;
+                    Err(format_err!("回环连接已关闭"))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[test]
+fn test_pending_acks_follow_packet_ids() {
+    let mut acks = PendingAcks::default();
+    let mut receivers = Vec::new();
+    for _ in 0..3 {
+        let (sender, receiver) = oneshot::channel();
+        acks.queued.push_back(sender);
+        receivers.push(receiver);
+    }
+    acks.written(1);
+    acks.written(0);
+    acks.written(2);
+    // QoS 0 写出即完成，其余等待对应报文ID的确认
+    assert!(receivers[1].try_recv().is_ok());
+    assert!(receivers[0].try_recv().is_err());
+    // 重连后重发不会占用后面的排队项
+    acks.written(1);
+    acks.acked(2);
+    assert!(receivers[2].try_recv().is_ok());
+    assert!(receivers[0].try_recv().is_err());
+    acks.acked(1);
+    assert!(receivers[0].try_recv().is_ok());
+    assert!(acks.queued.is_empty() && acks.inflight.is_empty());
+}
+
+#[tokio::test]
+async fn test_loopback_delivers_to_subscribers() {
+    let broker = LoopbackBroker::new();
+    let (publisher, _) = broker.connect("pub");
+    let (subscriber, mut events) = broker.connect("sub");
+    assert_eq!(events.poll().await.unwrap(), TransportEvent::Connected);
+    subscriber.subscribe("node/+", QoS::AtLeastOnce).await.unwrap();
+    publisher
+        .publish("node/1/log", "ignored", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    publisher
+        .publish("node/1", "hello", QoS::AtLeastOnce, false)
+        .await
+        .unwrap();
+    let expected = IncomingMessage {
+        topic: "node/1".to_string(),
+        payload: "hello".to_string(),
+        qos: QoS::AtLeastOnce,
+        retain: false,
+    };
+    assert_eq!(
+        events.poll().await.unwrap(),
+        TransportEvent::Message(expected.clone())
+    );
+    assert_eq!(broker.published().len(), 2);
+    assert_eq!(broker.published()[1], ("pub".to_string(), expected));
+}
@@ -1,173 +1,640 @@
+use crate::broker::{start_broker, stop_broker};
+use crate::command_store::{StoredCommand, load_commands};
+use crate::command_tracker::{
+    CommandStatus, handle_ack, mark_cancelled, mark_expired, mark_queued, mark_requeued, mark_send_failed,
+    new_correlation_id, track_command,
+};
+use crate::mqtt::connect::MQTTConfig;
 use crate::mqtt::pool::MqttConnectionPool;
-use crate::mqtt::receiver::{MQTTConfig, Receiver, mqtt_receiver_with_config};
-use crate::node_state_entity::{ NodeStateChangeString, NodeStateRegister};
+use crate::mqtt::receiver::{Receiver, mqtt_receiver_with_config};
+use crate::mqtt::reconnect::{CONNECTION_STATUS_EVENT, clear_status, report_error, wait_connected};
+use crate::mqtt::topic::RoutedMessage;
+use crate::mqtt::transport::parse_qos;
+use crate::node_registry::{NodeInfo, NodeRegistry};
+use crate::node_state_entity::{NodeCommand, NodeStateChangeString, NodeStateRegister};
+use crate::outbox::{OUTBOX, unix_now};
+use crate::profile::ConnectionProfile;
+use crate::rule_engine::{RULES, RuleFiring, record_activity};
+use crate::store::{
+    CommandAudit, close_store, discard_command, open_store, persist_command, persist_node, restore_nodes, store_path,
+};
 use anyhow::{Error, format_err};
+use chrono::{Local, TimeZone};
 use once_cell::sync::Lazy;
+use rumqttc::QoS;
+use serde_json::Value;
 use futures::executor::block_on;
 use tokio::sync::Mutex;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
 use std::collections::HashMap;
-use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use crate::UPDATE_STATE_EVENT;
 
 pub static TOPIC2RECEIVER: Lazy<Mutex<HashMap<String, Arc<Mutex<Receiver>>>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
-pub static POOL: OnceLock<Arc<MqttConnectionPool>> = OnceLock::new();
-
-pub static STATE: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// 暂时不内联 rmqtt
-// pub struct BrokerConfigure {
-//     pub class: String,
-//     pub name: String,
-//     pub addr: [u8; 4],
-//     pub port: u16,
-// }
-// impl BrokerConfigure {
-//     pub fn new(class: &str, name: &str, addr: [u8; 4], port: u16) -> Self {
-//         BrokerConfigure {
-//             class: String::from(class),
-//             name: String::from(name),
-//             addr,
-//             port,
-//         }
-//     }
-// }
-// pub async fn run_mqtt_broker(configures: Vec<BrokerConfigure>) -> Result<(), Err> {
-//     let scx = ServerContext::new().build().await;
-//     let mut mqtt_server = MqttServer::new(scx);
-//     for config in configures {
-//         match config.class.as_str() {
-//             "tcp" => {
-//                 mqtt_server = mqtt_server.listener(
-//                     Builder::new()
-//                         .name(config.name.as_str())
-//                         .laddr((config.addr, config.port).into())
-//                         .bind()?
-//                         .tcp()?,
-//                 );
-//             }
-//             "ws" => {
-//                 mqtt_server = mqtt_server.listener(
-//                     Builder::new()
-//                         .name(config.name.as_str())
-//                         .laddr((config.addr, config.port).into())
-//                         .bind()?
-//                         .ws()?,
-//                 );
-//             }
-//             _ => {}
-//         }
-//     }
-//     mqtt_server.build().run().await?;
-//     Ok(())
-// }
-
-pub async fn init_client_pool(name: String, size: usize) -> Result<(), Error> {
-    POOL.set(Arc::new(
-        MqttConnectionPool::new(name.as_str(), "", "", size).await,
-    ))
-    .map_err(|_| format_err!("Can't init MqttConnection pool"))
-    .expect("cant format error");
+pub static POOL: Lazy<RwLock<Option<Arc<MqttConnectionPool>>>> = Lazy::new(|| RwLock::new(None));
+
+/// 当前使用的连接配置
+pub static ACTIVE_PROFILE: Lazy<RwLock<Option<ConnectionProfile>>> = Lazy::new(|| RwLock::new(None));
+
+// 当前运行中的连接任务，切换配置时需要中止
+static MQTT_TASK: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
+
+// add_mqtt_receiver 创建的接收器序号
+static RECEIVER_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+pub static NODES: Lazy<Mutex<NodeRegistry>> = Lazy::new(|| Mutex::new(NodeRegistry::default()));
+
+pub async fn init_client_pool(config: &MQTTConfig, size: usize) -> Result<(), Error> {
+    let pool = Arc::new(MqttConnectionPool::new_with_config(config, size).await?);
+    *POOL
+        .write()
+        .map_err(|_| format_err!("Can't init MqttConnection pool"))? = Some(pool);
     Ok(())
 }
 
-pub async fn send_mqtt_msg(topic: String, msg: String) -> Result<(), Error> {
-    let pool = POOL.get().expect("Pool not initialized");
-    pool.send(topic.as_str(), msg.as_str()).await?;
+/// 获取当前连接池，未连接时返回 None
+pub fn current_pool() -> Option<Arc<MqttConnectionPool>> {
+    POOL.read().ok().and_then(|pool| pool.clone())
+}
+
+pub async fn send_mqtt_msg(topic: String, msg: String, qos: QoS, retain: bool) -> Result<(), Error> {
+    let pool = current_pool().ok_or_else(|| format_err!("Pool not initialized"))?;
+    pool.send(topic.as_str(), msg.as_str(), qos, retain).await?;
     Ok(())
 }
-pub async fn add_mqtt_receiver(server_url: &str, topic: &str) -> Result<(), Error> {
-    let mqtt_client_cfg = MQTTConfig {
-        host: server_url.to_string(),
-        username: "".to_string(),
-        password: "".to_string(),
-    };
+pub async fn add_mqtt_receiver(config: &MQTTConfig, topic: &str) -> Result<(), Error> {
+    // 每个接收器使用不同的客户端ID，避免互相顶替
+    let role = format!("recv_{}", RECEIVER_INDEX.fetch_add(1, Ordering::Relaxed));
+    let receiver = mqtt_receiver_with_config(config, topic, &role).await?;
     let mut topic2receiver = TOPIC2RECEIVER.lock().await;
-    topic2receiver.insert(
-        topic.to_string(),
-        Arc::new(Mutex::new(
-            mqtt_receiver_with_config(&mqtt_client_cfg, topic).await,
-        )),
-    );
+    topic2receiver.insert(topic.to_string(), Arc::new(Mutex::new(receiver)));
     Ok(())
 }
 
-pub async fn add_state_receiver(server_url: &str, topic: &str) -> Result<(), Error> {
-    let mqtt_client_cfg = MQTTConfig {
-        host: server_url.to_string(),
-        username: "".to_string(),
-        password: "".to_string(),
+pub async fn add_state_receiver(config: &MQTTConfig, topic: &str, qos: QoS) -> Result<(), Error> {
+    let mut receiver = mqtt_receiver_with_config(config, topic, "state").await?;
+    receiver.set_qos(qos);
+    register_state_receiver(receiver, topic).await
+}
+
+// 状态主题带通配符时（例如 `nodes/+/state`），消息中没有 id 则使用第一个通配符对应的主题层级
+fn state_payload(message: &RoutedMessage) -> String {
+    let Some(node_id) = message.wildcards.first().filter(|id| !id.is_empty()) else {
+        return message.payload.to_string();
     };
-    let receiver = mqtt_receiver_with_config(&mqtt_client_cfg, topic).await;
-    receiver.add_callback(|msg| {
+    match serde_json::from_str::<Value>(message.payload) {
+        Ok(Value::Object(mut object)) if !object.contains_key("id") => {
+            object.insert("id".to_string(), Value::from(*node_id));
+            Value::Object(object).to_string()
+        }
+        _ => message.payload.to_string(),
+    }
+}
+
+/// 为接收器添加状态处理：注册消息和状态变化写入节点表并通知界面刷新
+///
+/// # 参数
+/// * `receiver` - 订阅状态主题的接收器，可以使用任意传输层，按接收器的 QoS 订阅
+/// * `topic` - 状态主题，可以是带通配符的过滤器
+pub async fn register_state_receiver(receiver: Receiver, topic: &str) -> Result<(), Error> {
+    let runtime = Handle::current();
+    receiver.add_route(topic, receiver.qos(), move |message| {
+        let msg = state_payload(message);
+        let msg = msg.as_str();
+        // 只在更新节点表时持有锁，写数据库和检查规则使用节点的副本
         if let Ok(change_data) = serde_json::from_str::<NodeStateChangeString>(msg) {
-            let mut state =  block_on(STATE.lock());
-            change_data
-                .state_change
-                .iter()
-                .for_each(|(k, v)| match state.get_mut(k) {
-                    None => {
-                        println!("State change {} not found", k)
-                    }
-                    Some(ori) => {
-                        *ori = v.clone();
-                    }
-                });
+            let changed: Vec<String> = change_data.state_change.keys().cloned().collect();
+            let node = {
+                let mut nodes = block_on(NODES.lock());
+                // 未注册节点的状态变更忽略
+                let Ok(id) = nodes.apply_change(change_data) else {
+                    return;
+                };
+                nodes.get(&id).cloned()
+            };
+            if let Some(node) = node {
+                persist_node(&node, &changed);
+                run_rules(&runtime, &node);
+            }
             // 更新视图
-            UPDATE_STATE_EVENT.clone().notify(1);
+            UPDATE_STATE_EVENT.clone().notify(usize::MAX);
         } else if let Ok(register) = serde_json::from_str::<NodeStateRegister>(msg) {
-            println!("Register found");
-            let mut state = block_on(STATE.lock());
-            state.insert("id".to_string(), register.id);
-            state.insert(
-                "position_type".to_string(),
-                register.position_type.to_string(),
-            );
-            state.insert(
-                "position".to_string(),
-                register.position.iter().fold(String::new(), |mut acc, s| {
-                    acc.push_str(s.to_string().as_str());
-                    acc
-                }),
-            );
-            for (k, v) in register.state.iter() {
-                state.insert(k.to_string(), v.to_string());
-            }
+            let node = block_on(NODES.lock()).register(register).clone();
+            persist_node(&node, &node.state.keys().cloned().collect::<Vec<_>>());
+            run_rules(&runtime, &node);
             // 通知更新视图
-            UPDATE_STATE_EVENT.clone().notify(1);
-        } else {
-            println!("State change {} not found", msg)
+            UPDATE_STATE_EVENT.clone().notify(usize::MAX);
         }
+    })?;
+    let mut topic2receiver = TOPIC2RECEIVER.lock().await;
+    topic2receiver.insert(topic.to_string(), Arc::new(Mutex::new(receiver)));
+    Ok(())
+}
+
+// 节点状态变化后检查自动执行规则，满足条件的规则在后台发送命令
+//
+// 接收器的回调运行在普通线程中，需要传入 tokio 运行时
+fn run_rules(runtime: &Handle, node: &NodeInfo) {
+    let Ok(mut rules) = RULES.lock() else {
+        return;
+    };
+    let check = rules.evaluate(node, Local::now());
+    // 等待条件持续或冷却结束期间可能不再有状态变化，到时间后按最新状态再检查一次；
+    // 每个规则和节点只保留一个定时任务，条件不再满足时任务被取消
+    for recheck in check.rechecks {
+        let handle = runtime.clone();
+        let task = recheck.clone();
+        let timer = runtime.spawn(async move {
+            tokio::time::sleep(task.after).await;
+            if let Ok(mut rules) = RULES.lock() {
+                rules.finish_recheck(&task);
+            }
+            let node = NODES.lock().await.get(&task.node).cloned();
+            if let Some(node) = node {
+                run_rules(&handle, &node);
+            }
+        });
+        rules.set_recheck_task(&recheck, timer);
+    }
+    drop(rules);
+    for firing in check.fired {
+        runtime.spawn(fire_rule(firing));
+    }
+}
+
+// 发送规则对应的命令，试运行时只记录
+async fn fire_rule(firing: RuleFiring) {
+    if firing.dry_run {
+        record_activity(&firing, "试运行，未发送".to_string());
+        return;
+    }
+    let command = load_commands().and_then(|commands| {
+        commands
+            .into_iter()
+            .find(|c| c.name == firing.command)
+            .ok_or_else(|| format_err!("命令库中没有命令: {}", firing.command))
+    });
+    let result = match command {
+        Ok(command) => send_or_queue_command(&command).await,
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        Ok(Dispatch::Sent(_)) => "已发送".to_string(),
+        Ok(Dispatch::Queued(_)) => "未连接，已进入发送队列".to_string(),
+        Err(e) => format!("发送失败: {}", e),
+    };
+    record_activity(&firing, result);
+}
+
+/// 添加命令回复接收器，收到的回复交给命令跟踪处理
+pub async fn add_reply_receiver(config: &MQTTConfig, topic: &str, qos: QoS) -> Result<(), Error> {
+    let mut receiver = mqtt_receiver_with_config(config, topic, "reply").await?;
+    receiver.set_qos(qos);
+    receiver.add_callback(|msg| {
+        handle_ack(msg);
     });
     let mut topic2receiver = TOPIC2RECEIVER.lock().await;
     topic2receiver.insert(topic.to_string(), Arc::new(Mutex::new(receiver)));
     Ok(())
 }
 
-pub async  fn init_mqtt(server_url: &str, topic: &str) {
-    init_client_pool(server_url.to_string(), 10)
+// 取出接收器后立即释放映射表的锁，避免事件循环期间一直占用
+async fn take_receiver(topic: &str) -> Result<Arc<Mutex<Receiver>>, Error> {
+    TOPIC2RECEIVER
+        .lock()
         .await
-        .unwrap();
-    add_state_receiver(server_url, topic)
+        .get(topic)
+        .cloned()
+        .ok_or_else(|| format_err!("主题 {} 的接收器不存在", topic))
+}
+
+// 按配置启动或停止内置服务器
+async fn prepare_broker(profile: &ConnectionProfile) -> Result<(), Error> {
+    if profile.embedded_broker {
+        start_broker(&profile.broker_listeners).await
+    } else {
+        stop_broker().await;
+        Ok(())
+    }
+}
+
+// 按配置打开或关闭本地数据库
+//
+// `restore` 为 true 时把保存的节点和保留时间内的状态历史读入节点表
+async fn prepare_store(profile: &ConnectionProfile, restore: bool) -> Result<(), Error> {
+    if !profile.local_store {
+        close_store();
+        return Ok(());
+    }
+    // 数据库不可用（例如编译时未启用 sqlite 功能）时照常连接，只是不保存记录
+    if let Err(e) = open_store(&store_path(), profile.history_retention().max_age) {
+        eprintln!("本地数据库不可用，不保存节点和命令记录: {}", e);
+        return Ok(());
+    }
+    if !restore {
+        return Ok(());
+    }
+    let retention = chrono::Duration::seconds(profile.history_retention_secs.min(i64::MAX as u64) as i64);
+    let since = Local::now()
+        .checked_sub_signed(retention)
+        .unwrap_or_else(|| Local.timestamp_opt(0, 0).unwrap());
+    let (nodes, history) = restore_nodes(since)?;
+    let mut registry = NODES.lock().await;
+    for node in nodes {
+        registry.insert(node);
+    }
+    for (node, key, point) in history {
+        registry.history_mut().record(&node, &key, &point.value, point.at);
+    }
+    UPDATE_STATE_EVENT.clone().notify(usize::MAX);
+    Ok(())
+}
+
+pub async  fn init_mqtt(profile: ConnectionProfile) -> Result<(), Error> {
+    prepare_broker(&profile).await?;
+    let config = profile.mqtt_config();
+    NODES
+        .lock()
         .await
-        .unwrap();
-    let top2r = TOPIC2RECEIVER.lock().await;
-    let mut receiver = top2r.get(topic).unwrap().lock().await;
-    receiver.start().await;
+        .history_mut()
+        .set_retention(profile.history_retention());
+    prepare_store(&profile, true).await?;
+    init_client_pool(&config, profile.pool_size).await?;
+    add_state_receiver(&config, &profile.state_topic, parse_qos(profile.state_qos)?).await?;
+    add_reply_receiver(&config, &profile.reply_topic, parse_qos(profile.reply_qos)?).await?;
+    let state_receiver = take_receiver(&profile.state_topic).await?;
+    let reply_receiver = take_receiver(&profile.reply_topic).await?;
+    let mut state_receiver = state_receiver.lock().await;
+    let mut reply_receiver = reply_receiver.lock().await;
+    tokio::join!(state_receiver.start(), reply_receiver.start(), flush_outbox_on_connect());
+    Ok(())
+}
+
+/// 只建立发送命令所需的连接（连接池和回复接收器），不订阅状态主题
+///
+/// 命令行客户端使用，回复接收器连上服务器后才返回，避免漏掉很快到达的回复
+pub async fn init_command_client(profile: ConnectionProfile) -> Result<(), Error> {
+    shutdown_mqtt().await;
+    prepare_broker(&profile).await?;
+    prepare_store(&profile, false).await?;
+    let config = profile.mqtt_config();
+    init_client_pool(&config, profile.pool_size).await?;
+    add_reply_receiver(&config, &profile.reply_topic, parse_qos(profile.reply_qos)?).await?;
+    let reply_receiver = take_receiver(&profile.reply_topic).await?;
+    let client_id = reply_receiver.lock().await.client_id().to_string();
+    let timeout = Duration::from_secs(profile.ack_timeout_secs);
+    if let Ok(mut active) = ACTIVE_PROFILE.write() {
+        *active = Some(profile);
+    }
+    let task = tokio::spawn(async move {
+        reply_receiver.lock().await.start().await;
+    });
+    *MQTT_TASK.lock().await = Some(task);
+    if !wait_connected(&client_id, timeout).await {
+        return Err(format_err!("{} 秒内未能连接到服务器", timeout.as_secs()));
+    }
+    Ok(())
 }
+
+/// 发送命令，并开始等待节点回复
+///
+/// # 参数
+/// * `command` - 要发送的命令
+///
+/// # 返回值
+/// 返回本次发送的关联ID，可用于匹配节点回复
+pub async fn send_command(command: &StoredCommand) -> Result<String, Error> {
+    let pool = current_pool().ok_or_else(|| format_err!("尚未连接到服务器"))?;
+    publish_command(&pool, command, new_correlation_id(), false).await
+}
+
+// 使用指定的关联ID发送命令，并开始等待节点回复
+//
+// `queued` 为 true 表示发送的是队列中的命令，因连接中断失败时回到队列，不记为失败
+async fn publish_command(
+    pool: &MqttConnectionPool,
+    command: &StoredCommand,
+    id: String,
+    queued: bool,
+) -> Result<String, Error> {
+    let (reply_topic, timeout, sender) = ACTIVE_PROFILE
+        .read()
+        .map_err(|_| format_err!("读取连接配置失败"))?
+        .as_ref()
+        .map(|p| {
+            (
+                p.reply_topic.clone(),
+                Duration::from_secs(p.ack_timeout_secs),
+                command_sender(p),
+            )
+        })
+        .unwrap_or_default();
+    let op_value: HashMap<String, serde_json::Value> = command
+        .values
+        .iter()
+        .map(|p| (p.key.clone(), p.value.clone()))
+        .collect();
+    let payload = NodeCommand::builder()
+        .id(id.clone())
+        .reply_to(reply_topic)
+        .op(command.name.clone())
+        .op_value(op_value)
+        .build();
+    let text = serde_json::to_string(&payload)?;
+    let qos = command.delivery.qos_level()?;
+    track_command(&id, &command.name, timeout);
+    persist_command(&CommandAudit {
+        id: id.clone(),
+        name: command.name.clone(),
+        sender,
+        topic: command.topic.clone(),
+        payload: text.clone(),
+        qos: qos as u8,
+        retain: command.delivery.retain,
+        sent_at: Local::now(),
+        status: CommandStatus::Pending.as_str().to_string(),
+        message: None,
+        finished_at: None,
+    });
+    // 收到服务器确认（PUBACK/PUBCOMP）后才算发送成功
+    if let Err(e) = pool
+        .send(&command.topic, &text, qos, command.delivery.retain)
+        .await
+    {
+        if queued && !pool.is_connected() {
+            mark_requeued(&id);
+            discard_command(&id);
+        } else {
+            mark_send_failed(&id, e.to_string());
+        }
+        return Err(e);
+    }
+    Ok(id)
+}
+
+// 命令记录中的发送者：系统用户名@客户端ID（未指定客户端ID时为前缀）
+fn command_sender(profile: &ConnectionProfile) -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let client = if profile.client_id.is_empty() {
+        &profile.client_id_prefix
+    } else {
+        &profile.client_id
+    };
+    format!("{}@{}", user, client)
+}
+
+/// 命令的发送结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dispatch {
+    // 已发送，等待节点回复
+    Sent(String),
+    // 未连接，已进入发送队列
+    Queued(String),
+}
+
+impl Dispatch {
+    /// 本次发送的关联ID，排队的命令发出时使用同一个ID
+    pub fn id(self) -> String {
+        match self {
+            Dispatch::Sent(id) | Dispatch::Queued(id) => id,
+        }
+    }
+}
+
+/// 发送命令，未连接到服务器时放入发送队列，连接恢复后按顺序发送
+///
+/// # 参数
+/// * `command` - 要发送的命令
+///
+/// # 返回值
+/// 返回发送结果和关联ID；队列已满或未启用队列时返回错误
+pub async fn send_or_queue_command(command: &StoredCommand) -> Result<Dispatch, Error> {
+    let pool = current_pool().filter(|pool| pool.is_connected());
+    // 队列中还有较早的命令时排在它们后面，保证发送顺序
+    let backlog = !OUTBOX.lock().map_err(|_| format_err!("读取发送队列失败"))?.is_empty();
+    if let Some(pool) = &pool
+        && !backlog
+    {
+        return publish_command(pool, command, new_correlation_id(), false)
+            .await
+            .map(Dispatch::Sent);
+    }
+    let (capacity, ttl) = ACTIVE_PROFILE
+        .read()
+        .map_err(|_| format_err!("读取连接配置失败"))?
+        .as_ref()
+        .map(|p| (p.outbox_capacity, p.outbox_ttl_secs))
+        .unwrap_or_default();
+    if capacity == 0 {
+        return Err(format_err!("尚未连接到服务器"));
+    }
+    let ttl = (ttl > 0).then(|| Duration::from_secs(ttl));
+    let id = new_correlation_id();
+    OUTBOX
+        .lock()
+        .map_err(|_| format_err!("读取发送队列失败"))?
+        .push(&id, command.clone(), capacity, ttl, unix_now())?;
+    mark_queued(&id, &command.name);
+    if let Some(pool) = pool {
+        tokio::spawn(async move { flush_outbox(&pool).await });
+    }
+    Ok(Dispatch::Queued(id))
+}
+
+/// 取消命令所有排队中的发送
+///
+/// # 返回值
+/// 被取消的条数
+pub fn cancel_queued_command(command_name: &str) -> Result<usize, Error> {
+    let cancelled = OUTBOX
+        .lock()
+        .map_err(|_| format_err!("读取发送队列失败"))?
+        .cancel(command_name)?;
+    for id in &cancelled {
+        mark_cancelled(id);
+    }
+    Ok(cancelled.len())
+}
+
+/// 按入队顺序发送排队中的命令，已过期的不再发送
+///
+/// 连接中断导致发送失败时保留该命令并停止，等下次连接后继续
+pub async fn flush_outbox(pool: &MqttConnectionPool) {
+    // 同一时间只有一个任务按顺序发送队列
+    static FLUSHING: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    let _flushing = FLUSHING.lock().await;
+    loop {
+        let front = OUTBOX.lock().ok().and_then(|outbox| outbox.front().cloned());
+        let Some(queued) = front else {
+            return;
+        };
+        if queued.is_expired(unix_now()) {
+            mark_expired(&queued.id);
+        } else if let Err(e) = publish_command(pool, &queued.command, queued.id.clone(), true).await {
+            eprintln!("排队命令 {} 发送失败: {}", queued.command.name, e);
+            if !pool.is_connected() {
+                // 等待下次连接后重发，状态仍显示为排队中
+                return;
+            }
+        }
+        // 发送期间可能已被取消，按ID移除
+        if let Ok(mut outbox) = OUTBOX.lock()
+            && let Err(e) = outbox.remove(&queued.id)
+        {
+            eprintln!("发送队列保存失败: {}", e);
+        }
+    }
+}
+
+// 每次连接状态变化时检查连接池，已连接则发送排队中的命令
+async fn flush_outbox_on_connect() {
+    let event = CONNECTION_STATUS_EVENT.clone();
+    loop {
+        let listener = event.listen();
+        if let Some(pool) = current_pool().filter(|pool| pool.is_connected()) {
+            flush_outbox(&pool).await;
+        }
+        listener.await;
+    }
+}
+
+/// 断开当前连接：中止事件循环任务，清空接收器和连接池
+pub async fn shutdown_mqtt() {
+    if let Some(task) = MQTT_TASK.lock().await.take() {
+        task.abort();
+        let _ = task.await;
+    }
+    TOPIC2RECEIVER.lock().await.clear();
+    if let Ok(mut pool) = POOL.write() {
+        *pool = None;
+    }
+    clear_status();
+    close_store();
+    NODES.lock().await.clear();
+    UPDATE_STATE_EVENT.clone().notify(usize::MAX);
+}
+
+/// 切换到指定的连接配置，会先断开旧连接再重新建立连接池和接收器
+pub async fn switch_profile(profile: ConnectionProfile) {
+    shutdown_mqtt().await;
+    if let Ok(mut active) = ACTIVE_PROFILE.write() {
+        *active = Some(profile.clone());
+    }
+    let task = tokio::spawn(async move {
+        if let Err(e) = init_mqtt(profile).await {
+            eprintln!("连接失败: {:?}", e);
+            report_error(&e.to_string());
+        }
+    });
+    *MQTT_TASK.lock().await = Some(task);
+}
+/// 测试用：以内存回环连接作为当前连接池，并使用默认连接配置
+///
+/// 连接池是全局的，返回的锁释放前其它测试不能替换连接池
+#[cfg(test)]
+pub(crate) async fn use_loopback_pool(
+    broker: &crate::mqtt::transport::LoopbackBroker,
+) -> tokio::sync::MutexGuard<'static, ()> {
+    use crate::mqtt::pool::MqttConnection;
+
+    static LOOPBACK_POOL_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    let guard = LOOPBACK_POOL_LOCK.lock().await;
+    let pool = Arc::new(
+        MqttConnectionPool::from_connections(vec![MqttConnection::with_transport(
+            broker.connect("pool_0"),
+            "pool_0",
+        )])
+        .unwrap(),
+    );
+    *POOL.write().unwrap() = Some(pool.clone());
+    *ACTIVE_PROFILE.write().unwrap() = Some(ConnectionProfile::default());
+    for _ in 0..50 {
+        if pool.is_connected() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    guard
+}
+
+#[tokio::test]
+async fn test_same_command_sent_concurrently() {
+    use crate::command_store::Delivery;
+    use crate::command_tracker::{COMMAND_STATUS, wait_command};
+    use crate::mqtt::transport::LoopbackBroker;
+
+    let broker = LoopbackBroker::new();
+    let _pool = use_loopback_pool(&broker).await;
+    let command = StoredCommand {
+        name: "test_concurrent".to_string(),
+        topic: "test/concurrent".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    let (first, second) = tokio::join!(send_command(&command), send_command(&command));
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_ne!(first, second);
+    assert_eq!(broker.published().len(), 2);
+    // 两次发送各自得到回复，互不覆盖
+    handle_ack(&format!(r#"{{"id": "{}", "status": "failed"}}"#, second));
+    handle_ack(&format!(r#"{{"id": "{}", "status": "ok"}}"#, first));
+    assert_eq!(wait_command(&first).await.0, CommandStatus::Acked);
+    assert_eq!(wait_command(&second).await.0, CommandStatus::Failed);
+    let status = COMMAND_STATUS.lock().unwrap();
+    let latest = status.latest_id("test_concurrent").unwrap();
+    assert!(latest == first || latest == second);
+    assert_eq!(status.latest("test_concurrent"), status.get(latest));
+}
+
 #[tokio::test]
-async fn test_mqtt_broker() {
-    init_client_pool("127.0.0.1:1883".to_string(), 10)
+async fn test_state_receiver_over_loopback() {
+    use crate::mqtt::pool::{MqttConnection, MqttConnectionPool};
+    use crate::mqtt::transport::LoopbackBroker;
+    use std::time::Duration;
+
+    let broker = LoopbackBroker::new();
+    let receiver = Receiver::with_transport(broker.connect("state"), "state", "test/+/state");
+    register_state_receiver(receiver, "test/+/state").await.unwrap();
+    let receiver = take_receiver("test/+/state").await.unwrap();
+    tokio::spawn(async move { receiver.lock().await.start().await });
+    // 等待接收器处理 Connected 事件并完成订阅
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let pool = MqttConnectionPool::from_connections(vec![
+        MqttConnection::with_transport(broker.connect("pool_0"), "pool_0"),
+        MqttConnection::with_transport(broker.connect("pool_1"), "pool_1"),
+    ])
+    .unwrap();
+    let register = r#"{"id": "loop-1", "position_type": 1, "position": [1.0], "state": {"mode": "auto"}}"#;
+    pool.send("test/loop-1/state", register, QoS::AtLeastOnce, false)
         .await
         .unwrap();
-    add_state_receiver("127.0.0.1:1883", "test/1")
+    // 消息中没有 id 时从主题中取节点ID
+    let change = r#"{"state_change": {"mode": "manual"}}"#;
+    pool.send("test/loop-1/state", change, QoS::AtLeastOnce, false)
         .await
         .unwrap();
-    let top2r = TOPIC2RECEIVER.lock().await;
-    let receiver = top2r.get("test/1").unwrap();
-    let mut receiver = receiver.lock().await;
-    receiver.add_callback(|msg| {
-        println!("{:?}", msg);
-    });
-    receiver.start().await;
+    // 连接池轮流使用各个连接
+    let publishers: Vec<String> = broker.published().into_iter().map(|(id, _)| id).collect();
+    assert_eq!(publishers, ["pool_0", "pool_1"]);
+
+    let mut state = None;
+    for _ in 0..50 {
+        state = NODES
+            .lock()
+            .await
+            .get("loop-1")
+            .and_then(|node| node.state.get("mode").cloned());
+        if state.as_deref() == Some("manual") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(state.as_deref(), Some("manual"));
 }
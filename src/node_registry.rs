@@ -0,0 +1,146 @@
+use crate::node_state_entity::{NodeStateChangeString, NodeStateRegister};
+use crate::state_history::StateHistory;
+use anyhow::{Error, format_err};
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+
+// 已注册节点
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    pub id: String,
+    pub position_type: i64,
+    pub position: Vec<f64>,
+    pub state: BTreeMap<String, String>,
+    pub registered_at: DateTime<Local>,
+    pub updated_at: DateTime<Local>,
+}
+
+impl NodeInfo {
+    /// 位置的显示文本，例如 "10.5, 20.3, 5"
+    pub fn position_text(&self) -> String {
+        self.position
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// 节点注册表，按节点ID区分各节点的状态
+#[derive(Default)]
+pub struct NodeRegistry {
+    nodes: BTreeMap<String, NodeInfo>,
+    // 每次注册和状态变化的取值
+    history: StateHistory,
+}
+
+impl NodeRegistry {
+    /// 注册节点，同ID节点重复注册时覆盖其位置和状态
+    pub fn register(&mut self, register: NodeStateRegister) -> &NodeInfo {
+        let now = Local::now();
+        let node = NodeInfo {
+            id: register.id.clone(),
+            position_type: register.position_type,
+            position: register.position,
+            state: register.state.into_iter().collect(),
+            registered_at: now,
+            updated_at: now,
+        };
+        for (k, v) in &node.state {
+            self.history.record(&node.id, k, v, now);
+        }
+        self.nodes.insert(register.id.clone(), node);
+        &self.nodes[&register.id]
+    }
+
+    /// 将状态变更应用到对应节点，只更新节点已注册的状态项
+    ///
+    /// 消息未携带 `id` 时，若只注册了一个节点则应用到该节点（兼容旧格式）
+    ///
+    /// # 返回值
+    /// 返回被更新的节点ID
+    pub fn apply_change(&mut self, change: NodeStateChangeString) -> Result<String, Error> {
+        let node = match &change.id {
+            Some(id) => self
+                .nodes
+                .get_mut(id)
+                .ok_or_else(|| format_err!("节点 {} 未注册", id))?,
+            None if self.nodes.len() == 1 => self.nodes.values_mut().next().unwrap(),
+            None => return Err(format_err!("状态变更缺少节点 id")),
+        };
+        let now = Local::now();
+        // 注册时没有的状态项忽略
+        for (k, v) in change.state_change {
+            if let Some(ori) = node.state.get_mut(&k) {
+                self.history.record(&node.id, &k, &v, now);
+                *ori = v;
+            }
+        }
+        node.updated_at = now;
+        Ok(node.id.clone())
+    }
+
+    /// 加入已保存的节点，例如从本地数据库恢复
+    pub fn insert(&mut self, node: NodeInfo) {
+        self.nodes.insert(node.id.clone(), node);
+    }
+
+    pub fn get(&self, id: &str) -> Option<&NodeInfo> {
+        self.nodes.get(id)
+    }
+
+    /// 按ID排序的全部节点
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.nodes.values()
+    }
+
+    /// 各节点状态的历史取值
+    pub fn history(&self) -> &StateHistory {
+        &self.history
+    }
+
+    pub fn history_mut(&mut self) -> &mut StateHistory {
+        &mut self.history
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.history.clear();
+    }
+}
+
+#[test]
+fn test_node_registry_routes_changes_by_id() {
+    let register = |id: &str| -> NodeStateRegister {
+        serde_json::from_str(&format!(
+            r#"{{"id": "{}", "position_type": 2, "position": [1.0, 2.0], "state": {{"status": "idle"}}}}"#,
+            id
+        ))
+        .unwrap()
+    };
+    let change = |json: &str| -> NodeStateChangeString { serde_json::from_str(json).unwrap() };
+
+    let mut registry = NodeRegistry::default();
+    registry.register(register("node-1"));
+    // 只有一个节点时兼容不带 id 的旧格式
+    registry
+        .apply_change(change(r#"{"state_change": {"status": "active"}}"#))
+        .unwrap();
+    registry.register(register("node-2"));
+    assert!(registry
+        .apply_change(change(r#"{"state_change": {"status": "x"}}"#))
+        .is_err());
+    registry
+        .apply_change(change(r#"{"id": "node-2", "state_change": {"status": "ready"}}"#))
+        .unwrap();
+
+    assert_eq!(registry.get("node-1").unwrap().state["status"], "active");
+    assert_eq!(registry.get("node-2").unwrap().state["status"], "ready");
+    assert_eq!(registry.get("node-1").unwrap().position_text(), "1, 2");
+    let history: Vec<&str> = registry
+        .history()
+        .points("node-1", "status")
+        .map(|p| p.value.as_str())
+        .collect();
+    assert_eq!(history, ["idle", "active"]);
+}
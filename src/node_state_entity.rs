@@ -1,5 +1,6 @@
 use bon::Builder;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 
 // dto
@@ -13,6 +14,9 @@ pub struct NodeStateChangeFloat {
 
 #[derive(Deserialize)]
 pub struct NodeStateChangeString {
+    // 目标节点ID，旧格式可以省略
+    #[serde(default)]
+    pub id: Option<String>,
     pub state_change: HashMap<String, String>,
 }
 
@@ -27,6 +31,21 @@ pub struct NodeStateRegister {
 #[derive(Builder, Serialize)]
 pub struct NodeCommand {
     // server -> node
+    // 关联ID，节点回复时原样带回
+    pub id: String,
+    // 节点回复的主题
+    pub reply_to: String,
     pub op: String,
-    pub op_value: HashMap<String, f64>,
+    // 参数值按声明的类型序列化：数字、字符串、布尔或嵌套对象
+    pub op_value: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+pub struct CommandAck {
+    // node -> server，id 与 NodeCommand::id 一致
+    pub id: String,
+    // "ok"/"acked" 表示执行成功，"failed"/"error" 表示执行失败
+    pub status: String,
+    #[serde(default)]
+    pub message: Option<String>,
 }
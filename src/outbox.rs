@@ -0,0 +1,257 @@
+use crate::app_dir::app_config_dir;
+use crate::command_store::StoredCommand;
+use crate::command_tracker::mark_queued;
+use anyhow::{Error, format_err};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 当前发送队列文件的结构版本
+pub const OUTBOX_VERSION: u32 = 1;
+
+const OUTBOX_FILE_NAME: &str = "outbox.json";
+
+// 未连接时排队等待发送的命令
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct QueuedCommand {
+    // 发送时使用的关联ID
+    pub id: String,
+    pub command: StoredCommand,
+    // 入队时间（Unix 时间戳，秒）
+    pub queued_at: i64,
+    // 过期时间（Unix 时间戳，秒），None 表示不过期
+    pub expires_at: Option<i64>,
+}
+
+impl QueuedCommand {
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|t| now >= t)
+    }
+}
+
+// 发送队列文件
+#[derive(Serialize, Deserialize)]
+struct OutboxFile {
+    version: u32,
+    commands: Vec<QueuedCommand>,
+}
+
+/// 发送队列，按入队顺序保存，每次修改后立即写入文件，程序重启后继续发送
+pub struct Outbox {
+    path: PathBuf,
+    commands: VecDeque<QueuedCommand>,
+}
+
+/// 默认位置的发送队列，读取失败时使用空队列
+pub static OUTBOX: Lazy<Mutex<Outbox>> = Lazy::new(|| {
+    let path = app_config_dir().join(OUTBOX_FILE_NAME);
+    Mutex::new(Outbox::load_from(&path).unwrap_or_else(|e| {
+        eprintln!("发送队列读取失败: {}", e);
+        Outbox {
+            path,
+            commands: VecDeque::new(),
+        }
+    }))
+});
+
+/// 当前 Unix 时间戳（秒）
+pub fn unix_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+impl Outbox {
+    /// 从指定文件加载发送队列，文件不存在时为空队列
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        let commands = match fs::read_to_string(path) {
+            Ok(text) => {
+                let file: OutboxFile = serde_json::from_str(&text)
+                    .map_err(|e| format_err!("发送队列文件损坏: {}", e))?;
+                if file.version != OUTBOX_VERSION {
+                    return Err(format_err!("不支持的发送队列版本: {}", file.version));
+                }
+                file.commands.into()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Outbox {
+            path: path.to_path_buf(),
+            commands,
+        })
+    }
+
+    // 先写临时文件再改名，与命令库相同
+    fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OutboxFile {
+            version: OUTBOX_VERSION,
+            commands: self.commands.iter().cloned().collect(),
+        };
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&file)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// 命令入队
+    ///
+    /// # 参数
+    /// * `id` - 发送时使用的关联ID
+    /// * `command` - 要发送的命令
+    /// * `capacity` - 队列最多保存的命令数，已满时返回错误
+    /// * `ttl` - 排队的有效期，None 表示不过期
+    /// * `now` - 当前时间戳
+    pub fn push(
+        &mut self,
+        id: &str,
+        command: StoredCommand,
+        capacity: usize,
+        ttl: Option<Duration>,
+        now: i64,
+    ) -> Result<(), Error> {
+        if self.commands.len() >= capacity {
+            return Err(format_err!("发送队列已满（{} 条），命令 {} 未排队", capacity, command.name));
+        }
+        self.commands.push_back(QueuedCommand {
+            id: id.to_string(),
+            command,
+            queued_at: now,
+            expires_at: ttl.map(|ttl| now + ttl.as_secs() as i64),
+        });
+        // 写入失败时不留在内存中，否则调用方报告失败后它仍会被发出去
+        if let Err(e) = self.save() {
+            self.commands.pop_back();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// 最早入队的命令
+    pub fn front(&self) -> Option<&QueuedCommand> {
+        self.commands.front()
+    }
+
+    /// 按关联ID移出命令
+    pub fn remove(&mut self, id: &str) -> Result<Option<QueuedCommand>, Error> {
+        let Some(index) = self.commands.iter().position(|c| c.id == id) else {
+            return Ok(None);
+        };
+        // 已发出的命令即使写入失败也从内存中移出，避免同一次运行中重复发送
+        let removed = self.commands.remove(index);
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// 取消某个命令所有排队中的发送
+    ///
+    /// # 返回值
+    /// 被取消的发送的关联ID
+    pub fn cancel(&mut self, command_name: &str) -> Result<Vec<String>, Error> {
+        let cancelled: Vec<String> = self
+            .commands
+            .iter()
+            .filter(|c| c.command.name == command_name)
+            .map(|c| c.id.clone())
+            .collect();
+        if !cancelled.is_empty() {
+            let before = self.commands.clone();
+            self.commands.retain(|c| c.command.name != command_name);
+            if let Err(e) = self.save() {
+                self.commands = before;
+                return Err(e);
+            }
+        }
+        Ok(cancelled)
+    }
+
+    pub fn commands(&self) -> impl Iterator<Item = &QueuedCommand> {
+        self.commands.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
+
+/// 把上次退出时仍在排队的命令显示为排队中
+pub fn restore_queued_status() {
+    let outbox = OUTBOX.lock().unwrap();
+    for queued in outbox.commands() {
+        mark_queued(&queued.id, &queued.command.name);
+    }
+}
+
+#[test]
+fn test_outbox_bounded_persistent_queue() {
+    use crate::command_store::Delivery;
+
+    let dir = std::env::temp_dir().join(format!("slint_rmqtt_outbox_{}", std::process::id()));
+    let path = dir.join(OUTBOX_FILE_NAME);
+    let command = |name: &str| StoredCommand {
+        name: name.to_string(),
+        topic: "node/1".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    let mut outbox = Outbox::load_from(&path).unwrap();
+    assert!(outbox.is_empty());
+    outbox.push("1", command("fan"), 3, None, 100).unwrap();
+    outbox.push("2", command("pump"), 3, Some(Duration::from_secs(60)), 100).unwrap();
+    outbox.push("3", command("fan"), 3, None, 110).unwrap();
+    assert!(outbox.push("4", command("fan"), 3, None, 120).is_err());
+
+    // 重新加载后顺序不变
+    let mut outbox = Outbox::load_from(&path).unwrap();
+    let ids: Vec<&str> = outbox.commands().map(|c| c.id.as_str()).collect();
+    assert_eq!(ids, ["1", "2", "3"]);
+    assert!(!outbox.commands[1].is_expired(159));
+    assert!(outbox.commands[1].is_expired(160));
+
+    assert_eq!(outbox.cancel("fan").unwrap(), ["1", "3"]);
+    assert_eq!(outbox.front().map(|c| c.id.as_str()), Some("2"));
+    assert_eq!(outbox.remove("2").unwrap().map(|c| c.command.name), Some("pump".to_string()));
+    assert!(Outbox::load_from(&path).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_outbox_unwritable_keeps_memory_unchanged() {
+    use crate::command_store::Delivery;
+
+    // 父目录是一个普通文件，队列文件无法写入
+    let blocker = std::env::temp_dir().join(format!("slint_rmqtt_outbox_blocker_{}", std::process::id()));
+    fs::write(&blocker, "").unwrap();
+    let mut outbox = Outbox {
+        path: blocker.join(OUTBOX_FILE_NAME),
+        commands: VecDeque::new(),
+    };
+    let command = StoredCommand {
+        name: "fan".to_string(),
+        topic: "node/1".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    assert!(outbox.push("1", command.clone(), 3, None, 100).is_err());
+    assert!(outbox.is_empty());
+
+    // 取消时写入失败，命令仍在队列中
+    outbox.commands.push_back(QueuedCommand {
+        id: "2".to_string(),
+        command,
+        queued_at: 100,
+        expires_at: None,
+    });
+    assert!(outbox.cancel("fan").is_err());
+    assert_eq!(outbox.len(), 1);
+    fs::remove_file(&blocker).unwrap();
+}
@@ -0,0 +1,424 @@
+use crate::app_dir::app_config_dir;
+use crate::broker::BrokerConfigure;
+use crate::mqtt::connect::{MQTTConfig, parse_url};
+use crate::mqtt::tls::TlsSettings;
+use crate::mqtt::topic::validate_filter;
+use crate::mqtt::transport::parse_qos;
+use crate::state_history::HistoryRetention;
+use anyhow::{Error, format_err};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 当前连接配置文件的结构版本
+pub const PROFILE_STORE_VERSION: u32 = 1;
+
+const PROFILE_FILE_NAME: &str = "profiles.json";
+
+// 连接配置档
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ConnectionProfile {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    pub client_id_prefix: String,
+    // 指定的客户端ID，为空时由前缀和安装ID自动生成
+    pub client_id: String,
+    pub keep_alive_secs: u64,
+    pub state_topic: String,
+    pub pool_size: usize,
+    pub tls: TlsSettings,
+    // 节点回复命令的主题
+    pub reply_topic: String,
+    // 订阅状态主题和回复主题的 QoS（0、1、2）
+    pub state_qos: u8,
+    pub reply_qos: u8,
+    // 等待命令回复的超时时间（秒）
+    pub ack_timeout_secs: u64,
+    // 未连接时最多排队的命令数，0 表示不排队
+    pub outbox_capacity: usize,
+    // 命令排队的有效期（秒），0 表示不过期
+    pub outbox_ttl_secs: u64,
+    // 状态历史的保留时间（秒）
+    pub history_retention_secs: u64,
+    // 每个状态项最多保留的历史条数
+    pub history_max_points: usize,
+    // 把节点、状态历史和命令记录保存到本地 SQLite 数据库（需要编译时启用 sqlite 功能）
+    pub local_store: bool,
+    // 连接前先启动内置服务器
+    pub embedded_broker: bool,
+    // 内置服务器的监听配置
+    pub broker_listeners: Vec<BrokerConfigure>,
+}
+
+impl Default for ConnectionProfile {
+    fn default() -> Self {
+        ConnectionProfile {
+            name: "default".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            username: String::new(),
+            password: String::new(),
+            client_id_prefix: "dt".to_string(),
+            client_id: String::new(),
+            keep_alive_secs: 2,
+            state_topic: "server/0".to_string(),
+            pool_size: 10,
+            tls: TlsSettings::default(),
+            reply_topic: "server/0/reply".to_string(),
+            state_qos: 1,
+            reply_qos: 1,
+            ack_timeout_secs: 10,
+            outbox_capacity: 100,
+            outbox_ttl_secs: 0,
+            history_retention_secs: 3600,
+            history_max_points: 2000,
+            local_store: false,
+            embedded_broker: false,
+            broker_listeners: BrokerConfigure::defaults(),
+        }
+    }
+}
+
+impl ConnectionProfile {
+    /// 服务器地址；`host` 本身带协议（如 `wss://...`）时原样使用，端口字段不生效
+    pub fn server_url(&self) -> String {
+        if self.host.contains("://") {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    /// 转换为连接层使用的配置
+    pub fn mqtt_config(&self) -> MQTTConfig {
+        MQTTConfig {
+            host: self.server_url(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            client_id_prefix: self.client_id_prefix.clone(),
+            client_id: self.client_id.clone(),
+            keep_alive_secs: self.keep_alive_secs,
+            tls: self.tls.clone(),
+        }
+    }
+
+    /// 状态历史的保留策略
+    pub fn history_retention(&self) -> HistoryRetention {
+        HistoryRetention {
+            max_age: Duration::from_secs(self.history_retention_secs),
+            max_points: self.history_max_points,
+        }
+    }
+
+    /// 检查配置是否可用于建立连接
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(format_err!("配置名称不能为空"));
+        }
+        if self.host.trim().is_empty() {
+            return Err(format_err!("服务器地址不能为空"));
+        }
+        parse_url(&self.server_url())?;
+        if self.state_topic.trim().is_empty() {
+            return Err(format_err!("状态主题不能为空"));
+        }
+        validate_filter(&self.state_topic)?;
+        if self.reply_topic.trim().is_empty() {
+            return Err(format_err!("回复主题不能为空"));
+        }
+        // 回复主题会作为 reply_to 发给节点，不能带通配符
+        if self.reply_topic.contains(['+', '#']) {
+            return Err(format_err!("回复主题不能包含 + 或 #"));
+        }
+        if self.reply_topic == self.state_topic {
+            return Err(format_err!("回复主题不能与状态主题相同"));
+        }
+        parse_qos(self.state_qos)?;
+        parse_qos(self.reply_qos)?;
+        // 客户端ID会拼接到各连接的ID中，只允许常见字符
+        let valid_id = |id: &str| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if self.client_id_prefix.trim().is_empty() || !valid_id(&self.client_id_prefix) {
+            return Err(format_err!("客户端ID前缀只能包含字母、数字、_ 和 -"));
+        }
+        if !valid_id(&self.client_id) {
+            return Err(format_err!("客户端ID只能包含字母、数字、_ 和 -"));
+        }
+        if self.history_retention_secs == 0 || self.history_max_points == 0 {
+            return Err(format_err!("状态历史的保留时间和条数必须大于0"));
+        }
+        if self.pool_size == 0 {
+            return Err(format_err!("连接池大小必须大于0"));
+        }
+        if self.tls.client_cert_file.is_some() != self.tls.client_key_file.is_some() {
+            return Err(format_err!("客户端证书和私钥需要同时配置"));
+        }
+        if self.embedded_broker && self.broker_listeners.is_empty() {
+            return Err(format_err!("内置服务器至少需要一个监听配置"));
+        }
+        Ok(())
+    }
+}
+
+// 连接配置文件
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProfileStore {
+    pub version: u32,
+    pub active: String,
+    pub profiles: Vec<ConnectionProfile>,
+}
+
+impl Default for ProfileStore {
+    fn default() -> Self {
+        ProfileStore {
+            version: PROFILE_STORE_VERSION,
+            active: "default".to_string(),
+            profiles: vec![ConnectionProfile::default()],
+        }
+    }
+}
+
+impl ProfileStore {
+    /// 按名称查找配置
+    pub fn get(&self, name: &str) -> Option<&ConnectionProfile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// 当前选中的配置，找不到时退回第一个配置或默认配置
+    pub fn active_profile(&self) -> ConnectionProfile {
+        self.get(&self.active)
+            .or_else(|| self.profiles.first())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// 新增或替换同名配置
+    pub fn upsert(&mut self, profile: ConnectionProfile) {
+        match self.profiles.iter_mut().find(|p| p.name == profile.name) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    /// 所有配置名称
+    pub fn names(&self) -> Vec<String> {
+        self.profiles.iter().map(|p| p.name.clone()).collect()
+    }
+}
+
+/// 默认的连接配置文件路径
+pub fn profiles_path() -> PathBuf {
+    app_config_dir().join(PROFILE_FILE_NAME)
+}
+
+/// 从指定文件加载连接配置，文件不存在时返回默认配置
+pub fn load_profiles_from(path: &Path) -> Result<ProfileStore, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ProfileStore::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let store: ProfileStore = serde_json::from_str(&text)
+        .map_err(|e| format_err!("连接配置文件 {} 格式错误: {}", path.display(), e))?;
+    if store.version != PROFILE_STORE_VERSION {
+        return Err(format_err!("不支持的连接配置版本: {}", store.version));
+    }
+    Ok(store)
+}
+
+/// 保存连接配置到指定文件
+pub fn save_profiles_to(path: &Path, store: &ProfileStore) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(store)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// 命令行连接参数，会覆盖配置文件中选中配置的对应字段
+#[derive(Args, Debug, Default, Clone)]
+pub struct ProfileArgs {
+    /// 连接配置文件路径
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// 使用的配置名称
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// 服务器地址，可以是 host 或 mqtt://、mqtts://、ws://、wss:// 开头的URL
+    #[arg(long)]
+    pub host: Option<String>,
+    /// 服务器端口
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// 用户名
+    #[arg(long)]
+    pub username: Option<String>,
+    /// 密码
+    #[arg(long)]
+    pub password: Option<String>,
+    /// 客户端ID前缀
+    #[arg(long)]
+    pub client_id_prefix: Option<String>,
+    /// 指定客户端ID（各连接在其后加上用途），不指定时由前缀和安装ID生成
+    #[arg(long)]
+    pub client_id: Option<String>,
+    /// 心跳间隔（秒）
+    #[arg(long)]
+    pub keep_alive: Option<u64>,
+    /// 状态主题
+    #[arg(long)]
+    pub state_topic: Option<String>,
+    /// 连接池大小
+    #[arg(long)]
+    pub pool_size: Option<usize>,
+    /// 命令回复主题
+    #[arg(long)]
+    pub reply_topic: Option<String>,
+    /// 订阅状态主题的 QoS（0、1、2）
+    #[arg(long)]
+    pub state_qos: Option<u8>,
+    /// 订阅回复主题的 QoS（0、1、2）
+    #[arg(long)]
+    pub reply_qos: Option<u8>,
+    /// 等待命令回复的超时时间（秒）
+    #[arg(long)]
+    pub ack_timeout: Option<u64>,
+    /// 使用TLS连接
+    #[arg(long)]
+    pub tls: bool,
+    /// CA证书文件(PEM)
+    #[arg(long)]
+    pub ca_file: Option<PathBuf>,
+    /// 客户端证书文件(PEM)
+    #[arg(long)]
+    pub client_cert: Option<PathBuf>,
+    /// 客户端私钥文件(PEM)
+    #[arg(long)]
+    pub client_key: Option<PathBuf>,
+    /// 跳过服务器证书校验（仅用于实验环境）
+    #[arg(long)]
+    pub insecure_skip_verify: bool,
+    /// ALPN协议，可多次指定
+    #[arg(long)]
+    pub alpn: Vec<String>,
+    /// 启动内置MQTT服务器（需要编译时启用 broker 功能）
+    #[arg(long)]
+    pub embedded_broker: bool,
+    /// 使用本地 SQLite 数据库保存命令记录（需要编译时启用 sqlite 功能）
+    #[arg(long)]
+    pub local_store: bool,
+}
+
+impl ProfileArgs {
+    /// 配置文件路径，未指定时使用默认路径
+    pub fn config_path(&self) -> PathBuf {
+        self.config.clone().unwrap_or_else(profiles_path)
+    }
+
+    /// 在配置文件的基础上应用命令行参数，得到最终使用的配置
+    pub fn resolve(&self, store: &ProfileStore) -> Result<ConnectionProfile, Error> {
+        let mut profile = match &self.profile {
+            Some(name) => store
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format_err!("找不到连接配置: {}", name))?,
+            None => store.active_profile(),
+        };
+        if let Some(host) = &self.host {
+            profile.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            profile.port = port;
+        }
+        if let Some(username) = &self.username {
+            profile.username = username.clone();
+        }
+        if let Some(password) = &self.password {
+            profile.password = password.clone();
+        }
+        if let Some(prefix) = &self.client_id_prefix {
+            profile.client_id_prefix = prefix.clone();
+        }
+        if let Some(client_id) = &self.client_id {
+            profile.client_id = client_id.clone();
+        }
+        if let Some(keep_alive) = self.keep_alive {
+            profile.keep_alive_secs = keep_alive;
+        }
+        if let Some(topic) = &self.state_topic {
+            profile.state_topic = topic.clone();
+        }
+        if let Some(size) = self.pool_size {
+            profile.pool_size = size;
+        }
+        if let Some(topic) = &self.reply_topic {
+            profile.reply_topic = topic.clone();
+        }
+        if let Some(qos) = self.state_qos {
+            profile.state_qos = qos;
+        }
+        if let Some(qos) = self.reply_qos {
+            profile.reply_qos = qos;
+        }
+        if let Some(timeout) = self.ack_timeout {
+            profile.ack_timeout_secs = timeout;
+        }
+        if self.tls {
+            profile.tls.enabled = true;
+        }
+        if let Some(ca) = &self.ca_file {
+            profile.tls.ca_file = Some(ca.clone());
+        }
+        if let Some(cert) = &self.client_cert {
+            profile.tls.client_cert_file = Some(cert.clone());
+        }
+        if let Some(key) = &self.client_key {
+            profile.tls.client_key_file = Some(key.clone());
+        }
+        if self.insecure_skip_verify {
+            profile.tls.insecure_skip_verify = true;
+        }
+        if !self.alpn.is_empty() {
+            profile.tls.alpn = self.alpn.clone();
+        }
+        if self.embedded_broker {
+            profile.embedded_broker = true;
+        }
+        if self.local_store {
+            profile.local_store = true;
+        }
+        profile.validate()?;
+        Ok(profile)
+    }
+}
+
+#[test]
+fn test_profile_args_override() {
+    let mut store = ProfileStore::default();
+    store.upsert(ConnectionProfile {
+        name: "site".to_string(),
+        host: "10.0.0.2".to_string(),
+        ..ConnectionProfile::default()
+    });
+    let args = ProfileArgs {
+        profile: Some("site".to_string()),
+        port: Some(8883),
+        ..ProfileArgs::default()
+    };
+    let profile = args.resolve(&store).unwrap();
+    assert_eq!(profile.server_url(), "10.0.0.2:8883");
+    assert_eq!(profile.state_topic, "server/0");
+
+    let missing = ProfileArgs {
+        profile: Some("missing".to_string()),
+        ..ProfileArgs::default()
+    };
+    assert!(missing.resolve(&store).is_err());
+}
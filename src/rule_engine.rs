@@ -0,0 +1,452 @@
+use crate::command_store::{load_rules, save_rules};
+use crate::node_registry::NodeInfo;
+use anyhow::{Error, format_err};
+use chrono::{DateTime, Local};
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// 规则触发记录保存的条数，超过后丢弃最早的
+pub const RULE_ACTIVITY_CAPACITY: usize = 200;
+
+// 条件的比较方式
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompareOp {
+    #[default]
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    // 状态取值包含条件中的文字
+    #[serde(rename = "contains")]
+    Contains,
+}
+
+impl CompareOp {
+    /// 全部比较方式，顺序与规则窗口中的选项一致
+    pub const ALL: [CompareOp; 7] = [
+        CompareOp::Eq,
+        CompareOp::Ne,
+        CompareOp::Gt,
+        CompareOp::Ge,
+        CompareOp::Lt,
+        CompareOp::Le,
+        CompareOp::Contains,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Contains => "contains",
+        }
+    }
+
+    /// 比较状态取值和条件中的值，两边都是数字时按数值比较，否则按文字比较
+    ///
+    /// 文字只支持 `==`、`!=` 和 `contains`，其余比较方式不满足
+    pub fn compare(&self, actual: &str, expected: &str) -> bool {
+        let numbers = actual
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .zip(expected.trim().parse::<f64>().ok());
+        match (self, numbers) {
+            (CompareOp::Contains, _) => actual.contains(expected),
+            (CompareOp::Eq, Some((a, b))) => a == b,
+            (CompareOp::Ne, Some((a, b))) => a != b,
+            (CompareOp::Gt, Some((a, b))) => a > b,
+            (CompareOp::Ge, Some((a, b))) => a >= b,
+            (CompareOp::Lt, Some((a, b))) => a < b,
+            (CompareOp::Le, Some((a, b))) => a <= b,
+            (CompareOp::Eq, None) => actual == expected,
+            (CompareOp::Ne, None) => actual != expected,
+            _ => false,
+        }
+    }
+}
+
+// 自动执行规则：节点状态满足条件时发送命令库中的命令
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Rule {
+    pub name: String,
+    pub enabled: bool,
+    // 节点ID，为空时对所有节点生效
+    pub node: String,
+    // 状态项
+    pub key: String,
+    pub op: CompareOp,
+    pub value: String,
+    // 要发送的命令名称
+    pub command: String,
+    // 条件持续满足多少秒后才触发，0 表示立即触发
+    pub debounce_secs: u64,
+    // 同一节点两次触发的最小间隔（秒）
+    pub cooldown_secs: u64,
+    // 试运行：只记录触发，不发送命令
+    pub dry_run: bool,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule {
+            name: String::new(),
+            enabled: true,
+            node: String::new(),
+            key: String::new(),
+            op: CompareOp::default(),
+            value: String::new(),
+            command: String::new(),
+            debounce_secs: 0,
+            cooldown_secs: 60,
+            dry_run: false,
+        }
+    }
+}
+
+impl Rule {
+    /// 检查规则是否完整
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(format_err!("规则名称不能为空"));
+        }
+        if self.key.trim().is_empty() {
+            return Err(format_err!("状态项不能为空"));
+        }
+        if self.command.trim().is_empty() {
+            return Err(format_err!("需要选择触发时发送的命令"));
+        }
+        Ok(())
+    }
+
+    /// 节点当前的状态是否满足条件，节点没有该状态项时不满足
+    pub fn matches(&self, node: &NodeInfo) -> bool {
+        (self.node.is_empty() || self.node == node.id)
+            && node
+                .state
+                .get(&self.key)
+                .is_some_and(|value| self.op.compare(value, &self.value))
+    }
+}
+
+// 规则满足条件，需要发送命令
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleFiring {
+    pub rule: String,
+    pub node: String,
+    pub command: String,
+    pub dry_run: bool,
+}
+
+// 规则在等待条件持续或冷却结束，需要在 `after` 之后按节点的最新状态再检查一次
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleRecheck {
+    pub rule: String,
+    pub node: String,
+    pub after: Duration,
+}
+
+// 一次检查的结果
+#[derive(Debug, Default)]
+pub struct RuleCheck {
+    pub fired: Vec<RuleFiring>,
+    // 新安排的再次检查，已经安排过相同时间的不会重复出现
+    pub rechecks: Vec<RuleRecheck>,
+}
+
+// 规则在某个节点上的触发状态
+#[derive(Default)]
+struct Episode {
+    // 条件开始满足的时间，不满足时为 None
+    since: Option<DateTime<Local>>,
+    // 本次满足期间已经触发过
+    fired: bool,
+    last_fired: Option<DateTime<Local>>,
+    // 已安排的再次检查时间及其定时任务，每个规则和节点最多一个
+    recheck_at: Option<DateTime<Local>>,
+    recheck_task: Option<JoinHandle<()>>,
+}
+
+impl Episode {
+    // 条件不再满足，取消等待中的再次检查
+    fn reset(&mut self) {
+        self.since = None;
+        self.fired = false;
+        self.recheck_at = None;
+        if let Some(task) = self.recheck_task.take() {
+            task.abort();
+        }
+    }
+}
+
+fn seconds(secs: u64) -> chrono::Duration {
+    chrono::Duration::seconds(secs.min(u32::MAX as u64) as i64)
+}
+
+/// 规则及其在各节点上的触发状态
+#[derive(Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    // (规则名称, 节点ID) -> 触发状态
+    episodes: HashMap<(String, String), Episode>,
+}
+
+impl RuleEngine {
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// 记录再次检查的定时任务，替换并取消同一规则和节点之前的任务
+    pub fn set_recheck_task(&mut self, recheck: &RuleRecheck, task: JoinHandle<()>) {
+        let key = (recheck.rule.clone(), recheck.node.clone());
+        match self.episodes.get_mut(&key) {
+            Some(episode) => {
+                if let Some(old) = episode.recheck_task.replace(task) {
+                    old.abort();
+                }
+            }
+            None => task.abort(),
+        }
+    }
+
+    /// 再次检查的时间已到，之后的检查可以重新安排
+    pub fn finish_recheck(&mut self, recheck: &RuleRecheck) {
+        let key = (recheck.rule.clone(), recheck.node.clone());
+        if let Some(episode) = self.episodes.get_mut(&key) {
+            episode.recheck_at = None;
+            episode.recheck_task = None;
+        }
+    }
+
+    /// 替换全部规则，仍然存在的规则保留触发和冷却状态
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        self.episodes
+            .retain(|(name, _), _| rules.iter().any(|rule| &rule.name == name));
+        self.rules = rules;
+    }
+
+    /// 节点状态变化后检查规则
+    ///
+    /// 条件从不满足变为满足、并持续 `debounce_secs` 后触发一次，条件恢复前不再重复触发；
+    /// 同一节点两次触发至少间隔 `cooldown_secs`，冷却期间满足条件的会在冷却结束后触发
+    ///
+    /// # 参数
+    /// * `node` - 状态变化后的节点
+    /// * `now` - 当前时间
+    pub fn evaluate(&mut self, node: &NodeInfo, now: DateTime<Local>) -> RuleCheck {
+        let mut check = RuleCheck::default();
+        for rule in &self.rules {
+            let key = (rule.name.clone(), node.id.clone());
+            if !rule.enabled || !rule.matches(node) {
+                if let Some(episode) = self.episodes.get_mut(&key) {
+                    episode.reset();
+                }
+                continue;
+            }
+            let episode = self.episodes.entry(key).or_default();
+            let since = *episode.since.get_or_insert(now);
+            if episode.fired {
+                continue;
+            }
+            let mut ready = since + seconds(rule.debounce_secs);
+            if let Some(last) = episode.last_fired {
+                ready = ready.max(last + seconds(rule.cooldown_secs));
+            }
+            if now < ready {
+                // 等待期间的状态变化不重复安排
+                if episode.recheck_at != Some(ready) {
+                    episode.recheck_at = Some(ready);
+                    check.rechecks.push(RuleRecheck {
+                        rule: rule.name.clone(),
+                        node: node.id.clone(),
+                        after: (ready - now).to_std().unwrap_or_default(),
+                    });
+                }
+                continue;
+            }
+            episode.fired = true;
+            episode.last_fired = Some(now);
+            check.fired.push(RuleFiring {
+                rule: rule.name.clone(),
+                node: node.id.clone(),
+                command: rule.command.clone(),
+                dry_run: rule.dry_run,
+            });
+        }
+        check
+    }
+}
+
+// 一次规则触发的记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleActivity {
+    pub at: DateTime<Local>,
+    pub rule: String,
+    pub node: String,
+    pub command: String,
+    pub dry_run: bool,
+    // 发送结果或失败原因
+    pub result: String,
+}
+
+/// 当前生效的规则
+pub static RULES: Lazy<Mutex<RuleEngine>> = Lazy::new(|| Mutex::new(RuleEngine::default()));
+
+/// 最近的规则触发记录，最新的在后
+pub static RULE_ACTIVITY: Lazy<Mutex<VecDeque<RuleActivity>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(RULE_ACTIVITY_CAPACITY)));
+
+/// 有新的触发记录时触发，供界面刷新
+pub static RULE_ACTIVITY_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+/// 从命令库文件重新加载规则
+pub fn reload_rules() -> Result<(), Error> {
+    let rules = load_rules()?;
+    RULES
+        .lock()
+        .map_err(|_| format_err!("读取规则失败"))?
+        .set_rules(rules);
+    Ok(())
+}
+
+// 修改规则并保存到命令库文件，保存成功后才生效
+fn update_rules(f: impl FnOnce(&mut Vec<Rule>) -> Result<(), Error>) -> Result<(), Error> {
+    let mut engine = RULES.lock().map_err(|_| format_err!("读取规则失败"))?;
+    let mut rules = engine.rules().to_vec();
+    f(&mut rules)?;
+    save_rules(&rules)?;
+    engine.set_rules(rules);
+    Ok(())
+}
+
+/// 新增或修改规则（可同时改名），规则名称不能重复
+///
+/// # 参数
+/// * `original` - 修改前的名称，新增时为 None
+/// * `rule` - 新的规则
+pub fn save_rule(original: Option<&str>, rule: Rule) -> Result<(), Error> {
+    rule.validate()?;
+    update_rules(|rules| {
+        if rules
+            .iter()
+            .any(|r| r.name == rule.name && Some(r.name.as_str()) != original)
+        {
+            return Err(format_err!("规则 {} 已存在", rule.name));
+        }
+        match original.and_then(|name| rules.iter().position(|r| r.name == name)) {
+            Some(index) => rules[index] = rule,
+            None => rules.push(rule),
+        }
+        Ok(())
+    })
+}
+
+/// 删除规则
+pub fn remove_rule(name: &str) -> Result<(), Error> {
+    update_rules(|rules| {
+        rules.retain(|r| r.name != name);
+        Ok(())
+    })
+}
+
+/// 启用或停用规则
+pub fn set_rule_enabled(name: &str, enabled: bool) -> Result<(), Error> {
+    update_rules(|rules| {
+        let rule = rules
+            .iter_mut()
+            .find(|r| r.name == name)
+            .ok_or_else(|| format_err!("规则 {} 不存在", name))?;
+        rule.enabled = enabled;
+        Ok(())
+    })
+}
+
+/// 记录一次规则触发
+///
+/// # 参数
+/// * `firing` - 触发的规则
+/// * `result` - 发送结果或失败原因
+pub fn record_activity(firing: &RuleFiring, result: String) {
+    if let Ok(mut activity) = RULE_ACTIVITY.lock() {
+        if activity.len() >= RULE_ACTIVITY_CAPACITY {
+            activity.pop_front();
+        }
+        activity.push_back(RuleActivity {
+            at: Local::now(),
+            rule: firing.rule.clone(),
+            node: firing.node.clone(),
+            command: firing.command.clone(),
+            dry_run: firing.dry_run,
+            result,
+        });
+    }
+    RULE_ACTIVITY_EVENT.clone().notify(usize::MAX);
+}
+
+#[test]
+fn test_rule_debounce_and_cooldown() {
+    use std::collections::BTreeMap;
+
+    let start = Local::now();
+    let at = |secs: i64| start + chrono::Duration::seconds(secs);
+    let node = |alert: &str| NodeInfo {
+        id: "n1".to_string(),
+        position_type: 1,
+        position: vec![],
+        state: BTreeMap::from([("alert".to_string(), alert.to_string())]),
+        registered_at: start,
+        updated_at: start,
+    };
+    let mut engine = RuleEngine::default();
+    engine.set_rules(vec![Rule {
+        name: "stop".to_string(),
+        key: "alert".to_string(),
+        op: CompareOp::Ne,
+        value: "none".to_string(),
+        command: "shutdown".to_string(),
+        debounce_secs: 5,
+        cooldown_secs: 30,
+        ..Rule::default()
+    }]);
+    assert!(engine.evaluate(&node("none"), at(0)).fired.is_empty());
+    // 条件需要持续 5 秒
+    let check = engine.evaluate(&node("fire"), at(1));
+    assert!(check.fired.is_empty());
+    assert_eq!(check.rechecks, [RuleRecheck {
+        rule: "stop".to_string(),
+        node: "n1".to_string(),
+        after: Duration::from_secs(5),
+    }]);
+    // 等待期间的状态变化不再安排新的检查
+    assert!(engine.evaluate(&node("smoke"), at(3)).rechecks.is_empty());
+    assert_eq!(engine.evaluate(&node("fire"), at(6)).fired.len(), 1);
+    // 条件恢复前不重复触发
+    assert!(engine.evaluate(&node("smoke"), at(8)).fired.is_empty());
+    // 恢复后再次满足，冷却结束后才触发
+    engine.evaluate(&node("none"), at(10));
+    let check = engine.evaluate(&node("fire"), at(12));
+    assert_eq!(check.rechecks[0].after, Duration::from_secs(24));
+    assert_eq!(engine.evaluate(&node("fire"), at(36)).fired[0].command, "shutdown");
+
+    assert!(CompareOp::Gt.compare("10", "9.5"));
+    assert!(!CompareOp::Gt.compare("abc", "9"));
+    assert!(CompareOp::Eq.compare("1.0", "1"));
+    assert!(CompareOp::Contains.compare("overheat", "heat"));
+}
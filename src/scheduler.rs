@@ -0,0 +1,569 @@
+use crate::app_dir::app_config_dir;
+use crate::command_store::{load_commands, load_schedules, save_schedules};
+use crate::mqtt_handle::{Dispatch, send_or_queue_command};
+use anyhow::{Error, format_err};
+use chrono::{DateTime, Local, TimeZone};
+use croner::Cron;
+use event_listener::Event;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 当前定时任务执行记录文件的结构版本
+pub const SCHEDULE_STATE_VERSION: u32 = 1;
+
+const SCHEDULE_STATE_FILE_NAME: &str = "schedule_state.json";
+
+// 调度循环最长的等待时间，系统时间被修改后也能及时发现到期的任务
+const MAX_IDLE: Duration = Duration::from_secs(60);
+
+// 定时任务的执行时间
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduleKind {
+    // 在指定时间执行一次（Unix 时间戳，秒）
+    Once { at: i64 },
+    // 每隔一段时间执行一次
+    Interval { secs: u64 },
+    // cron 表达式：分 时 日 月 周，例如 "0 9 * * 1-5"
+    Cron { expr: String },
+}
+
+/// Unix 时间戳（秒）转为本地时间
+pub fn local_time(timestamp: i64) -> Option<DateTime<Local>> {
+    Local.timestamp_opt(timestamp, 0).single()
+}
+
+fn seconds(secs: u64) -> chrono::Duration {
+    chrono::Duration::seconds(secs.min(u32::MAX as u64) as i64)
+}
+
+impl ScheduleKind {
+    /// 检查执行时间是否有效
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            ScheduleKind::Once { at } if local_time(*at).is_none() => {
+                Err(format_err!("执行时间无效"))
+            }
+            ScheduleKind::Interval { secs: 0 } => Err(format_err!("间隔必须大于0")),
+            ScheduleKind::Cron { expr } => Cron::new(expr)
+                .parse()
+                .map(|_| ())
+                .map_err(|e| format_err!("cron 表达式错误: {}", e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 指定时间之后的下一次执行时间
+    ///
+    /// # 返回值
+    /// 一次性任务的时间已过或 cron 表达式错误时返回 None
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            ScheduleKind::Once { at } => local_time(*at).filter(|at| *at > after),
+            ScheduleKind::Interval { secs } => (*secs > 0).then(|| after + seconds(*secs)),
+            ScheduleKind::Cron { expr } => Cron::new(expr)
+                .parse()
+                .ok()?
+                .find_next_occurrence(&after, false)
+                .ok(),
+        }
+    }
+
+    /// 执行时间的显示文本，例如 "每 60 秒"
+    pub fn describe(&self) -> String {
+        match self {
+            ScheduleKind::Once { at } => local_time(*at)
+                .map(|t| format!("{} 执行一次", t.format("%Y-%m-%d %H:%M")))
+                .unwrap_or_default(),
+            ScheduleKind::Interval { secs } => format!("每 {} 秒", secs),
+            ScheduleKind::Cron { expr } => format!("cron {}", expr),
+        }
+    }
+}
+
+// 程序关闭期间错过执行时的处理方式
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedPolicy {
+    // 跳过错过的执行，从启动时间计算下一次
+    #[default]
+    Skip,
+    // 启动后立即补执行一次，错过多次也只执行一次
+    RunOnce,
+}
+
+impl MissedPolicy {
+    /// 全部处理方式，顺序与定时任务窗口中的选项一致
+    pub const ALL: [MissedPolicy; 2] = [MissedPolicy::Skip, MissedPolicy::RunOnce];
+}
+
+// 定时发送命令库中的命令
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Schedule {
+    pub name: String,
+    pub enabled: bool,
+    pub command: String,
+    pub kind: ScheduleKind,
+    pub missed: MissedPolicy,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            name: String::new(),
+            enabled: true,
+            command: String::new(),
+            kind: ScheduleKind::Interval { secs: 60 },
+            missed: MissedPolicy::default(),
+        }
+    }
+}
+
+impl Schedule {
+    /// 检查定时任务是否完整
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() {
+            return Err(format_err!("任务名称不能为空"));
+        }
+        if self.command.trim().is_empty() {
+            return Err(format_err!("需要选择执行的命令"));
+        }
+        self.kind.validate()
+    }
+
+    // 从指定时间计算下一次执行时间，停用的任务不执行
+    fn next_run(&self, now: DateTime<Local>) -> Option<i64> {
+        if !self.enabled {
+            return None;
+        }
+        self.kind.next_after(now).map(|t| t.timestamp())
+    }
+
+    // 没有执行记录时的下次执行时间；一次性任务保持原定时间，已错过的由 `catch_up` 按错过执行的处理方式处理
+    fn first_run(&self, now: DateTime<Local>) -> Option<i64> {
+        match self.kind {
+            ScheduleKind::Once { at } if self.enabled => local_time(at).map(|t| t.timestamp()),
+            _ => self.next_run(now),
+        }
+    }
+}
+
+// 定时任务的执行记录，与任务定义分开保存，避免每次执行都改写命令库
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ScheduleRun {
+    // 下次执行时间（Unix 时间戳，秒），None 表示不再执行
+    pub next_run: Option<i64>,
+    // 最近一次执行时间（Unix 时间戳，秒）
+    pub last_run: Option<i64>,
+    // 最近一次执行的结果
+    pub last_result: String,
+}
+
+/// 定时任务及其执行记录
+#[derive(Default)]
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    // 任务名称 -> 执行记录
+    runs: HashMap<String, ScheduleRun>,
+}
+
+impl Scheduler {
+    /// 使用保存的执行记录恢复，没有记录的周期任务从当前时间计算下次执行时间，
+    /// 没有记录的一次性任务保持原定时间
+    ///
+    /// 错过的执行保持原来的时间，由 `catch_up` 处理
+    pub fn restore(
+        schedules: Vec<Schedule>,
+        mut runs: HashMap<String, ScheduleRun>,
+        now: DateTime<Local>,
+    ) -> Self {
+        runs.retain(|name, _| schedules.iter().any(|s| &s.name == name));
+        for schedule in &schedules {
+            let run = runs
+                .entry(schedule.name.clone())
+                .or_insert_with(|| ScheduleRun {
+                    next_run: schedule.first_run(now),
+                    ..ScheduleRun::default()
+                });
+            if !schedule.enabled {
+                run.next_run = None;
+            }
+        }
+        Scheduler { schedules, runs }
+    }
+
+    pub fn schedules(&self) -> &[Schedule] {
+        &self.schedules
+    }
+
+    pub fn run(&self, name: &str) -> Option<&ScheduleRun> {
+        self.runs.get(name)
+    }
+
+    /// 替换全部任务，执行时间或启用状态改变的任务从当前时间重新计算下次执行时间
+    pub fn set_schedules(&mut self, schedules: Vec<Schedule>, now: DateTime<Local>) {
+        for schedule in &schedules {
+            let unchanged = self.schedules.iter().any(|s| {
+                s.name == schedule.name && s.kind == schedule.kind && s.enabled == schedule.enabled
+            });
+            let run = self.runs.entry(schedule.name.clone()).or_default();
+            if !unchanged {
+                run.next_run = schedule.next_run(now);
+            }
+        }
+        self.runs
+            .retain(|name, _| schedules.iter().any(|s| &s.name == name));
+        self.schedules = schedules;
+    }
+
+    /// 处理程序关闭期间错过的执行，启动时调用一次
+    ///
+    /// 设置为跳过的任务从当前时间重新计算下次执行时间；设置为补执行的任务保持已到期，
+    /// 由下一次 `take_due` 执行一次
+    pub fn catch_up(&mut self, now: DateTime<Local>) {
+        for schedule in self.schedules.iter().filter(|s| s.enabled) {
+            let Some(run) = self.runs.get_mut(&schedule.name) else {
+                continue;
+            };
+            let Some(missed) = run.next_run.filter(|t| *t <= now.timestamp()) else {
+                continue;
+            };
+            if schedule.missed == MissedPolicy::Skip {
+                run.next_run = schedule.next_run(now);
+                run.last_result = format!(
+                    "错过 {} 的执行，已跳过",
+                    local_time(missed)
+                        .map(|t| t.format("%m-%d %H:%M").to_string())
+                        .unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    /// 取出已到期的任务，并计算它们的下一次执行时间
+    pub fn take_due(&mut self, now: DateTime<Local>) -> Vec<Schedule> {
+        let mut due = Vec::new();
+        for schedule in self.schedules.iter().filter(|s| s.enabled) {
+            let Some(run) = self.runs.get_mut(&schedule.name) else {
+                continue;
+            };
+            if run.next_run.is_some_and(|t| t <= now.timestamp()) {
+                run.last_run = Some(now.timestamp());
+                run.last_result = "执行中".to_string();
+                run.next_run = schedule.next_run(now);
+                due.push(schedule.clone());
+            }
+        }
+        due
+    }
+
+    /// 最早的下次执行时间（Unix 时间戳，秒），没有待执行的任务时返回 None
+    pub fn next_wakeup(&self) -> Option<i64> {
+        self.schedules
+            .iter()
+            .filter(|s| s.enabled)
+            .filter_map(|s| self.runs.get(&s.name)?.next_run)
+            .min()
+    }
+
+    /// 记录一次执行的结果
+    pub fn record_result(&mut self, name: &str, result: String) {
+        if let Some(run) = self.runs.get_mut(name) {
+            run.last_result = result;
+        }
+    }
+}
+
+// 执行记录文件
+#[derive(Serialize, Deserialize)]
+struct ScheduleStateFile {
+    version: u32,
+    runs: HashMap<String, ScheduleRun>,
+}
+
+pub fn schedule_state_path() -> PathBuf {
+    app_config_dir().join(SCHEDULE_STATE_FILE_NAME)
+}
+
+/// 从指定文件加载定时任务的执行记录，文件不存在时为空
+pub fn load_schedule_state_from(path: &Path) -> Result<HashMap<String, ScheduleRun>, Error> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let file: ScheduleStateFile = serde_json::from_str(&text)
+                .map_err(|e| format_err!("定时任务执行记录文件损坏: {}", e))?;
+            if file.version != SCHEDULE_STATE_VERSION {
+                return Err(format_err!("不支持的执行记录版本: {}", file.version));
+            }
+            Ok(file.runs)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 将定时任务的执行记录保存到指定文件，先写入临时文件再改名
+pub fn save_schedule_state_to(path: &Path, runs: &HashMap<String, ScheduleRun>) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = ScheduleStateFile {
+        version: SCHEDULE_STATE_VERSION,
+        runs: runs.clone(),
+    };
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(&file)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// 当前的定时任务
+pub static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(|| Mutex::new(Scheduler::default()));
+
+/// 定时任务或执行记录变化时触发，供调度循环和界面刷新
+pub static SCHEDULE_EVENT: Lazy<Arc<Event>> = Lazy::new(|| Arc::new(Event::new()));
+
+// 保存执行记录并通知变化，写入失败只输出日志，不影响调度
+//
+// 写文件时不持有 SCHEDULER 的锁；在文件锁内读取最新记录，最后一次写入的总是最新的
+fn save_state() {
+    static STATE_FILE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    let Ok(_file) = STATE_FILE.lock() else {
+        return;
+    };
+    let Some(runs) = SCHEDULER.lock().ok().map(|scheduler| scheduler.runs.clone()) else {
+        return;
+    };
+    if let Err(e) = save_schedule_state_to(&schedule_state_path(), &runs) {
+        eprintln!("定时任务执行记录保存失败: {}", e);
+    }
+    SCHEDULE_EVENT.clone().notify(usize::MAX);
+}
+
+/// 从命令库文件加载定时任务，并处理程序关闭期间错过的执行
+pub fn reload_schedules() -> Result<(), Error> {
+    let schedules = load_schedules()?;
+    let runs = load_schedule_state_from(&schedule_state_path()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        HashMap::new()
+    });
+    let now = Local::now();
+    let mut scheduler = Scheduler::restore(schedules, runs, now);
+    scheduler.catch_up(now);
+    *SCHEDULER
+        .lock()
+        .map_err(|_| format_err!("读取定时任务失败"))? = scheduler;
+    save_state();
+    Ok(())
+}
+
+/// 从命令库重新读取任务定义，已有任务保留执行记录，用于命令改名后同步任务引用的命令
+pub fn refresh_schedules() -> Result<(), Error> {
+    let schedules = load_schedules()?;
+    SCHEDULER
+        .lock()
+        .map_err(|_| format_err!("读取定时任务失败"))?
+        .set_schedules(schedules, Local::now());
+    save_state();
+    Ok(())
+}
+
+// 修改定时任务并保存到命令库文件，保存成功后才生效
+//
+// 写文件期间不持有 SCHEDULER 的锁，调度循环不会被阻塞；修改之间由单独的锁保证先后顺序
+fn update_schedules(
+    f: impl FnOnce(&mut Vec<Schedule>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    static UPDATING: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    let _updating = UPDATING.lock().map_err(|_| format_err!("读取定时任务失败"))?;
+    let mut schedules = SCHEDULER
+        .lock()
+        .map_err(|_| format_err!("读取定时任务失败"))?
+        .schedules()
+        .to_vec();
+    f(&mut schedules)?;
+    save_schedules(&schedules)?;
+    SCHEDULER
+        .lock()
+        .map_err(|_| format_err!("读取定时任务失败"))?
+        .set_schedules(schedules, Local::now());
+    save_state();
+    Ok(())
+}
+
+/// 新增或修改定时任务（可同时改名），任务名称不能重复
+///
+/// # 参数
+/// * `original` - 修改前的名称，新增时为 None
+/// * `schedule` - 新的定时任务；修改了执行时间的一次性任务，时间必须晚于当前时间
+pub fn save_schedule(original: Option<&str>, schedule: Schedule) -> Result<(), Error> {
+    schedule.validate()?;
+    update_schedules(|schedules| {
+        if schedules
+            .iter()
+            .any(|s| s.name == schedule.name && Some(s.name.as_str()) != original)
+        {
+            return Err(format_err!("定时任务 {} 已存在", schedule.name));
+        }
+        let index = original.and_then(|name| schedules.iter().position(|s| s.name == name));
+        let kind_changed = index.is_none_or(|i| schedules[i].kind != schedule.kind);
+        if kind_changed && schedule.kind.next_after(Local::now()).is_none() {
+            return Err(format_err!("执行时间已过"));
+        }
+        match index {
+            Some(index) => schedules[index] = schedule,
+            None => schedules.push(schedule),
+        }
+        Ok(())
+    })
+}
+
+/// 删除定时任务
+pub fn remove_schedule(name: &str) -> Result<(), Error> {
+    update_schedules(|schedules| {
+        schedules.retain(|s| s.name != name);
+        Ok(())
+    })
+}
+
+/// 启用或停用定时任务，重新启用时从当前时间计算下次执行时间
+pub fn set_schedule_enabled(name: &str, enabled: bool) -> Result<(), Error> {
+    update_schedules(|schedules| {
+        let schedule = schedules
+            .iter_mut()
+            .find(|s| s.name == name)
+            .ok_or_else(|| format_err!("定时任务 {} 不存在", name))?;
+        schedule.enabled = enabled;
+        Ok(())
+    })
+}
+
+// 发送定时任务的命令并记录结果，未连接时进入发送队列
+async fn fire_schedule(schedule: Schedule) {
+    let command = load_commands().and_then(|commands| {
+        commands
+            .into_iter()
+            .find(|c| c.name == schedule.command)
+            .ok_or_else(|| format_err!("命令库中没有命令: {}", schedule.command))
+    });
+    let result = match command {
+        Ok(command) => send_or_queue_command(&command).await,
+        Err(e) => Err(e),
+    };
+    let result = match result {
+        Ok(Dispatch::Sent(_)) => "已发送".to_string(),
+        Ok(Dispatch::Queued(_)) => "未连接，已进入发送队列".to_string(),
+        Err(e) => format!("发送失败: {}", e),
+    };
+    if let Ok(mut scheduler) = SCHEDULER.lock() {
+        scheduler.record_result(&schedule.name, result);
+    }
+    save_state();
+}
+
+/// 调度循环，到期的任务在后台发送，程序运行期间一直执行
+///
+/// 需要在 tokio 运行时中调用，任务在 `reload_schedules` 之后才会执行
+pub async fn run_scheduler() {
+    let event = SCHEDULE_EVENT.clone();
+    loop {
+        let listener = event.listen();
+        let now = Local::now();
+        let (due, wakeup) = {
+            let Ok(mut scheduler) = SCHEDULER.lock() else {
+                return;
+            };
+            (scheduler.take_due(now), scheduler.next_wakeup())
+        };
+        if !due.is_empty() {
+            save_state();
+        }
+        for schedule in due {
+            tokio::spawn(fire_schedule(schedule));
+        }
+        let wait = wakeup
+            .map(|t| Duration::from_secs((t - now.timestamp()).max(0) as u64))
+            .map_or(MAX_IDLE, |wait| wait.min(MAX_IDLE));
+        // 任务被修改时提前醒来重新计算
+        let _ = tokio::time::timeout(wait, listener).await;
+    }
+}
+
+#[test]
+fn test_scheduler_due_and_missed_runs() {
+    let at = |h: u32, m: u32| Local.with_ymd_and_hms(2026, 3, 2, h, m, 0).unwrap();
+    let schedule = |name: &str, kind: ScheduleKind, missed: MissedPolicy| Schedule {
+        name: name.to_string(),
+        command: "fan".to_string(),
+        kind,
+        missed,
+        ..Schedule::default()
+    };
+    let cron = ScheduleKind::Cron {
+        expr: "30 9 * * 1-5".to_string(),
+    };
+    assert_eq!(cron.next_after(at(9, 0)), Some(at(9, 30)));
+    assert_eq!(cron.next_after(at(9, 30)), Some(at(9, 30) + chrono::Duration::days(1)));
+    assert!(ScheduleKind::Cron { expr: "61 * * * *".to_string() }.validate().is_err());
+    assert!(ScheduleKind::Interval { secs: 0 }.validate().is_err());
+
+    let mut scheduler = Scheduler::default();
+    scheduler.set_schedules(
+        vec![
+            schedule("once", ScheduleKind::Once { at: at(10, 0).timestamp() }, MissedPolicy::Skip),
+            schedule("every", ScheduleKind::Interval { secs: 600 }, MissedPolicy::Skip),
+        ],
+        at(9, 0),
+    );
+    assert_eq!(scheduler.next_wakeup(), Some(at(9, 10).timestamp()));
+    assert!(scheduler.take_due(at(9, 5)).is_empty());
+    let due: Vec<String> = scheduler.take_due(at(10, 0)).into_iter().map(|s| s.name).collect();
+    assert_eq!(due, ["once", "every"]);
+    assert_eq!(scheduler.run("once").unwrap().next_run, None);
+    assert_eq!(scheduler.run("every").unwrap().next_run, Some(at(10, 10).timestamp()));
+
+    // 关闭期间错过的执行：跳过的重新计算，补执行的保持到期
+    let runs = scheduler.runs.clone();
+    let mut restored = Scheduler::restore(
+        vec![
+            schedule("every", ScheduleKind::Interval { secs: 600 }, MissedPolicy::Skip),
+            schedule("daily", cron.clone(), MissedPolicy::RunOnce),
+        ],
+        runs,
+        at(12, 0),
+    );
+    restored.runs.get_mut("daily").unwrap().next_run = Some(at(9, 30).timestamp());
+    restored.catch_up(at(12, 0));
+    assert!(restored.run("once").is_none());
+    assert_eq!(restored.run("every").unwrap().next_run, Some(at(12, 10).timestamp()));
+    let due: Vec<String> = restored.take_due(at(12, 0)).into_iter().map(|s| s.name).collect();
+    assert_eq!(due, ["daily"]);
+    assert_eq!(
+        restored.run("daily").unwrap().next_run,
+        Some((at(9, 30) + chrono::Duration::days(1)).timestamp())
+    );
+
+    // 没有执行记录的一次性任务在关闭期间错过：补执行的执行一次，跳过的不再执行
+    let once = ScheduleKind::Once { at: at(10, 0).timestamp() };
+    let mut restored = Scheduler::restore(
+        vec![
+            schedule("run_once", once.clone(), MissedPolicy::RunOnce),
+            schedule("skip", once, MissedPolicy::Skip),
+        ],
+        HashMap::new(),
+        at(12, 0),
+    );
+    restored.catch_up(at(12, 0));
+    assert_eq!(restored.run("skip").unwrap().next_run, None);
+    assert!(restored.run("skip").unwrap().last_result.contains("已跳过"));
+    let due: Vec<String> = restored.take_due(at(12, 0)).into_iter().map(|s| s.name).collect();
+    assert_eq!(due, ["run_once"]);
+    assert_eq!(restored.run("run_once").unwrap().next_run, None);
+    assert!(restored.take_due(at(12, 1)).is_empty());
+}
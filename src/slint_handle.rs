@@ -1,20 +1,66 @@
-use crate::{CommandSetWindow, MainView};
+use slint_rmqtt::broker::BrokerConfigure;
+use slint_rmqtt::command_macro::{
+    CommandMacro, MACRO_STATUS, MACROS, MacroState, MacroStep, find_macro, reload_macros,
+    remove_macro, save_macro, start_macro,
+};
+use slint_rmqtt::command_param::{CommandParam, ParamType};
+use slint_rmqtt::command_tracker::COMMAND_STATUS;
+use slint_rmqtt::command_store::{
+    Delivery, StoredCommand, command_references, load_commands, save_commands, save_renamed_commands,
+};
+use slint_rmqtt::mqtt::message_log::{
+    Direction, LogEntry, LogFilter, MESSAGE_LOG, MESSAGE_LOG_EVENT, pretty_payload,
+};
+use slint_rmqtt::mqtt::tls::TlsSettings;
+use slint_rmqtt::mqtt_handle::{NODES, send_or_queue_command, switch_profile};
+use slint_rmqtt::node_registry::NodeRegistry;
+use slint_rmqtt::rule_engine::{
+    CompareOp, RULE_ACTIVITY, RULE_ACTIVITY_EVENT, RULES, Rule, reload_rules, remove_rule, save_rule,
+    set_rule_enabled,
+};
+use slint_rmqtt::scheduler::{
+    MissedPolicy, SCHEDULE_EVENT, SCHEDULER, Schedule, ScheduleKind, local_time, refresh_schedules,
+    reload_schedules, remove_schedule, save_schedule, set_schedule_enabled,
+};
+use slint_rmqtt::UPDATE_STATE_EVENT;
+use slint_rmqtt::state_history::chart_path;
+use slint_rmqtt::profile::{ConnectionProfile, ProfileArgs, ProfileStore, load_profiles_from, save_profiles_to};
+use crate::{
+    CommandItem, CommandSetWindow, LogItem, MacroStepRow, MacroWindow, MainView, MessageLogWindow,
+    Notification, ParamRow,
+    ProfileData, RuleActivityItem, RuleData, RulesWindow, ScheduleData, ScheduleItem,
+    ScheduleWindow, SettingsWindow, StateChartWindow,
+};
+use anyhow::{Error, format_err};
+use event_listener::Event;
 use once_cell::sync::Lazy;
 
 use native_dialog::{DialogBuilder, MessageLevel};
-use slint::{ComponentHandle, Model, ModelRc, PhysicalPosition, SharedString, VecModel, Weak};
+use slint::{CloseRequestResponse, ComponentHandle, Model, ModelRc, PhysicalPosition, SharedString, VecModel, Weak};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::Mutex;
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread::{self, LocalKey};
+use std::time::Duration;
 
-pub static COMMAND_NAME2COMMAND_DICT: Lazy<Mutex<HashMap<String, Vec<(String, f32)>>>> =
+type CommandDict = Vec<CommandParam>;
+
+pub static COMMAND_NAME2COMMAND_DICT: Lazy<Mutex<HashMap<String, CommandDict>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
 pub static COMMAND_NAME2TOPIC: Lazy<Mutex<HashMap<String, String>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-pub static COMMANDS: Lazy<Mutex<Vec<SharedString>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static COMMAND_NAME2DELIVERY: Lazy<Mutex<HashMap<String, Delivery>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static COMMANDS: Lazy<Mutex<Vec<SharedString>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub static PROFILES: Lazy<Mutex<ProfileStore>> = Lazy::new(|| Mutex::new(ProfileStore::default()));
+static PROFILES_PATH: OnceLock<PathBuf> = OnceLock::new();
 pub fn show_windows_dialog(text: String, title: String) {
     thread::spawn(move || {
         DialogBuilder::message()
@@ -26,54 +72,1683 @@ pub fn show_windows_dialog(text: String, title: String) {
             .unwrap();
     });
 }
-fn add_item_to_model(window: CommandSetWindow) {
-    let data_model = window.get_data().clone();
-    if let Some(vec_model) = data_model
-        .as_any()
-        .downcast_ref::<VecModel<(SharedString, f32)>>()
+
+/// 弹出确认框，确认后在界面线程中执行 `on_confirm`
+///
+/// # 参数
+/// * `text` - 提示内容
+/// * `title` - 标题
+/// * `on_confirm` - 确认后执行的操作
+pub fn show_confirm_dialog(text: String, title: String, on_confirm: impl FnOnce() + Send + 'static) {
+    // 确认框会阻塞，放到单独线程中
+    thread::spawn(move || {
+        let confirmed = DialogBuilder::message()
+            .set_level(MessageLevel::Warning)
+            .set_title(title)
+            .set_text(text)
+            .confirm()
+            .show()
+            .unwrap_or(false);
+        if confirmed {
+            let _ = slint::invoke_from_event_loop(on_confirm);
+        }
+    });
+}
+
+// 同时只打开一个的窗口，句柄保存在界面线程中，关闭后清空
+thread_local! {
+    static MESSAGE_LOG_WINDOW: RefCell<Option<MessageLogWindow>> = const { RefCell::new(None) };
+    static STATE_CHART_WINDOW: RefCell<Option<StateChartWindow>> = const { RefCell::new(None) };
+    static RULES_WINDOW: RefCell<Option<RulesWindow>> = const { RefCell::new(None) };
+    static SCHEDULE_WINDOW: RefCell<Option<ScheduleWindow>> = const { RefCell::new(None) };
+}
+
+// 窗口打开期间的自动刷新方式
+struct WindowRefresh<W> {
+    // 收到通知后刷新
+    event: Arc<Event>,
+    // 两次刷新之间的最短间隔
+    throttle: Duration,
+    // 没有通知时也按该间隔刷新
+    period: Option<Duration>,
+    // 在界面线程中刷新窗口
+    refresh: fn(&W),
+}
+
+impl<W> WindowRefresh<W> {
+    fn on(event: &Arc<Event>, refresh: fn(&W)) -> Self {
+        WindowRefresh {
+            event: event.clone(),
+            throttle: Duration::ZERO,
+            period: None,
+            refresh,
+        }
+    }
+}
+
+/// 打开同时只有一个的窗口，已经打开时显示已有的窗口；需要在界面线程中调用
+///
+/// # 参数
+/// * `slot` - 保存窗口句柄的线程局部变量
+/// * `create` - 创建窗口、设置回调并填充初始内容
+/// * `refresh` - 窗口打开期间的自动刷新方式，窗口关闭后停止刷新
+///
+/// # 返回值
+/// 新创建或已打开的窗口
+fn open_singleton_window<W: ComponentHandle + 'static>(
+    slot: &'static LocalKey<RefCell<Option<W>>>,
+    create: impl FnOnce() -> W,
+    refresh: WindowRefresh<W>,
+) -> W {
+    if let Some(window) = slot.with_borrow(|window| window.as_ref().map(|w| w.clone_strong())) {
+        window.show().unwrap();
+        return window;
+    }
+    let window = create();
+    let open = Arc::new(AtomicBool::new(true));
+    let open_clone = open.clone();
+    window.window().on_close_requested(move || {
+        open_clone.store(false, Ordering::Relaxed);
+        // 不在窗口自己的回调中释放窗口，回到事件循环后再清空
+        let _ = slint::invoke_from_event_loop(move || slot.set(None));
+        CloseRequestResponse::HideWindow
+    });
+    tokio::spawn(async move {
+        let WindowRefresh {
+            event,
+            throttle,
+            period,
+            refresh,
+        } = refresh;
+        loop {
+            let listener = event.listen();
+            match period {
+                Some(period) => {
+                    let _ = tokio::time::timeout(period, listener).await;
+                }
+                None => listener.await,
+            }
+            if !open.load(Ordering::Relaxed) {
+                break;
+            }
+            let refreshed = slint::invoke_from_event_loop(move || {
+                slot.with_borrow(|window| {
+                    if let Some(window) = window {
+                        refresh(window);
+                    }
+                })
+            });
+            if refreshed.is_err() {
+                break;
+            }
+            tokio::time::sleep(throttle).await;
+        }
+    });
+    slot.set(Some(window.clone_strong()));
+    window.show().unwrap();
+    window
+}
+// 通知自动消失前显示的时间
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(6);
+
+static NOTIFICATION_ID: AtomicI32 = AtomicI32::new(0);
+
+// 主界面的通知列表，首次使用时创建
+fn notification_model(main_view: &MainView) -> ModelRc<Notification> {
+    let model = main_view.get_notifications();
+    if model.as_any().is::<VecModel<Notification>>() {
+        return model;
+    }
+    let model = ModelRc::from(Rc::new(VecModel::<Notification>::default()));
+    main_view.set_notifications(model.clone());
+    model
+}
+
+/// 在主界面右下角显示通知，一段时间后自动消失，只能在界面线程调用
+///
+/// # 参数
+/// * `main_view` - 主界面
+/// * `title` - 通知标题，例如 "发送失败"
+/// * `text` - 通知内容
+pub fn show_notification(main_view: &MainView, title: &str, text: String) {
+    let id = NOTIFICATION_ID.fetch_add(1, Ordering::Relaxed);
+    let model = notification_model(main_view);
+    if let Some(model) = model.as_any().downcast_ref::<VecModel<Notification>>() {
+        model.push(Notification {
+            id,
+            title: SharedString::from(title),
+            text: SharedString::from(text),
+        });
+    }
+    let main_view = main_view.as_weak();
+    slint::Timer::single_shot(NOTIFICATION_TIMEOUT, move || {
+        if let Some(main_view) = main_view.upgrade() {
+            dismiss_notification(&main_view, id);
+        }
+    });
+}
+
+/// 关闭指定的通知
+pub fn dismiss_notification(main_view: &MainView, id: i32) {
+    let model = notification_model(main_view);
+    if let Some(model) = model.as_any().downcast_ref::<VecModel<Notification>>()
+        && let Some(index) = model.iter().position(|n| n.id == id)
     {
-        vec_model.push((SharedString::from(""), 0.0));
-    } else {
-        eprintln!("Failed to downcast to VecModel");
+        model.remove(index);
     }
 }
 
-fn add_command(
-    data_model: ModelRc<(SharedString, f32)>,
-    name: SharedString,
-    topic: SharedString,
-    main_view: Weak<MainView>,
-) {
-    let mut n2d = COMMAND_NAME2COMMAND_DICT.lock().unwrap();
-    let mut n2t = COMMAND_NAME2TOPIC.lock().unwrap();
-    let mut command_names = COMMANDS.lock().unwrap();
-    let mut value_vec: Vec<(String, f32)> = vec![];
-    if let Some(vec_model) = data_model
+/// 在 tokio 运行时中执行命令或命令宏，界面线程不等待发送结果，多个命令可以同时执行
+///
+/// 命令不存在或发送失败时在主界面显示通知
+pub fn dispatch_command(main_view: Weak<MainView>, command_name: &str) {
+    let Some(command) = stored_command(command_name) else {
+        let Some(main_view) = main_view.upgrade() else {
+            return;
+        };
+        // 命令宏的进度通过命令列表显示
+        match find_macro(command_name) {
+            Some(command_macro) => {
+                if let Err(e) = start_macro(command_macro) {
+                    show_notification(&main_view, "执行失败", e.to_string());
+                }
+                refresh_command_list(&main_view);
+            }
+            None => {
+                show_notification(&main_view, "发送失败", format!("命令 {} 不存在", command_name))
+            }
+        }
+        return;
+    };
+    tokio::spawn(async move {
+        // 未连接时进入发送队列，界面显示为排队中
+        if let Err(e) = send_or_queue_command(&command).await {
+            let text = format!("{}: {}", command.name, e);
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(main_view) = main_view.upgrade() {
+                    show_notification(&main_view, "发送失败", text);
+                }
+            });
+        }
+    });
+}
+
+// 编辑窗口中的参数行
+fn param_rows(window: &CommandSetWindow) -> Option<ModelRc<ParamRow>> {
+    let data_model = window.get_data();
+    if data_model
         .as_any()
-        .downcast_ref::<VecModel<(SharedString, f32)>>()
+        .downcast_ref::<VecModel<ParamRow>>()
+        .is_none()
     {
-        vec_model.iter().for_each(|item| {
-            value_vec.push((item.0.to_string(), item.1));
+        eprintln!("Failed to downcast to VecModel");
+        return None;
+    }
+    Some(data_model)
+}
+
+fn add_item_to_model(window: CommandSetWindow) {
+    let Some(data_model) = param_rows(&window) else {
+        return;
+    };
+    if let Some(vec_model) = data_model.as_any().downcast_ref::<VecModel<ParamRow>>() {
+        vec_model.push(ParamRow {
+            key: SharedString::new(),
+            kind: SharedString::from(ParamType::Float.as_str()),
+            value: SharedString::from("0"),
+            options: SharedString::new(),
+            error: SharedString::new(),
         });
-        n2d.insert(name.to_string(), value_vec);
-        n2t.insert(name.to_string(), topic.to_string());
-        command_names.push(name);
-        println!("{:?}", n2d);
-        println!("{:?}",n2t);
-        main_view.unwrap().set_commands(ModelRc::from(Rc::new(VecModel::from(command_names.clone()))));
     }
 }
-pub fn open_command_set_window(main_view: Weak<MainView>) {
+
+fn remove_item_from_model(window: &CommandSetWindow, index: i32) {
+    let Some(data_model) = param_rows(window) else {
+        return;
+    };
+    if let Some(vec_model) = data_model.as_any().downcast_ref::<VecModel<ParamRow>>()
+        && (0..vec_model.row_count() as i32).contains(&index)
+    {
+        vec_model.remove(index as usize);
+    }
+}
+
+// 交换两行的位置，用于上移和下移
+fn move_item_in_model(window: &CommandSetWindow, from: i32, to: i32) {
+    let Some(data_model) = param_rows(window) else {
+        return;
+    };
+    let range = 0..data_model.row_count() as i32;
+    if from == to || !range.contains(&from) || !range.contains(&to) {
+        return;
+    }
+    let (from, to) = (from as usize, to as usize);
+    let row = data_model.row_data(from).unwrap_or_default();
+    let other = data_model.row_data(to).unwrap_or_default();
+    data_model.set_row_data(from, other);
+    data_model.set_row_data(to, row);
+}
+
+// 命令编辑窗口的校验错误，按字段分开以便显示在对应位置
+#[derive(Default, Debug)]
+struct FormErrors {
+    name: Option<String>,
+    topic: Option<String>,
+    // 与参数行一一对应
+    rows: Vec<Option<String>>,
+}
+
+impl FormErrors {
+    fn is_empty(&self) -> bool {
+        self.name.is_none() && self.topic.is_none() && self.rows.iter().all(Option::is_none)
+    }
+}
+
+// 校验编辑窗口的输入，全部通过时返回命令
+fn validate_command_form(
+    name: &str,
+    topic: &str,
+    delivery: Delivery,
+    rows: &[ParamRow],
+) -> Result<StoredCommand, FormErrors> {
+    let mut errors = FormErrors::default();
+    let name = name.trim();
+    let topic = topic.trim();
+    if name.is_empty() {
+        errors.name = Some("命令名称不能为空".to_string());
+    }
+    if topic.is_empty() {
+        errors.topic = Some("主题名称不能为空".to_string());
+    } else if topic.contains(['+', '#']) {
+        errors.topic = Some("发送主题不能包含通配符 + 或 #".to_string());
+    }
+    let mut values = Vec::with_capacity(rows.len());
+    for (i, row) in rows.iter().enumerate() {
+        let key = row.key.trim();
+        let result = if key.is_empty() {
+            Err(format_err!("属性名不能为空"))
+        } else if rows[..i].iter().any(|r| r.key.trim() == key) {
+            Err(format_err!("属性名 {} 重复", key))
+        } else {
+            ParamType::parse(&row.kind)
+                .and_then(|t| CommandParam::parse(key, t, &row.value, &row.options))
+        };
+        match result {
+            Ok(param) => {
+                values.push(param);
+                errors.rows.push(None);
+            }
+            Err(e) => errors.rows.push(Some(e.to_string())),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(StoredCommand {
+        name: name.to_string(),
+        topic: topic.to_string(),
+        values,
+        delivery,
+    })
+}
+
+// 把校验结果显示到编辑窗口，没有错误的字段清空提示
+fn show_form_errors(window: &CommandSetWindow, errors: &FormErrors) {
+    let text = |e: Option<&String>| SharedString::from(e.cloned().unwrap_or_default());
+    window.set_name_error(text(errors.name.as_ref()));
+    window.set_topic_error(text(errors.topic.as_ref()));
+    let Some(data_model) = param_rows(window) else {
+        return;
+    };
+    for i in 0..data_model.row_count() {
+        let error = text(errors.rows.get(i).and_then(Option::as_ref));
+        if let Some(mut row) = data_model.row_data(i).filter(|row| row.error != error) {
+            row.error = error;
+            data_model.set_row_data(i, row);
+        }
+    }
+}
+
+// 命令库的四个映射表，修改时同时持有四把锁，保证命令名称在各表中一致
+struct CommandMaps {
+    n2d: MutexGuard<'static, HashMap<String, CommandDict>>,
+    n2t: MutexGuard<'static, HashMap<String, String>>,
+    n2q: MutexGuard<'static, HashMap<String, Delivery>>,
+    names: MutexGuard<'static, Vec<SharedString>>,
+}
+
+impl CommandMaps {
+    fn lock() -> Self {
+        CommandMaps {
+            n2d: COMMAND_NAME2COMMAND_DICT.lock().unwrap(),
+            n2t: COMMAND_NAME2TOPIC.lock().unwrap(),
+            n2q: COMMAND_NAME2DELIVERY.lock().unwrap(),
+            names: COMMANDS.lock().unwrap(),
+        }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.names.iter().any(|n| n == name)
+    }
+
+    fn get(&self, name: &str) -> Option<StoredCommand> {
+        Some(StoredCommand {
+            name: name.to_string(),
+            topic: self.n2t.get(name)?.clone(),
+            values: self.n2d.get(name)?.clone(),
+            delivery: self.n2q.get(name).copied().unwrap_or_default(),
+        })
+    }
+
+    /// 新建或修改命令
+    ///
+    /// # 参数
+    /// * `original` - 被修改命令的原名称，新建时为 None；与新名称不同时视为改名，命令保持原位置
+    /// * `command` - 新的命令内容
+    fn upsert(&mut self, original: Option<&str>, command: StoredCommand) -> Result<(), Error> {
+        if command.name.trim().is_empty() {
+            return Err(format_err!("命令名称不能为空"));
+        }
+        if command.topic.trim().is_empty() {
+            return Err(format_err!("主题名称不能为空"));
+        }
+        if original != Some(command.name.as_str()) && self.contains(&command.name) {
+            return Err(format_err!("命令名称 {} 已存在", command.name));
+        }
+        match original {
+            Some(old) => {
+                let index = self
+                    .names
+                    .iter()
+                    .position(|n| n == old)
+                    .ok_or_else(|| format_err!("命令 {} 不存在", old))?;
+                self.n2d.remove(old);
+                self.n2t.remove(old);
+                self.n2q.remove(old);
+                self.names[index] = SharedString::from(command.name.as_str());
+            }
+            None => self.names.push(SharedString::from(command.name.as_str())),
+        }
+        self.n2d.insert(command.name.clone(), command.values);
+        self.n2q.insert(command.name.clone(), command.delivery);
+        self.n2t.insert(command.name, command.topic);
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        self.n2d.remove(name);
+        self.n2t.remove(name);
+        self.n2q.remove(name);
+        let len = self.names.len();
+        self.names.retain(|n| n != name);
+        self.names.len() != len
+    }
+
+    /// 复制命令，新命令名称为 "原名称_副本"，重名时追加序号，插入在原命令之后
+    ///
+    /// # 返回值
+    /// 返回新命令的名称
+    fn duplicate(&mut self, name: &str) -> Result<String, Error> {
+        let mut command = self
+            .get(name)
+            .ok_or_else(|| format_err!("命令 {} 不存在", name))?;
+        command.name = (1..)
+            .map(|i| match i {
+                1 => format!("{}_副本", name),
+                i => format!("{}_副本{}", name, i),
+            })
+            .find(|n| !self.contains(n) && find_macro(n).is_none())
+            .unwrap_or_default();
+        self.upsert(None, command.clone())?;
+        // 新命令默认追加在末尾，移到原命令之后
+        if let Some(index) = self.names.iter().position(|n| n == name) {
+            let copy = self.names.pop().unwrap_or_default();
+            self.names.insert(index + 1, copy);
+        }
+        Ok(command.name)
+    }
+
+    fn stored_commands(&self) -> Vec<StoredCommand> {
+        self.names.iter().filter_map(|name| self.get(name)).collect()
+    }
+
+    // 持久化并刷新主界面的命令列表
+    fn save(&self, main_view: &MainView) {
+        if let Err(e) = save_commands(&self.stored_commands()) {
+            show_windows_dialog(format!("命令保存失败: {}", e), "保存失败".to_string());
+        }
+        main_view.set_commands(command_list_model(&self.names));
+    }
+
+    // 命令改名后持久化，引用原名称的规则、命令宏和定时任务一并改名
+    fn save_renamed(&self, main_view: &MainView, old: &str, new: &str) {
+        let result = save_renamed_commands(&self.stored_commands(), old, new)
+            .and_then(|_| reload_rules())
+            .and_then(|_| reload_macros())
+            .and_then(|_| refresh_schedules());
+        if let Err(e) = result {
+            show_windows_dialog(format!("命令保存失败: {}", e), "保存失败".to_string());
+        }
+        main_view.set_commands(command_list_model(&self.names));
+    }
+}
+
+/// 按名称获取命令
+pub fn stored_command(name: &str) -> Option<StoredCommand> {
+    CommandMaps::lock().get(name)
+}
+
+// 保存编辑窗口中的命令，成功后窗口转为编辑该命令
+fn save_command(window: &CommandSetWindow, main_view: Weak<MainView>) {
+    let Some(data_model) = param_rows(window) else {
+        return;
+    };
+    let rows: Vec<ParamRow> = data_model.iter().collect();
+    let delivery = Delivery {
+        qos: window.get_qos().clamp(0, 2) as u8,
+        retain: window.get_retain(),
+    };
+    let command = match validate_command_form(
+        &window.get_command_name(),
+        &window.get_topic_name(),
+        delivery,
+        &rows,
+    ) {
+        Ok(command) => command,
+        Err(errors) => {
+            show_form_errors(window, &errors);
+            return;
+        }
+    };
+    let original = window.get_original_name();
+    let original = (!original.is_empty()).then_some(original.as_str());
+    // 命令和命令宏显示在同一个列表中，名称不能重复
+    if find_macro(&command.name).is_some() {
+        let errors = FormErrors {
+            name: Some(format!("已有同名的命令宏 {}", command.name)),
+            ..FormErrors::default()
+        };
+        show_form_errors(window, &errors);
+        return;
+    }
+    let mut maps = CommandMaps::lock();
+    if let Err(e) = maps.upsert(original, command.clone()) {
+        // 输入校验已通过，这里只会是名称冲突
+        let errors = FormErrors {
+            name: Some(e.to_string()),
+            ..FormErrors::default()
+        };
+        show_form_errors(window, &errors);
+        return;
+    }
+    show_form_errors(window, &FormErrors::default());
+    match original.filter(|old| *old != command.name) {
+        Some(old) => {
+            COMMAND_STATUS.lock().unwrap().forget(old);
+            maps.save_renamed(&main_view.unwrap(), old, &command.name);
+        }
+        None => maps.save(&main_view.unwrap()),
+    }
+    window.set_original_name(SharedString::from(command.name));
+}
+
+/// 复制命令或命令宏
+pub fn duplicate_command(name: &str, main_view: &MainView) {
+    if find_macro(name).is_some() {
+        match duplicate_macro(name) {
+            Ok(()) => refresh_command_list(main_view),
+            Err(e) => show_windows_dialog(e.to_string(), "复制失败".to_string()),
+        }
+        return;
+    }
+    let mut maps = CommandMaps::lock();
+    match maps.duplicate(name) {
+        Ok(_) => maps.save(main_view),
+        Err(e) => show_windows_dialog(e.to_string(), "复制失败".to_string()),
+    }
+}
+
+/// 确认后删除命令或命令宏，仍被规则、命令宏或定时任务引用的命令不能删除
+pub fn delete_command(name: String, main_view: Weak<MainView>) {
+    let is_macro = find_macro(&name).is_some();
+    let kind = if is_macro { "命令宏" } else { "命令" };
+    if !is_macro {
+        match command_references(&name) {
+            Ok(references) if references.is_empty() => {}
+            Ok(references) => {
+                show_windows_dialog(
+                    format!("命令 {} 正被 {} 使用，请先修改或删除它们", name, references.join("、")),
+                    "无法删除".to_string(),
+                );
+                return;
+            }
+            Err(e) => {
+                show_windows_dialog(e.to_string(), "删除失败".to_string());
+                return;
+            }
+        }
+    }
+    show_confirm_dialog(
+        format!("确定删除{} {} 吗？", kind, name),
+        format!("删除{}", kind),
+        move || {
+            if is_macro {
+                if let Err(e) = remove_macro(&name) {
+                    show_windows_dialog(e.to_string(), "删除失败".to_string());
+                }
+                refresh_command_list(&main_view.unwrap());
+                return;
+            }
+            let mut maps = CommandMaps::lock();
+            if maps.remove(&name) {
+                COMMAND_STATUS.lock().unwrap().forget(&name);
+                maps.save(&main_view.unwrap());
+            }
+        },
+    );
+}
+
+/// 启动时从磁盘加载命令库并填充命令列表
+pub fn load_stored_commands(main_view: &MainView) {
+    let stored = match load_commands() {
+        Ok(stored) => stored,
+        Err(e) => {
+            show_windows_dialog(e.to_string(), "命令库加载失败".to_string());
+            return;
+        }
+    };
+    let mut maps = CommandMaps::lock();
+    for command in stored {
+        // 文件被手动修改导致重名时只保留第一个
+        if let Err(e) = maps.upsert(None, command) {
+            eprintln!("跳过命令: {}", e);
+        }
+    }
+    // 规则、命令宏和定时任务与命令保存在同一个文件中
+    if let Err(e) = reload_rules() {
+        show_windows_dialog(e.to_string(), "规则加载失败".to_string());
+    }
+    if let Err(e) = reload_macros() {
+        show_windows_dialog(e.to_string(), "命令宏加载失败".to_string());
+    }
+    if let Err(e) = reload_schedules() {
+        show_windows_dialog(e.to_string(), "定时任务加载失败".to_string());
+    }
+    main_view.set_commands(command_list_model(&maps.names));
+}
+
+// 命令宏的状态文本，例如执行中显示 "2/4 等待 5 秒"
+fn macro_status_text(name: &str) -> (&'static str, String) {
+    let status = MACRO_STATUS.lock().unwrap();
+    let Some(progress) = status.get(name) else {
+        return ("", String::new());
+    };
+    let message = match progress.state {
+        MacroState::Running | MacroState::Cancelled => {
+            format!("{}/{} {}", progress.step, progress.total, progress.message)
+                .trim_end()
+                .to_string()
+        }
+        MacroState::Done => String::new(),
+        MacroState::Failed => progress.message.clone(),
+    };
+    (progress.state.as_str(), message)
+}
+
+// 命令列表及每个命令最近一次执行的状态，命令宏排在命令之后
+fn command_list_model(command_names: &[SharedString]) -> ModelRc<CommandItem> {
+    let status = COMMAND_STATUS.lock().unwrap();
+    let items: Vec<CommandItem> = command_names
+        .iter()
+        .map(|name| {
+            let (status, message) = status
+                .latest(name.as_str())
+                .map(|(s, m)| (s.as_str(), m.clone().unwrap_or_default()))
+                .unwrap_or_default();
+            CommandItem {
+                name: name.clone(),
+                status: SharedString::from(status),
+                message: SharedString::from(message),
+                is_macro: false,
+            }
+        })
+        .chain(MACROS.lock().unwrap().iter().map(|m| {
+            let (status, message) = macro_status_text(&m.name);
+            CommandItem {
+                name: SharedString::from(m.name.as_str()),
+                status: SharedString::from(status),
+                message: SharedString::from(message),
+                is_macro: true,
+            }
+        }))
+        .collect();
+    ModelRc::from(Rc::new(VecModel::from(items)))
+}
+
+/// 刷新主界面的命令列表
+pub fn refresh_command_list(main_view: &MainView) {
+    let command_names = COMMANDS.lock().unwrap();
+    main_view.set_commands(command_list_model(&command_names));
+}
+
+// 命令参数转为编辑窗口中的一行
+fn param_to_row(param: &CommandParam) -> ParamRow {
+    ParamRow {
+        key: SharedString::from(param.key.as_str()),
+        kind: SharedString::from(param.param_type.as_str()),
+        value: SharedString::from(param.value_text()),
+        options: SharedString::from(param.options.join(",")),
+        error: SharedString::new(),
+    }
+}
+
+/// 打开命令编辑窗口
+///
+/// # 参数
+/// * `name` - 要编辑的命令名称，为 None 时新建命令
+pub fn open_command_set_window(main_view: Weak<MainView>, name: Option<String>) {
     slint::invoke_from_event_loop(move || {
         let command_set_window = CommandSetWindow::new().unwrap();
+        if let Some(command) = name.as_deref().and_then(stored_command) {
+            let rows: Vec<ParamRow> = command.values.iter().map(param_to_row).collect();
+            command_set_window.set_data(ModelRc::from(Rc::new(VecModel::from(rows))));
+            command_set_window.set_command_name(SharedString::from(command.name.as_str()));
+            command_set_window.set_topic_name(SharedString::from(command.topic));
+            command_set_window.set_qos(command.delivery.qos as i32);
+            command_set_window.set_retain(command.delivery.retain);
+            command_set_window.set_original_name(SharedString::from(command.name));
+        }
         let window = command_set_window.clone_strong();
         command_set_window.on_add_item(move || {
             add_item_to_model(window.clone_strong());
         });
-        let parent_pos = (&main_view).unwrap().window().position();
+        let window = command_set_window.as_weak();
+        command_set_window.on_remove_item(move |i| remove_item_from_model(&window.unwrap(), i));
+        let window = command_set_window.as_weak();
+        command_set_window.on_move_item(move |from, to| {
+            move_item_in_model(&window.unwrap(), from, to)
+        });
+        let parent_pos = main_view.unwrap().window().position();
         command_set_window.window().set_position(PhysicalPosition::new(parent_pos.x+520,parent_pos.y+150));
-        command_set_window.on_submit(move |d, n, t| add_command(d, n, t, main_view.clone()));
+        let window = command_set_window.as_weak();
+        command_set_window.on_submit(move |_, _, _| save_command(&window.unwrap(), main_view.clone()));
         command_set_window.show().unwrap();
     })
     .unwrap();
 }
+
+// 复制命令宏，命名规则与复制命令相同
+fn duplicate_macro(name: &str) -> Result<(), Error> {
+    let mut command_macro =
+        find_macro(name).ok_or_else(|| format_err!("命令宏 {} 不存在", name))?;
+    let maps = CommandMaps::lock();
+    command_macro.name = (1..)
+        .map(|i| match i {
+            1 => format!("{}_副本", name),
+            i => format!("{}_副本{}", name, i),
+        })
+        .find(|n| !maps.contains(n) && find_macro(n).is_none())
+        .unwrap_or_default();
+    save_macro(None, command_macro)
+}
+
+// 命令宏的一步转为编辑窗口中的一行
+fn step_to_row(step: &MacroStep) -> MacroStepRow {
+    let secs = |secs: u64| secs.min(i32::MAX as u64) as i32;
+    let mut row = MacroStepRow::default();
+    match step {
+        MacroStep::Command {
+            command,
+            wait_reply,
+        } => {
+            row.command = SharedString::from(command.as_str());
+            row.wait_reply = *wait_reply;
+        }
+        MacroStep::Delay { secs: delay } => {
+            row.kind = 1;
+            row.secs = secs(*delay);
+        }
+        MacroStep::WaitState {
+            node,
+            key,
+            op,
+            value,
+            timeout_secs,
+        } => {
+            row.kind = 2;
+            row.node = SharedString::from(node.as_str());
+            row.key = SharedString::from(key.as_str());
+            row.op = CompareOp::ALL.iter().position(|o| o == op).unwrap_or_default() as i32;
+            row.value = SharedString::from(value.as_str());
+            row.secs = secs(*timeout_secs);
+        }
+    }
+    row
+}
+
+fn row_to_step(row: &MacroStepRow) -> MacroStep {
+    let secs = row.secs.max(0) as u64;
+    match row.kind {
+        1 => MacroStep::Delay { secs },
+        2 => MacroStep::WaitState {
+            node: row.node.trim().to_string(),
+            key: row.key.trim().to_string(),
+            op: CompareOp::ALL
+                .get(row.op.max(0) as usize)
+                .copied()
+                .unwrap_or_default(),
+            value: row.value.trim().to_string(),
+            timeout_secs: secs,
+        },
+        _ => MacroStep::Command {
+            command: row.command.to_string(),
+            wait_reply: row.wait_reply,
+        },
+    }
+}
+
+// 编辑窗口中的步骤行
+fn macro_step_rows(window: &MacroWindow) -> Option<ModelRc<MacroStepRow>> {
+    let model = window.get_steps();
+    if model.as_any().downcast_ref::<VecModel<MacroStepRow>>().is_none() {
+        eprintln!("Failed to downcast to VecModel");
+        return None;
+    }
+    Some(model)
+}
+
+// 保存编辑窗口中的命令宏，成功后窗口转为编辑该命令宏
+fn submit_macro(window: &MacroWindow, main_view: &MainView) {
+    let Some(model) = macro_step_rows(window) else {
+        return;
+    };
+    let steps: Vec<MacroStep> = model.iter().map(|row| row_to_step(&row)).collect();
+    // 逐行显示校验结果
+    let mut valid = true;
+    for (i, step) in steps.iter().enumerate() {
+        let error = step.validate().err().map(|e| e.to_string()).unwrap_or_default();
+        valid &= error.is_empty();
+        if let Some(mut row) = model.row_data(i).filter(|row| row.error != error.as_str()) {
+            row.error = SharedString::from(error);
+            model.set_row_data(i, row);
+        }
+    }
+    let name = window.get_macro_name().trim().to_string();
+    // 命令和命令宏显示在同一个列表中，名称不能重复
+    if CommandMaps::lock().contains(&name) {
+        window.set_name_error(SharedString::from(format!("已有同名的命令 {}", name)));
+        return;
+    }
+    if !valid {
+        window.set_name_error(SharedString::new());
+        return;
+    }
+    let original = window.get_original_name();
+    let original = (!original.is_empty()).then_some(original.as_str());
+    if let Err(e) = save_macro(original, CommandMacro { name: name.clone(), steps }) {
+        window.set_name_error(SharedString::from(e.to_string()));
+        return;
+    }
+    window.set_name_error(SharedString::new());
+    if let Some(old) = original.filter(|old| *old != name) {
+        MACRO_STATUS.lock().unwrap().remove(old);
+    }
+    window.set_original_name(SharedString::from(name));
+    refresh_command_list(main_view);
+}
+
+/// 打开命令宏编辑窗口
+///
+/// # 参数
+/// * `name` - 要编辑的命令宏名称，为 None 时新建命令宏
+pub fn open_macro_window(main_view: Weak<MainView>, name: Option<String>) {
+    slint::invoke_from_event_loop(move || {
+        let macro_window = MacroWindow::new().unwrap();
+        let commands = COMMANDS.lock().unwrap().clone();
+        macro_window.set_commands(ModelRc::from(Rc::new(VecModel::from(commands))));
+        let rows: Vec<MacroStepRow> = match name.as_deref().and_then(find_macro) {
+            Some(command_macro) => {
+                macro_window.set_macro_name(SharedString::from(command_macro.name.as_str()));
+                macro_window.set_original_name(SharedString::from(command_macro.name.as_str()));
+                command_macro.steps.iter().map(step_to_row).collect()
+            }
+            None => Vec::new(),
+        };
+        macro_window.set_steps(ModelRc::from(Rc::new(VecModel::from(rows))));
+        let window = macro_window.as_weak();
+        macro_window.on_add_step(move || {
+            if let Some(model) = macro_step_rows(&window.unwrap())
+                && let Some(model) = model.as_any().downcast_ref::<VecModel<MacroStepRow>>()
+            {
+                model.push(MacroStepRow {
+                    secs: 5,
+                    ..MacroStepRow::default()
+                });
+            }
+        });
+        let window = macro_window.as_weak();
+        macro_window.on_remove_step(move |index| {
+            if let Some(model) = macro_step_rows(&window.unwrap())
+                && let Some(model) = model.as_any().downcast_ref::<VecModel<MacroStepRow>>()
+                && (0..model.row_count() as i32).contains(&index)
+            {
+                model.remove(index as usize);
+            }
+        });
+        let window = macro_window.as_weak();
+        macro_window.on_move_step(move |from, to| {
+            let Some(model) = macro_step_rows(&window.unwrap()) else {
+                return;
+            };
+            let Some(model) = model.as_any().downcast_ref::<VecModel<MacroStepRow>>() else {
+                return;
+            };
+            let count = model.row_count() as i32;
+            if from == to || !(0..count).contains(&from) || !(0..count).contains(&to) {
+                return;
+            }
+            let row = model.remove(from as usize);
+            model.insert(to as usize, row);
+        });
+        let parent_pos = main_view.unwrap().window().position();
+        macro_window
+            .window()
+            .set_position(PhysicalPosition::new(parent_pos.x + 520, parent_pos.y + 150));
+        let window = macro_window.as_weak();
+        macro_window.on_submit(move || submit_macro(&window.unwrap(), &main_view.unwrap()));
+        macro_window.show().unwrap();
+    })
+    .unwrap();
+}
+
+fn profile_to_data(profile: &ConnectionProfile) -> ProfileData {
+    ProfileData {
+        name: SharedString::from(profile.name.as_str()),
+        host: SharedString::from(profile.host.as_str()),
+        port: profile.port as i32,
+        username: SharedString::from(profile.username.as_str()),
+        password: SharedString::from(profile.password.as_str()),
+        client_id_prefix: SharedString::from(profile.client_id_prefix.as_str()),
+        client_id: SharedString::from(profile.client_id.as_str()),
+        keep_alive: profile.keep_alive_secs as i32,
+        state_topic: SharedString::from(profile.state_topic.as_str()),
+        pool_size: profile.pool_size as i32,
+        reply_topic: SharedString::from(profile.reply_topic.as_str()),
+        state_qos: profile.state_qos as i32,
+        reply_qos: profile.reply_qos as i32,
+        ack_timeout: profile.ack_timeout_secs as i32,
+        outbox_capacity: profile.outbox_capacity as i32,
+        outbox_ttl: profile.outbox_ttl_secs as i32,
+        history_retention: profile.history_retention_secs as i32,
+        history_max_points: profile.history_max_points as i32,
+        local_store: profile.local_store,
+        tls_enabled: profile.tls.enabled,
+        ca_file: path_to_text(&profile.tls.ca_file),
+        client_cert_file: path_to_text(&profile.tls.client_cert_file),
+        client_key_file: path_to_text(&profile.tls.client_key_file),
+        insecure_skip_verify: profile.tls.insecure_skip_verify,
+        alpn: SharedString::from(profile.tls.alpn.join(",")),
+        embedded_broker: profile.embedded_broker,
+    }
+}
+
+fn path_to_text(path: &Option<PathBuf>) -> SharedString {
+    path.as_ref()
+        .map(|p| SharedString::from(p.to_string_lossy().as_ref()))
+        .unwrap_or_default()
+}
+
+fn text_to_path(text: &SharedString) -> Option<PathBuf> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| PathBuf::from(text))
+}
+
+fn data_to_profile(data: &ProfileData) -> ConnectionProfile {
+    let name = data.name.trim().to_string();
+    // 监听配置不在界面中编辑，沿用已保存配置中的值
+    let broker_listeners = PROFILES
+        .lock()
+        .unwrap()
+        .get(&name)
+        .map(|p| p.broker_listeners.clone())
+        .unwrap_or_else(BrokerConfigure::defaults);
+    ConnectionProfile {
+        name,
+        host: data.host.trim().to_string(),
+        port: data.port.clamp(0, u16::MAX as i32) as u16,
+        username: data.username.to_string(),
+        password: data.password.to_string(),
+        client_id_prefix: data.client_id_prefix.trim().to_string(),
+        client_id: data.client_id.trim().to_string(),
+        keep_alive_secs: data.keep_alive.max(0) as u64,
+        state_topic: data.state_topic.trim().to_string(),
+        pool_size: data.pool_size.max(0) as usize,
+        reply_topic: data.reply_topic.trim().to_string(),
+        state_qos: data.state_qos.clamp(0, 2) as u8,
+        reply_qos: data.reply_qos.clamp(0, 2) as u8,
+        ack_timeout_secs: data.ack_timeout.max(1) as u64,
+        outbox_capacity: data.outbox_capacity.max(0) as usize,
+        outbox_ttl_secs: data.outbox_ttl.max(0) as u64,
+        history_retention_secs: data.history_retention.max(0) as u64,
+        history_max_points: data.history_max_points.max(0) as usize,
+        local_store: data.local_store,
+        tls: TlsSettings {
+            enabled: data.tls_enabled,
+            ca_file: text_to_path(&data.ca_file),
+            client_cert_file: text_to_path(&data.client_cert_file),
+            client_key_file: text_to_path(&data.client_key_file),
+            insecure_skip_verify: data.insecure_skip_verify,
+            alpn: data
+                .alpn
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(str::to_string)
+                .collect(),
+        },
+        embedded_broker: data.embedded_broker,
+        broker_listeners,
+    }
+}
+
+fn broker_info(profile: &ConnectionProfile) -> SharedString {
+    SharedString::from(format!("当前连接: {} ({})", profile.name, profile.server_url()))
+}
+
+/// 启动时加载连接配置并结合命令行参数得到要使用的配置
+pub fn load_profiles(args: &ProfileArgs, main_view: &MainView) -> ConnectionProfile {
+    let path = args.config_path();
+    let store = load_profiles_from(&path).unwrap_or_else(|e| {
+        show_windows_dialog(e.to_string(), "连接配置加载失败".to_string());
+        ProfileStore::default()
+    });
+    let profile = args.resolve(&store).unwrap_or_else(|e| {
+        show_windows_dialog(e.to_string(), "连接参数错误".to_string());
+        // 配置文件中当前配置本身无效时（例如连接池大小为0）使用默认配置
+        let active = store.active_profile();
+        match active.validate() {
+            Ok(()) => active,
+            Err(_) => ConnectionProfile::default(),
+        }
+    });
+    let _ = PROFILES_PATH.set(path);
+    *PROFILES.lock().unwrap() = store;
+    main_view.set_broker_info(broker_info(&profile));
+    profile
+}
+
+// 保存配置到文件，成功后返回全部配置名称
+fn save_profile(profile: ConnectionProfile, activate: bool) -> Option<Vec<SharedString>> {
+    if let Err(e) = profile.validate() {
+        show_windows_dialog(e.to_string(), "配置无效".to_string());
+        return None;
+    }
+    let mut store = PROFILES.lock().unwrap();
+    if activate {
+        store.active = profile.name.clone();
+    }
+    store.upsert(profile);
+    let path = PROFILES_PATH
+        .get()
+        .cloned()
+        .unwrap_or_else(slint_rmqtt::profile::profiles_path);
+    if let Err(e) = save_profiles_to(&path, &store) {
+        show_windows_dialog(format!("连接配置保存失败: {}", e), "保存失败".to_string());
+    }
+    Some(store.names().into_iter().map(SharedString::from).collect())
+}
+
+pub fn open_settings_window(main_view: Weak<MainView>) {
+    slint::invoke_from_event_loop(move || {
+        let settings_window = SettingsWindow::new().unwrap();
+        let (names, active) = {
+            let store = PROFILES.lock().unwrap();
+            (store.names(), store.active_profile())
+        };
+        let names: Vec<SharedString> = names.into_iter().map(SharedString::from).collect();
+        settings_window.set_profile_names(ModelRc::from(Rc::new(VecModel::from(names))));
+        settings_window.set_current_name(SharedString::from(active.name.as_str()));
+        settings_window.set_profile(profile_to_data(&active));
+        settings_window.set_local_store_available(cfg!(feature = "sqlite"));
+
+        let window = settings_window.as_weak();
+        settings_window.on_select_profile(move |name| {
+            if let Some(profile) = PROFILES.lock().unwrap().get(name.as_str()) {
+                window.unwrap().set_profile(profile_to_data(profile));
+            }
+        });
+        let window = settings_window.as_weak();
+        settings_window.on_save_profile(move |data| {
+            let profile = data_to_profile(&data);
+            let name = SharedString::from(profile.name.as_str());
+            if let Some(names) = save_profile(profile, false) {
+                let window = window.unwrap();
+                window.set_profile_names(ModelRc::from(Rc::new(VecModel::from(names))));
+                window.set_current_name(name);
+            }
+        });
+        let window = settings_window.as_weak();
+        settings_window.on_connect_profile(move |data| {
+            let profile = data_to_profile(&data);
+            if let Some(names) = save_profile(profile.clone(), true) {
+                let window = window.unwrap();
+                window.set_profile_names(ModelRc::from(Rc::new(VecModel::from(names))));
+                window.set_current_name(SharedString::from(profile.name.as_str()));
+                main_view.unwrap().set_broker_info(broker_info(&profile));
+                tokio::spawn(switch_profile(profile));
+            }
+        });
+        settings_window.show().unwrap();
+    })
+    .unwrap();
+}
+
+// 日志窗口刷新的最短间隔，消息很多时避免界面卡顿
+const MESSAGE_LOG_REFRESH_INTERVAL: Duration = Duration::from_millis(300);
+
+fn log_entry_to_item(entry: &LogEntry) -> LogItem {
+    LogItem {
+        seq: entry.seq as i32,
+        time: SharedString::from(entry.at.format("%H:%M:%S%.3f").to_string()),
+        direction: SharedString::from(entry.direction.as_str()),
+        topic: SharedString::from(entry.topic.as_str()),
+        qos: entry.qos as i32,
+        retain: entry.retain,
+        payload: SharedString::from(pretty_payload(&entry.payload)),
+    }
+}
+
+// 按窗口中的筛选条件刷新日志列表，最新的在最上面
+fn refresh_message_log(window: &MessageLogWindow) {
+    let filter = LogFilter {
+        text: window.get_filter_text().to_string(),
+        direction: match window.get_direction() {
+            1 => Some(Direction::In),
+            2 => Some(Direction::Out),
+            _ => None,
+        },
+    };
+    let (entries, total) = {
+        let log = MESSAGE_LOG.lock().unwrap();
+        (log.filtered(&filter), log.len())
+    };
+    let items: Vec<LogItem> = entries.iter().rev().map(log_entry_to_item).collect();
+    window.set_entries(ModelRc::from(Rc::new(VecModel::from(items))));
+    window.set_total(total as i32);
+}
+
+// 选择文件并导出全部日志
+fn export_message_log() {
+    let file_name = format!("mqtt_log_{}.jsonl", chrono::Local::now().format("%Y%m%d_%H%M%S"));
+    // 文件对话框会阻塞，放到单独的线程中
+    thread::spawn(move || {
+        let path = DialogBuilder::file()
+            .set_filename(file_name)
+            .add_filter("JSON Lines", ["jsonl"])
+            .save_single_file()
+            .show();
+        let path = match path {
+            Ok(Some(path)) => path,
+            Ok(None) => return,
+            Err(e) => {
+                show_windows_dialog(e.to_string(), "导出失败".to_string());
+                return;
+            }
+        };
+        let result = MESSAGE_LOG.lock().unwrap().export_to(&path);
+        if let Err(e) = result {
+            show_windows_dialog(format!("{}: {}", path.display(), e), "导出失败".to_string());
+        }
+    });
+}
+
+/// 打开消息日志窗口，有新消息时自动刷新
+pub fn open_message_log_window() {
+    fn create() -> MessageLogWindow {
+        let log_window = MessageLogWindow::new().unwrap();
+        refresh_message_log(&log_window);
+        let window = log_window.as_weak();
+        log_window.on_filter_changed(move || refresh_message_log(&window.unwrap()));
+        let window = log_window.as_weak();
+        log_window.on_clear(move || {
+            MESSAGE_LOG.lock().unwrap().clear();
+            refresh_message_log(&window.unwrap());
+        });
+        log_window.on_export(export_message_log);
+        log_window
+    }
+    slint::invoke_from_event_loop(move || {
+        let refresh = WindowRefresh {
+            throttle: MESSAGE_LOG_REFRESH_INTERVAL,
+            ..WindowRefresh::on(&MESSAGE_LOG_EVENT, refresh_message_log)
+        };
+        open_singleton_window(&MESSAGE_LOG_WINDOW, create, refresh);
+    })
+    .unwrap();
+}
+// 曲线窗口中可选的时间范围（秒），与界面中的选项顺序一致
+const CHART_WINDOWS_SECS: [u64; 6] = [60, 300, 900, 3600, 6 * 3600, 24 * 3600];
+
+// 曲线路径的坐标范围，与界面中 Path 的 viewbox 一致
+const CHART_WIDTH: f64 = 1000.0;
+const CHART_HEIGHT: f64 = 400.0;
+
+// 曲线窗口的当前选择
+#[derive(Clone, Default)]
+struct ChartSelection {
+    node: String,
+    key: String,
+    window_index: usize,
+}
+
+static CHART_SELECTION: Lazy<Mutex<ChartSelection>> = Lazy::new(|| Mutex::new(ChartSelection::default()));
+
+// 曲线窗口一次刷新的内容
+struct ChartSnapshot {
+    nodes: Vec<SharedString>,
+    node_index: i32,
+    keys: Vec<SharedString>,
+    key_index: i32,
+    commands: String,
+    min_label: String,
+    max_label: String,
+    start_label: String,
+    end_label: String,
+    latest_label: String,
+}
+
+fn state_chart_snapshot(registry: &NodeRegistry, selection: &ChartSelection) -> ChartSnapshot {
+    let nodes: Vec<String> = registry.nodes().map(|node| node.id.clone()).collect();
+    let node_index = nodes.iter().position(|n| *n == selection.node).unwrap_or(0);
+    let node = nodes.get(node_index).cloned().unwrap_or_default();
+    let history = registry.history();
+    let keys = history.numeric_keys(&node);
+    let key_index = keys.iter().position(|k| *k == selection.key).unwrap_or(0);
+    let key = keys.get(key_index).cloned().unwrap_or_default();
+    let window = CHART_WINDOWS_SECS[selection.window_index.min(CHART_WINDOWS_SECS.len() - 1)];
+    let end = chrono::Local::now();
+    let start = end - chrono::Duration::seconds(window as i64);
+    let points = history.numeric_points(&node, &key, start);
+    let path = chart_path(&points, start, end, CHART_WIDTH, CHART_HEIGHT);
+    let time_format = if window > 3600 { "%m-%d %H:%M" } else { "%H:%M:%S" };
+    ChartSnapshot {
+        nodes: nodes.into_iter().map(SharedString::from).collect(),
+        node_index: node_index as i32,
+        keys: keys.into_iter().map(SharedString::from).collect(),
+        key_index: key_index as i32,
+        min_label: path.as_ref().map(|p| p.min.to_string()).unwrap_or_default(),
+        max_label: path.as_ref().map(|p| p.max.to_string()).unwrap_or_default(),
+        commands: path.map(|p| p.commands).unwrap_or_default(),
+        start_label: start.format(time_format).to_string(),
+        end_label: end.format(time_format).to_string(),
+        latest_label: points
+            .last()
+            .map(|(at, v)| format!("最新 {}（{}），共 {} 条", v, at.format("%H:%M:%S"), points.len()))
+            .unwrap_or_default(),
+    }
+}
+
+fn apply_state_chart(window: &StateChartWindow, snapshot: ChartSnapshot) {
+    // 选项不变时不重设下拉框，避免打开的下拉列表被关闭
+    if window.get_nodes().iter().ne(snapshot.nodes.iter().cloned()) {
+        window.set_nodes(ModelRc::from(Rc::new(VecModel::from(snapshot.nodes))));
+    }
+    window.set_node_index(snapshot.node_index);
+    if window.get_keys().iter().ne(snapshot.keys.iter().cloned()) {
+        window.set_keys(ModelRc::from(Rc::new(VecModel::from(snapshot.keys))));
+    }
+    window.set_key_index(snapshot.key_index);
+    window.set_path_commands(SharedString::from(snapshot.commands));
+    window.set_min_label(SharedString::from(snapshot.min_label));
+    window.set_max_label(SharedString::from(snapshot.max_label));
+    window.set_start_label(SharedString::from(snapshot.start_label));
+    window.set_end_label(SharedString::from(snapshot.end_label));
+    window.set_latest_label(SharedString::from(snapshot.latest_label));
+}
+
+// 按当前选择刷新曲线窗口
+fn refresh_state_chart(window: &StateChartWindow) {
+    let selection = CHART_SELECTION.lock().unwrap().clone();
+    // 不在界面线程中等待节点表的锁，正被占用时留到下一次刷新
+    let Ok(registry) = NODES.try_lock() else {
+        return;
+    };
+    let snapshot = state_chart_snapshot(&registry, &selection);
+    drop(registry);
+    apply_state_chart(window, snapshot);
+}
+
+/// 打开状态曲线窗口，每秒或节点状态变化时刷新
+///
+/// # 参数
+/// * `node` - 显示的节点，为空时显示第一个节点；窗口已打开时切换到该节点
+pub fn open_state_chart_window(node: String) {
+    fn create() -> StateChartWindow {
+        let chart_window = StateChartWindow::new().unwrap();
+        let window_index = 1;
+        chart_window.set_window_index(window_index as i32);
+        *CHART_SELECTION.lock().unwrap() = ChartSelection {
+            window_index,
+            ..ChartSelection::default()
+        };
+        let window = chart_window.as_weak();
+        chart_window.on_selection_changed(move |node, key, window_index| {
+            *CHART_SELECTION.lock().unwrap() = ChartSelection {
+                node: node.to_string(),
+                key: key.to_string(),
+                window_index: window_index.max(0) as usize,
+            };
+            refresh_state_chart(&window.unwrap());
+        });
+        chart_window
+    }
+    slint::invoke_from_event_loop(move || {
+        let refresh = WindowRefresh {
+            period: Some(Duration::from_secs(1)),
+            ..WindowRefresh::on(&UPDATE_STATE_EVENT, refresh_state_chart)
+        };
+        let chart_window = open_singleton_window(&STATE_CHART_WINDOW, create, refresh);
+        if !node.is_empty() {
+            let mut selection = CHART_SELECTION.lock().unwrap();
+            selection.node = node;
+            selection.key.clear();
+        }
+        refresh_state_chart(&chart_window);
+    })
+    .unwrap();
+}
+
+fn rule_to_data(rule: &Rule) -> RuleData {
+    RuleData {
+        name: SharedString::from(rule.name.as_str()),
+        enabled: rule.enabled,
+        node: SharedString::from(rule.node.as_str()),
+        key: SharedString::from(rule.key.as_str()),
+        op: CompareOp::ALL.iter().position(|op| *op == rule.op).unwrap_or_default() as i32,
+        value: SharedString::from(rule.value.as_str()),
+        command: SharedString::from(rule.command.as_str()),
+        debounce: rule.debounce_secs.min(i32::MAX as u64) as i32,
+        cooldown: rule.cooldown_secs.min(i32::MAX as u64) as i32,
+        dry_run: rule.dry_run,
+    }
+}
+
+fn data_to_rule(data: &RuleData) -> Rule {
+    Rule {
+        name: data.name.trim().to_string(),
+        enabled: data.enabled,
+        node: data.node.trim().to_string(),
+        key: data.key.trim().to_string(),
+        op: CompareOp::ALL
+            .get(data.op.max(0) as usize)
+            .copied()
+            .unwrap_or_default(),
+        value: data.value.trim().to_string(),
+        command: data.command.to_string(),
+        debounce_secs: data.debounce.max(0) as u64,
+        cooldown_secs: data.cooldown.max(0) as u64,
+        dry_run: data.dry_run,
+    }
+}
+
+// 刷新规则列表、可选的命令和触发记录（最新的在最上面）
+fn refresh_rules_window(window: &RulesWindow) {
+    let rules: Vec<RuleData> = RULES.lock().unwrap().rules().iter().map(rule_to_data).collect();
+    window.set_rules(ModelRc::from(Rc::new(VecModel::from(rules))));
+    let commands = COMMANDS.lock().unwrap().clone();
+    window.set_commands(ModelRc::from(Rc::new(VecModel::from(commands))));
+    let activity: Vec<RuleActivityItem> = RULE_ACTIVITY
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|a| RuleActivityItem {
+            time: SharedString::from(a.at.format("%m-%d %H:%M:%S").to_string()),
+            rule: SharedString::from(a.rule.as_str()),
+            node: SharedString::from(a.node.as_str()),
+            command: SharedString::from(a.command.as_str()),
+            dry_run: a.dry_run,
+            result: SharedString::from(a.result.as_str()),
+        })
+        .collect();
+    window.set_activity(ModelRc::from(Rc::new(VecModel::from(activity))));
+}
+
+// 编辑区显示新的空白规则
+fn edit_new_rule(window: &RulesWindow) {
+    window.set_current(rule_to_data(&Rule::default()));
+    window.set_original_name(SharedString::new());
+    window.set_error(SharedString::new());
+}
+
+/// 打开自动规则窗口，有新的触发记录时自动刷新
+pub fn open_rules_window() {
+    fn create() -> RulesWindow {
+        let rules_window = RulesWindow::new().unwrap();
+        refresh_rules_window(&rules_window);
+        edit_new_rule(&rules_window);
+        let window = rules_window.as_weak();
+        rules_window.on_new_rule(move || edit_new_rule(&window.unwrap()));
+        let window = rules_window.as_weak();
+        rules_window.on_select_rule(move |name| {
+            let window = window.unwrap();
+            let rule = RULES
+                .lock()
+                .unwrap()
+                .rules()
+                .iter()
+                .find(|r| r.name == name.as_str())
+                .cloned();
+            if let Some(rule) = rule {
+                window.set_current(rule_to_data(&rule));
+                window.set_original_name(name);
+                window.set_error(SharedString::new());
+            }
+        });
+        let window = rules_window.as_weak();
+        rules_window.on_save_rule(move |data| {
+            let window = window.unwrap();
+            let rule = data_to_rule(&data);
+            let name = SharedString::from(rule.name.as_str());
+            let original = window.get_original_name();
+            let original = (!original.is_empty()).then_some(original.as_str());
+            match save_rule(original, rule) {
+                Ok(()) => {
+                    window.set_original_name(name);
+                    window.set_error(SharedString::new());
+                    refresh_rules_window(&window);
+                }
+                Err(e) => window.set_error(SharedString::from(e.to_string())),
+            }
+        });
+        let window = rules_window.as_weak();
+        rules_window.on_toggle_rule(move |name, enabled| {
+            let window = window.unwrap();
+            if let Err(e) = set_rule_enabled(name.as_str(), enabled) {
+                window.set_error(SharedString::from(e.to_string()));
+            }
+            // 编辑区正在显示该规则时同步勾选状态
+            if window.get_original_name() == name {
+                let mut current = window.get_current();
+                current.enabled = enabled;
+                window.set_current(current);
+            }
+            refresh_rules_window(&window);
+        });
+        let window = rules_window.as_weak();
+        rules_window.on_delete_rule(move |name| {
+            let window = window.clone();
+            show_confirm_dialog(
+                format!("确定删除规则 {} 吗？", name),
+                "删除规则".to_string(),
+                move || {
+                    let window = window.unwrap();
+                    match remove_rule(name.as_str()) {
+                        Ok(()) => edit_new_rule(&window),
+                        Err(e) => window.set_error(SharedString::from(e.to_string())),
+                    }
+                    refresh_rules_window(&window);
+                },
+            );
+        });
+        rules_window
+    }
+    slint::invoke_from_event_loop(move || {
+        open_singleton_window(
+            &RULES_WINDOW,
+            create,
+            WindowRefresh::on(&RULE_ACTIVITY_EVENT, refresh_rules_window),
+        );
+    })
+    .unwrap();
+}
+
+// 定时任务窗口中一次性任务的时间格式
+const SCHEDULE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+fn schedule_to_data(schedule: &Schedule) -> ScheduleData {
+    // 编辑区显示所有方式的输入框，其它方式使用默认值
+    let mut data = ScheduleData {
+        name: SharedString::from(schedule.name.as_str()),
+        enabled: schedule.enabled,
+        command: SharedString::from(schedule.command.as_str()),
+        kind: 0,
+        at: SharedString::from(
+            (chrono::Local::now() + chrono::Duration::hours(1))
+                .format(SCHEDULE_TIME_FORMAT)
+                .to_string(),
+        ),
+        interval: 60,
+        cron: SharedString::from("0 9 * * *"),
+        missed: MissedPolicy::ALL
+            .iter()
+            .position(|m| *m == schedule.missed)
+            .unwrap_or_default() as i32,
+    };
+    match &schedule.kind {
+        ScheduleKind::Once { at } => {
+            if let Some(at) = local_time(*at) {
+                data.at = SharedString::from(at.format(SCHEDULE_TIME_FORMAT).to_string());
+            }
+        }
+        ScheduleKind::Interval { secs } => {
+            data.kind = 1;
+            data.interval = (*secs).min(i32::MAX as u64) as i32;
+        }
+        ScheduleKind::Cron { expr } => {
+            data.kind = 2;
+            data.cron = SharedString::from(expr.as_str());
+        }
+    }
+    data
+}
+
+fn data_to_schedule(data: &ScheduleData) -> Result<Schedule, Error> {
+    let kind = match data.kind {
+        0 => {
+            let at = chrono::NaiveDateTime::parse_from_str(data.at.trim(), SCHEDULE_TIME_FORMAT)
+                .ok()
+                .and_then(|at| at.and_local_timezone(chrono::Local).single())
+                .ok_or_else(|| format_err!("时间格式应为 2026-01-01 09:00"))?;
+            ScheduleKind::Once { at: at.timestamp() }
+        }
+        1 => ScheduleKind::Interval {
+            secs: data.interval.max(0) as u64,
+        },
+        _ => ScheduleKind::Cron {
+            expr: data.cron.split_whitespace().collect::<Vec<_>>().join(" "),
+        },
+    };
+    Ok(Schedule {
+        name: data.name.trim().to_string(),
+        enabled: data.enabled,
+        command: data.command.to_string(),
+        kind,
+        missed: MissedPolicy::ALL
+            .get(data.missed.max(0) as usize)
+            .copied()
+            .unwrap_or_default(),
+    })
+}
+
+// 刷新任务列表（含下次、上次执行时间和结果）及可选的命令
+fn refresh_schedule_window(window: &ScheduleWindow) {
+    let time = |t: Option<i64>| {
+        SharedString::from(
+            t.and_then(local_time)
+                .map(|t| t.format("%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default(),
+        )
+    };
+    let scheduler = SCHEDULER.lock().unwrap();
+    let items: Vec<ScheduleItem> = scheduler
+        .schedules()
+        .iter()
+        .map(|schedule| {
+            let run = scheduler.run(&schedule.name).cloned().unwrap_or_default();
+            ScheduleItem {
+                name: SharedString::from(schedule.name.as_str()),
+                enabled: schedule.enabled,
+                description: SharedString::from(schedule.kind.describe()),
+                command: SharedString::from(schedule.command.as_str()),
+                next_run: time(run.next_run),
+                last_run: time(run.last_run),
+                result: SharedString::from(run.last_result),
+            }
+        })
+        .collect();
+    window.set_schedules(ModelRc::from(Rc::new(VecModel::from(items))));
+    let commands = COMMANDS.lock().unwrap().clone();
+    window.set_commands(ModelRc::from(Rc::new(VecModel::from(commands))));
+}
+
+// 编辑区显示新的空白任务
+fn edit_new_schedule(window: &ScheduleWindow) {
+    window.set_current(schedule_to_data(&Schedule::default()));
+    window.set_original_name(SharedString::new());
+    window.set_error(SharedString::new());
+}
+
+/// 打开定时任务窗口，执行时间和结果变化时自动刷新
+pub fn open_schedule_window() {
+    fn create() -> ScheduleWindow {
+        let schedule_window = ScheduleWindow::new().unwrap();
+        refresh_schedule_window(&schedule_window);
+        edit_new_schedule(&schedule_window);
+        let window = schedule_window.as_weak();
+        schedule_window.on_new_schedule(move || edit_new_schedule(&window.unwrap()));
+        let window = schedule_window.as_weak();
+        schedule_window.on_select_schedule(move |name| {
+            let window = window.unwrap();
+            let schedule = SCHEDULER
+                .lock()
+                .unwrap()
+                .schedules()
+                .iter()
+                .find(|s| s.name == name.as_str())
+                .cloned();
+            if let Some(schedule) = schedule {
+                window.set_current(schedule_to_data(&schedule));
+                window.set_original_name(name);
+                window.set_error(SharedString::new());
+            }
+        });
+        let window = schedule_window.as_weak();
+        schedule_window.on_save_schedule(move |data| {
+            let window = window.unwrap();
+            let original = window.get_original_name();
+            let original = (!original.is_empty()).then_some(original.as_str());
+            let result = data_to_schedule(&data).and_then(|schedule| {
+                let name = SharedString::from(schedule.name.as_str());
+                save_schedule(original, schedule).map(|_| name)
+            });
+            match result {
+                Ok(name) => {
+                    window.set_original_name(name);
+                    window.set_error(SharedString::new());
+                    refresh_schedule_window(&window);
+                }
+                Err(e) => window.set_error(SharedString::from(e.to_string())),
+            }
+        });
+        let window = schedule_window.as_weak();
+        schedule_window.on_toggle_schedule(move |name, enabled| {
+            let window = window.unwrap();
+            if let Err(e) = set_schedule_enabled(name.as_str(), enabled) {
+                window.set_error(SharedString::from(e.to_string()));
+            }
+            // 编辑区正在显示该任务时同步勾选状态
+            if window.get_original_name() == name {
+                let mut current = window.get_current();
+                current.enabled = enabled;
+                window.set_current(current);
+            }
+            refresh_schedule_window(&window);
+        });
+        let window = schedule_window.as_weak();
+        schedule_window.on_delete_schedule(move |name| {
+            let window = window.clone();
+            show_confirm_dialog(
+                format!("确定删除定时任务 {} 吗？", name),
+                "删除定时任务".to_string(),
+                move || {
+                    let window = window.unwrap();
+                    match remove_schedule(name.as_str()) {
+                        Ok(()) => edit_new_schedule(&window),
+                        Err(e) => window.set_error(SharedString::from(e.to_string())),
+                    }
+                    refresh_schedule_window(&window);
+                },
+            );
+        });
+        schedule_window
+    }
+    slint::invoke_from_event_loop(move || {
+        open_singleton_window(
+            &SCHEDULE_WINDOW,
+            create,
+            WindowRefresh::on(&SCHEDULE_EVENT, refresh_schedule_window),
+        );
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_command_maps_crud() {
+    let command = |name: &str| StoredCommand {
+        name: name.to_string(),
+        topic: "node/1".to_string(),
+        values: vec![],
+        delivery: Delivery::default(),
+    };
+    let mut maps = CommandMaps::lock();
+    maps.upsert(None, command("fan")).unwrap();
+    maps.upsert(None, command("pump")).unwrap();
+    // 新建和改名都不能与已有命令重名
+    assert!(maps.upsert(None, command("fan")).is_err());
+    assert!(maps.upsert(Some("pump"), command("fan")).is_err());
+    // 原名保存视为修改
+    maps.upsert(Some("fan"), command("fan")).unwrap();
+
+    assert_eq!(maps.duplicate("fan").unwrap(), "fan_副本");
+    assert_eq!(maps.duplicate("fan").unwrap(), "fan_副本2");
+    maps.upsert(Some("fan_副本"), command("heater")).unwrap();
+    assert!(maps.get("fan_副本").is_none());
+    assert_eq!(maps.names.as_slice(), ["fan", "fan_副本2", "heater", "pump"]);
+
+    assert!(maps.remove("fan_副本2"));
+    assert!(!maps.remove("fan_副本2"));
+    assert_eq!(maps.names.as_slice(), ["fan", "heater", "pump"]);
+    assert_eq!(maps.n2d.len(), 3);
+    assert_eq!(maps.n2t.len(), 3);
+    assert_eq!(maps.n2q.len(), 3);
+}
+
+#[test]
+fn test_validate_command_form() {
+    let row = |key: &str, kind: &str, value: &str| ParamRow {
+        key: SharedString::from(key),
+        kind: SharedString::from(kind),
+        value: SharedString::from(value),
+        ..ParamRow::default()
+    };
+    let rows = [
+        row("speed", "float", "75.5"),
+        row("", "int", "1"),
+        row("speed", "int", "2"),
+        row("level", "int", "high"),
+    ];
+    let errors = validate_command_form(" ", "node/+", Delivery::default(), &rows).unwrap_err();
+    assert!(errors.name.is_some());
+    assert!(errors.topic.is_some());
+    assert!(errors.rows[0].is_none());
+    assert!(errors.rows[1].as_deref().unwrap().contains("不能为空"));
+    assert!(errors.rows[2].as_deref().unwrap().contains("重复"));
+    assert!(errors.rows[3].is_some());
+
+    let delivery = Delivery { qos: 2, retain: true };
+    let command = validate_command_form(" fan ", "node/1", delivery, &rows[..1]).unwrap();
+    assert_eq!(command.delivery, delivery);
+    assert_eq!(command.name, "fan");
+    assert_eq!(command.values.len(), 1);
+}
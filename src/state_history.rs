@@ -0,0 +1,206 @@
+use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+// 状态历史的保留策略
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryRetention {
+    // 超过该时间的记录会被丢弃
+    pub max_age: Duration,
+    // 每个节点的每个状态项最多保留的条数
+    pub max_points: usize,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention {
+            max_age: Duration::from_secs(3600),
+            max_points: 2000,
+        }
+    }
+}
+
+// 状态项的一次取值
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatePoint {
+    pub at: DateTime<Local>,
+    pub value: String,
+}
+
+// 按节点、状态项保存的历史取值，超出保留策略的记录自动丢弃
+#[derive(Default)]
+pub struct StateHistory {
+    retention: HistoryRetention,
+    // 节点ID -> 状态项 -> 按时间先后的取值
+    series: BTreeMap<String, BTreeMap<String, VecDeque<StatePoint>>>,
+}
+
+// 丢弃超出保留策略的记录
+fn prune(points: &mut VecDeque<StatePoint>, retention: &HistoryRetention, now: DateTime<Local>) {
+    while points.len() > retention.max_points {
+        points.pop_front();
+    }
+    let max_age = chrono::Duration::from_std(retention.max_age).unwrap_or(chrono::Duration::MAX);
+    while points.front().is_some_and(|p| now - p.at > max_age) {
+        points.pop_front();
+    }
+}
+
+impl StateHistory {
+    pub fn retention(&self) -> &HistoryRetention {
+        &self.retention
+    }
+
+    /// 修改保留策略，已有的记录立即按新策略清理
+    pub fn set_retention(&mut self, retention: HistoryRetention) {
+        self.retention = retention;
+        let now = Local::now();
+        for keys in self.series.values_mut() {
+            for points in keys.values_mut() {
+                prune(points, &self.retention, now);
+            }
+        }
+    }
+
+    /// 记录一次状态取值
+    ///
+    /// # 参数
+    /// * `node` - 节点ID
+    /// * `key` - 状态项
+    /// * `value` - 取值
+    /// * `at` - 取值时间
+    pub fn record(&mut self, node: &str, key: &str, value: &str, at: DateTime<Local>) {
+        let points = self
+            .series
+            .entry(node.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_default();
+        points.push_back(StatePoint {
+            at,
+            value: value.to_string(),
+        });
+        prune(points, &self.retention, at);
+    }
+
+    /// 某个状态项按时间先后的全部取值
+    pub fn points(&self, node: &str, key: &str) -> impl Iterator<Item = &StatePoint> {
+        self.series
+            .get(node)
+            .and_then(|keys| keys.get(key))
+            .into_iter()
+            .flatten()
+    }
+
+    /// 某个状态项在 `since` 之后的数值取值，不是数字的取值会被跳过
+    pub fn numeric_points(&self, node: &str, key: &str, since: DateTime<Local>) -> Vec<(DateTime<Local>, f64)> {
+        self.points(node, key)
+            .filter(|p| p.at >= since)
+            .filter_map(|p| p.value.trim().parse::<f64>().ok().map(|v| (p.at, v)))
+            .filter(|(_, v)| v.is_finite())
+            .collect()
+    }
+
+    /// 节点中最近一次取值是数字的状态项，可以绘制曲线
+    pub fn numeric_keys(&self, node: &str) -> Vec<String> {
+        self.series
+            .get(node)
+            .into_iter()
+            .flatten()
+            .filter(|(_, points)| {
+                points
+                    .back()
+                    .is_some_and(|p| p.value.trim().parse::<f64>().is_ok())
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.series.clear();
+    }
+}
+
+// 曲线的绘制结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChartPath {
+    // SVG 路径命令，坐标范围为 0..width、0..height，y 轴向下
+    pub commands: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// 把数值取值转换为折线，横轴为 `start..end` 的时间，纵轴按最小值、最大值缩放
+///
+/// # 返回值
+/// 没有取值时返回 None；所有取值相同时画在中间
+pub fn chart_path(
+    points: &[(DateTime<Local>, f64)],
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    width: f64,
+    height: f64,
+) -> Option<ChartPath> {
+    if points.is_empty() {
+        return None;
+    }
+    let min = points.iter().map(|(_, v)| *v).fold(f64::INFINITY, f64::min);
+    let max = points.iter().map(|(_, v)| *v).fold(f64::NEG_INFINITY, f64::max);
+    let span = (end - start).num_milliseconds().max(1) as f64;
+    let x = |at: DateTime<Local>| ((at - start).num_milliseconds() as f64 / span * width).clamp(0.0, width);
+    let y = |v: f64| {
+        if max > min {
+            height - (v - min) / (max - min) * height
+        } else {
+            height / 2.0
+        }
+    };
+    let mut commands = String::new();
+    for (i, (at, v)) in points.iter().enumerate() {
+        let command = if i == 0 { "M" } else { "L" };
+        commands.push_str(&format!("{} {:.1} {:.1} ", command, x(*at), y(*v)));
+    }
+    // 最后一个取值一直保持到当前时间
+    if let Some((_, v)) = points.last() {
+        commands.push_str(&format!("L {:.1} {:.1}", width, y(*v)));
+    }
+    Some(ChartPath {
+        commands: commands.trim_end().to_string(),
+        min,
+        max,
+    })
+}
+
+#[test]
+fn test_state_history_retention_and_chart() {
+    let start = Local::now();
+    let at = |secs: i64| start + chrono::Duration::seconds(secs);
+    let mut history = StateHistory::default();
+    history.set_retention(HistoryRetention {
+        max_age: Duration::from_secs(60),
+        max_points: 3,
+    });
+    history.record("n1", "temp", "20", at(0));
+    history.record("n1", "temp", "bad", at(10));
+    history.record("n1", "temp", "22", at(20));
+    history.record("n1", "temp", "24", at(30));
+    history.record("n1", "mode", "auto", at(30));
+    // 超过条数上限丢弃最早的
+    assert_eq!(history.points("n1", "temp").count(), 3);
+    assert_eq!(history.numeric_points("n1", "temp", at(0)).len(), 2);
+    assert_eq!(history.numeric_keys("n1"), ["temp"]);
+    // 超过保留时间丢弃
+    history.record("n1", "temp", "26", at(85));
+    let values: Vec<f64> = history
+        .numeric_points("n1", "temp", start)
+        .into_iter()
+        .map(|(_, v)| v)
+        .collect();
+    assert_eq!(values, [24.0, 26.0]);
+
+    let points = history.numeric_points("n1", "temp", start);
+    let path = chart_path(&points, at(25), at(125), 100.0, 10.0).unwrap();
+    assert_eq!(path.commands, "M 5.0 10.0 L 60.0 0.0 L 100.0 0.0");
+    assert_eq!((path.min, path.max), (24.0, 26.0));
+    assert!(chart_path(&[], start, at(1), 100.0, 10.0).is_none());
+}
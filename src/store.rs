@@ -0,0 +1,587 @@
+use crate::app_dir::app_config_dir;
+use crate::node_registry::NodeInfo;
+use crate::state_history::StatePoint;
+use anyhow::{Error, format_err};
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+
+const STORE_FILE_NAME: &str = "slint_rmqtt.db";
+
+// 写入状态时删除过期历史的最小间隔
+#[cfg(feature = "sqlite")]
+const PRUNE_INTERVAL: chrono::Duration = chrono::Duration::minutes(1);
+
+/// 数据库结构的迁移脚本，按顺序执行，已执行到的版本记录在 `PRAGMA user_version`
+///
+/// * 1: 节点、状态历史和命令记录
+#[cfg(feature = "sqlite")]
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE nodes (
+        id TEXT PRIMARY KEY,
+        position_type INTEGER NOT NULL,
+        position TEXT NOT NULL,
+        state TEXT NOT NULL,
+        registered_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE state_history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        node TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT NOT NULL,
+        at INTEGER NOT NULL
+    );
+    CREATE INDEX state_history_node_key_at ON state_history (node, key, at);
+    CREATE TABLE command_audit (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        sender TEXT NOT NULL,
+        topic TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        qos INTEGER NOT NULL,
+        retain INTEGER NOT NULL,
+        sent_at INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        message TEXT,
+        finished_at INTEGER
+    );
+    CREATE INDEX command_audit_sent_at ON command_audit (sent_at);
+"];
+
+/// 默认的数据库文件路径
+pub fn store_path() -> PathBuf {
+    app_config_dir().join(STORE_FILE_NAME)
+}
+
+// 数据库中的时间为 Unix 毫秒时间戳
+#[cfg(feature = "sqlite")]
+fn to_millis(at: DateTime<Local>) -> i64 {
+    at.timestamp_millis()
+}
+
+#[cfg(feature = "sqlite")]
+fn from_millis(ms: i64) -> DateTime<Local> {
+    use chrono::TimeZone;
+    Local.timestamp_millis_opt(ms).single().unwrap_or_else(Local::now)
+}
+
+// 一条状态历史：(节点ID, 状态项, 取值)
+pub type StateRecord = (String, String, StatePoint);
+
+// 一条命令发送记录
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandAudit {
+    // 关联ID
+    pub id: String,
+    pub name: String,
+    // 发送者：系统用户名和客户端ID
+    pub sender: String,
+    pub topic: String,
+    pub payload: String,
+    pub qos: u8,
+    pub retain: bool,
+    pub sent_at: DateTime<Local>,
+    // 与 `CommandStatus::as_str` 相同
+    pub status: String,
+    pub message: Option<String>,
+    // 收到回复、失败或超时的时间
+    pub finished_at: Option<DateTime<Local>>,
+}
+
+impl CommandAudit {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "sender": self.sender,
+            "topic": self.topic,
+            "payload": self.payload,
+            "qos": self.qos,
+            "retain": self.retain,
+            "sent_at": self.sent_at.to_rfc3339(),
+            "status": self.status,
+            "message": self.message,
+            "finished_at": self.finished_at.map(|t| t.to_rfc3339()),
+        })
+    }
+}
+
+// 命令记录的查询条件
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    // 只查询该命令
+    pub name: Option<String>,
+    // 只查询该时间之后发送的
+    pub since: Option<DateTime<Local>>,
+    // 最多返回的条数，0 表示不限
+    pub limit: usize,
+}
+
+/// 本地 SQLite 数据库，保存节点、状态历史和命令记录
+#[cfg(feature = "sqlite")]
+pub struct Store {
+    conn: rusqlite::Connection,
+    // 状态历史的保留时间，None 表示不自动删除
+    retention: Option<chrono::Duration>,
+    // 上次删除过期历史的时间
+    last_prune: std::cell::Cell<Option<DateTime<Local>>>,
+}
+
+#[cfg(feature = "sqlite")]
+impl Store {
+    /// 打开数据库文件，不存在时创建，并执行未执行过的迁移
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = rusqlite::Connection::open(path)?;
+        // 图形界面和命令行客户端可能同时写入
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::with_connection(conn)
+    }
+
+    /// 打开内存数据库，用于测试
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: rusqlite::Connection) -> Result<Self, Error> {
+        let mut store = Store {
+            conn,
+            retention: None,
+            last_prune: std::cell::Cell::new(None),
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    /// 当前数据库结构版本
+    pub fn schema_version(&self) -> Result<usize, Error> {
+        let version: i64 = self.conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    fn migrate(&mut self) -> Result<(), Error> {
+        let version = self.schema_version()?;
+        if version > MIGRATIONS.len() {
+            return Err(format_err!(
+                "数据库版本 {} 比当前程序支持的 {} 新",
+                version,
+                MIGRATIONS.len()
+            ));
+        }
+        let tx = self.conn.transaction()?;
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            tx.execute_batch(sql)
+                .map_err(|e| format_err!("数据库迁移到版本 {} 失败: {}", i + 1, e))?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len() as i64)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 保存节点当前的注册信息和状态，已存在时覆盖
+    pub fn save_node(&self, node: &NodeInfo) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO nodes (id, position_type, position, state, registered_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                node.id,
+                node.position_type,
+                serde_json::to_string(&node.position)?,
+                serde_json::to_string(&node.state)?,
+                to_millis(node.registered_at),
+                to_millis(node.updated_at),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 按ID排序的全部节点
+    pub fn load_nodes(&self) -> Result<Vec<NodeInfo>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, position_type, position, state, registered_at, updated_at FROM nodes ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })?;
+        let mut nodes = Vec::new();
+        for row in rows {
+            let (id, position_type, position, state, registered_at, updated_at) = row?;
+            nodes.push(NodeInfo {
+                id,
+                position_type,
+                position: serde_json::from_str(&position)?,
+                state: serde_json::from_str(&state)?,
+                registered_at: from_millis(registered_at),
+                updated_at: from_millis(updated_at),
+            });
+        }
+        Ok(nodes)
+    }
+
+    /// 记录一次状态取值
+    pub fn record_state(&self, node: &str, key: &str, value: &str, at: DateTime<Local>) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO state_history (node, key, value, at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![node, key, value, to_millis(at)],
+        )?;
+        Ok(())
+    }
+
+    /// 查询状态历史，按时间先后排序
+    ///
+    /// # 参数
+    /// * `node` - 节点ID，None 表示全部节点
+    /// * `key` - 状态项，None 表示全部状态项
+    /// * `since` - 只查询该时间之后的记录
+    pub fn state_history(
+        &self,
+        node: Option<&str>,
+        key: Option<&str>,
+        since: DateTime<Local>,
+    ) -> Result<Vec<StateRecord>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT node, key, value, at FROM state_history
+             WHERE (?1 IS NULL OR node = ?1) AND (?2 IS NULL OR key = ?2) AND at >= ?3
+             ORDER BY at, id",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![node, key, to_millis(since)], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                StatePoint {
+                    value: row.get(2)?,
+                    at: from_millis(row.get(3)?),
+                },
+            ))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// 删除早于 `before` 的状态历史
+    ///
+    /// # 返回值
+    /// 删除的条数
+    pub fn prune_state_history(&self, before: DateTime<Local>) -> Result<usize, Error> {
+        Ok(self.conn.execute(
+            "DELETE FROM state_history WHERE at < ?1",
+            rusqlite::params![to_millis(before)],
+        )?)
+    }
+
+    /// 设置状态历史的保留时间，之后由 `prune_expired` 定期删除更早的历史
+    pub fn set_history_retention(&mut self, retention: std::time::Duration) {
+        self.retention = Some(chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX));
+    }
+
+    /// 删除超过保留时间的状态历史，距上次删除不足一分钟时不执行
+    ///
+    /// # 返回值
+    /// 删除的条数
+    pub fn prune_expired(&self, now: DateTime<Local>) -> Result<usize, Error> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        if self.last_prune.get().is_some_and(|last| now - last < PRUNE_INTERVAL) {
+            return Ok(0);
+        }
+        self.last_prune.set(Some(now));
+        match now.checked_sub_signed(retention) {
+            Some(before) => self.prune_state_history(before),
+            None => Ok(0),
+        }
+    }
+
+    /// 记录一条已发送的命令
+    pub fn record_command(&self, audit: &CommandAudit) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO command_audit
+             (id, name, sender, topic, payload, qos, retain, sent_at, status, message, finished_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            rusqlite::params![
+                audit.id,
+                audit.name,
+                audit.sender,
+                audit.topic,
+                audit.payload,
+                audit.qos,
+                audit.retain,
+                to_millis(audit.sent_at),
+                audit.status,
+                audit.message,
+                audit.finished_at.map(to_millis),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 删除一条命令记录
+    pub fn remove_command(&self, id: &str) -> Result<(), Error> {
+        self.conn
+            .execute("DELETE FROM command_audit WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    /// 更新命令的结果（回复、失败或超时）
+    pub fn finish_command(
+        &self,
+        id: &str,
+        status: &str,
+        message: Option<&str>,
+        at: DateTime<Local>,
+    ) -> Result<(), Error> {
+        self.conn.execute(
+            "UPDATE command_audit SET status = ?2, message = ?3, finished_at = ?4 WHERE id = ?1",
+            rusqlite::params![id, status, message, to_millis(at)],
+        )?;
+        Ok(())
+    }
+
+    /// 查询命令记录，最新发送的在前
+    pub fn commands(&self, query: &AuditQuery) -> Result<Vec<CommandAudit>, Error> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, sender, topic, payload, qos, retain, sent_at, status, message, finished_at
+             FROM command_audit
+             WHERE (?1 IS NULL OR name = ?1) AND (?2 IS NULL OR sent_at >= ?2)
+             ORDER BY sent_at DESC, rowid DESC
+             LIMIT ?3",
+        )?;
+        let limit = if query.limit == 0 { -1 } else { query.limit as i64 };
+        let rows = stmt.query_map(
+            rusqlite::params![query.name, query.since.map(to_millis), limit],
+            |row| {
+                Ok(CommandAudit {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    sender: row.get(2)?,
+                    topic: row.get(3)?,
+                    payload: row.get(4)?,
+                    qos: row.get(5)?,
+                    retain: row.get(6)?,
+                    sent_at: from_millis(row.get(7)?),
+                    status: row.get(8)?,
+                    message: row.get(9)?,
+                    finished_at: row.get::<_, Option<i64>>(10)?.map(from_millis),
+                })
+            },
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// 当前打开的数据库，未启用本地数据库时为 None
+#[cfg(feature = "sqlite")]
+static STORE: once_cell::sync::Lazy<std::sync::Mutex<Option<Store>>> =
+    once_cell::sync::Lazy::new(|| std::sync::Mutex::new(None));
+
+#[cfg(feature = "sqlite")]
+fn with_store<T>(f: impl FnOnce(&Store) -> Result<T, Error>) -> Result<T, Error> {
+    let store = STORE.lock().map_err(|_| format_err!("读取本地数据库失败"))?;
+    f(store.as_ref().ok_or_else(|| format_err!("本地数据库未打开"))?)
+}
+
+// 写入失败不影响收发消息，只输出错误
+#[cfg(feature = "sqlite")]
+fn persist(f: impl FnOnce(&Store) -> Result<(), Error>) {
+    if let Ok(store) = STORE.lock()
+        && let Some(store) = store.as_ref()
+        && let Err(e) = f(store)
+    {
+        eprintln!("本地数据库写入失败: {}", e);
+    }
+}
+
+/// 打开本地数据库，之后节点、状态变化和发送的命令都会写入
+///
+/// # 参数
+/// * `path` - 数据库文件路径
+/// * `retention` - 状态历史的保留时间，写入状态时定期删除更早的历史
+#[cfg(feature = "sqlite")]
+pub fn open_store(path: &Path, retention: std::time::Duration) -> Result<(), Error> {
+    let mut store = Store::open(path)?;
+    store.set_history_retention(retention);
+    *STORE.lock().map_err(|_| format_err!("读取本地数据库失败"))? = Some(store);
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn open_store(_path: &Path, _retention: std::time::Duration) -> Result<(), Error> {
+    Err(format_err!("当前版本编译时未启用 sqlite 功能，无法使用本地数据库"))
+}
+
+/// 关闭本地数据库，之后不再写入
+pub fn close_store() {
+    #[cfg(feature = "sqlite")]
+    if let Ok(mut store) = STORE.lock() {
+        *store = None;
+    }
+}
+
+/// 保存节点，并记录本次变化的状态取值
+///
+/// # 参数
+/// * `node` - 更新后的节点
+/// * `changed` - 本次变化的状态项
+pub fn persist_node(node: &NodeInfo, changed: &[String]) {
+    #[cfg(feature = "sqlite")]
+    persist(|store| {
+        store.save_node(node)?;
+        for key in changed {
+            if let Some(value) = node.state.get(key) {
+                store.record_state(&node.id, key, value, node.updated_at)?;
+            }
+        }
+        store.prune_expired(node.updated_at)?;
+        Ok(())
+    });
+    #[cfg(not(feature = "sqlite"))]
+    let _ = (node, changed);
+}
+
+/// 记录一条已发送的命令
+pub fn persist_command(audit: &CommandAudit) {
+    #[cfg(feature = "sqlite")]
+    persist(|store| store.record_command(audit));
+    #[cfg(not(feature = "sqlite"))]
+    let _ = audit;
+}
+
+/// 删除一条命令记录，用于发送失败后回到队列、之后会重新记录的命令
+pub fn discard_command(id: &str) {
+    #[cfg(feature = "sqlite")]
+    persist(|store| store.remove_command(id));
+    #[cfg(not(feature = "sqlite"))]
+    let _ = id;
+}
+
+/// 记录命令的结果
+pub fn persist_command_outcome(id: &str, status: &str, message: Option<&str>) {
+    #[cfg(feature = "sqlite")]
+    persist(|store| store.finish_command(id, status, message, Local::now()));
+    #[cfg(not(feature = "sqlite"))]
+    let _ = (id, status, message);
+}
+
+/// 读取保存的节点和 `since` 之后的状态历史，早于 `since` 的历史会被删除
+#[cfg(feature = "sqlite")]
+pub fn restore_nodes(since: DateTime<Local>) -> Result<(Vec<NodeInfo>, Vec<StateRecord>), Error> {
+    with_store(|store| {
+        store.prune_state_history(since)?;
+        Ok((store.load_nodes()?, store.state_history(None, None, since)?))
+    })
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn restore_nodes(_since: DateTime<Local>) -> Result<(Vec<NodeInfo>, Vec<StateRecord>), Error> {
+    Err(format_err!("当前版本编译时未启用 sqlite 功能，无法使用本地数据库"))
+}
+
+/// 查询命令记录，最新发送的在前；数据库未打开时打开默认位置的数据库文件
+#[cfg(feature = "sqlite")]
+pub fn query_commands(query: &AuditQuery) -> Result<Vec<CommandAudit>, Error> {
+    with_store(|store| store.commands(query)).or_else(|_| Store::open(&store_path())?.commands(query))
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn query_commands(_query: &AuditQuery) -> Result<Vec<CommandAudit>, Error> {
+    Err(format_err!("当前版本编译时未启用 sqlite 功能，无法使用本地数据库"))
+}
+
+/// 查询某个节点的状态历史，按时间先后排序；数据库未打开时打开默认位置的数据库文件
+#[cfg(feature = "sqlite")]
+pub fn query_state_history(
+    node: &str,
+    key: Option<&str>,
+    since: DateTime<Local>,
+) -> Result<Vec<StateRecord>, Error> {
+    with_store(|store| store.state_history(Some(node), key, since))
+        .or_else(|_| Store::open(&store_path())?.state_history(Some(node), key, since))
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn query_state_history(
+    _node: &str,
+    _key: Option<&str>,
+    _since: DateTime<Local>,
+) -> Result<Vec<StateRecord>, Error> {
+    Err(format_err!("当前版本编译时未启用 sqlite 功能，无法使用本地数据库"))
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store_round_trip() {
+    use std::collections::BTreeMap;
+
+    let store = Store::open_in_memory().unwrap();
+    assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+    let now = Local::now();
+    let node = NodeInfo {
+        id: "n1".to_string(),
+        position_type: 1,
+        position: vec![1.0, 2.5],
+        state: BTreeMap::from([("temp".to_string(), "21".to_string())]),
+        registered_at: now,
+        updated_at: now,
+    };
+    store.save_node(&node).unwrap();
+    let loaded = store.load_nodes().unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert_eq!((loaded[0].position.clone(), loaded[0].state.clone()), (node.position, node.state));
+
+    let old = now - chrono::Duration::hours(2);
+    store.record_state("n1", "temp", "19", old).unwrap();
+    store.record_state("n1", "temp", "21", now).unwrap();
+    store.record_state("n1", "mode", "auto", now).unwrap();
+    let since = now - chrono::Duration::hours(1);
+    assert_eq!(store.state_history(Some("n1"), Some("temp"), old).unwrap().len(), 2);
+    assert_eq!(store.prune_state_history(since).unwrap(), 1);
+    assert_eq!(store.state_history(None, None, old).unwrap().len(), 2);
+
+    // 写入时按保留时间删除，一分钟内只删除一次
+    let mut store = store;
+    assert_eq!(store.prune_expired(now).unwrap(), 0);
+    store.set_history_retention(std::time::Duration::from_secs(3600));
+    store.record_state("n1", "temp", "18", old).unwrap();
+    assert_eq!(store.prune_expired(now).unwrap(), 1);
+    store.record_state("n1", "temp", "17", old).unwrap();
+    assert_eq!(store.prune_expired(now + chrono::Duration::seconds(30)).unwrap(), 0);
+    assert_eq!(store.prune_expired(now + chrono::Duration::seconds(61)).unwrap(), 1);
+    assert_eq!(store.state_history(None, None, old).unwrap().len(), 2);
+
+    let audit = |id: &str, name: &str| CommandAudit {
+        id: id.to_string(),
+        name: name.to_string(),
+        sender: "op (dt_x_)".to_string(),
+        topic: "node/1".to_string(),
+        payload: "{}".to_string(),
+        qos: 1,
+        retain: false,
+        sent_at: now,
+        status: "pending".to_string(),
+        message: None,
+        finished_at: None,
+    };
+    store.record_command(&audit("c1", "fan")).unwrap();
+    store.record_command(&audit("c2", "pump")).unwrap();
+    store.finish_command("c1", "acked", Some("done"), now).unwrap();
+    let fan = store
+        .commands(&AuditQuery {
+            name: Some("fan".to_string()),
+            ..AuditQuery::default()
+        })
+        .unwrap();
+    assert_eq!(fan.len(), 1);
+    assert_eq!((fan[0].status.as_str(), fan[0].message.as_deref()), ("acked", Some("done")));
+    let latest = store.commands(&AuditQuery { limit: 1, ..AuditQuery::default() }).unwrap();
+    assert_eq!(latest[0].id, "c2");
+}